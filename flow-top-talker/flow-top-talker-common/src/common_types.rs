@@ -5,29 +5,73 @@
 pub static INGRESS_TRACKER_0_MAP_NAME: &str = "INGRESS_TRACKER_0";
 pub static INGRESS_TRACKER_1_MAP_NAME: &str = "INGRESS_TRACKER_1";
 pub static EGRESS_TRACKER_0_MAP_NAME: &str = "EGRESS_TRACKER_0";
-pub static EGRESS_TRACKER_1_MAP_NAME: &str = "INGRESS_TRACKER_1";
+pub static EGRESS_TRACKER_1_MAP_NAME: &str = "EGRESS_TRACKER_1";
+pub static INGRESS_HISTOGRAMS_0_MAP_NAME: &str = "INGRESS_HISTOGRAMS_0";
+pub static INGRESS_HISTOGRAMS_1_MAP_NAME: &str = "INGRESS_HISTOGRAMS_1";
+pub static EGRESS_HISTOGRAMS_0_MAP_NAME: &str = "EGRESS_HISTOGRAMS_0";
+pub static EGRESS_HISTOGRAMS_1_MAP_NAME: &str = "EGRESS_HISTOGRAMS_1";
 pub static FLAG_MAP_NAME: &str = "FLAG";
 pub static CONFIG_MAP_NAME: &str = "CONFIG";
+pub static INFLIGHT_MAP_NAME: &str = "INFLIGHT";
+pub static TCP_SOCK_OFFSETS_MAP_NAME: &str = "TCP_SOCK_OFFSETS";
+pub static TASK_OFFSETS_MAP_NAME: &str = "TASK_OFFSETS";
+pub static CONN_STARTS_MAP_NAME: &str = "CONN_STARTS";
+pub static ACTIVE_CALLS_MAP_NAME: &str = "ACTIVE_CALLS";
+pub static CONN_EVENTS_MAP_NAME: &str = "CONN_EVENTS";
+pub static VERSION_MAP_NAME: &str = "VERSION";
+pub static TRACED_MAP_NAME: &str = "TRACED";
+pub static ALLOWED_MAP_NAME: &str = "ALLOWED";
+
+/// All the flow tracker maps. These are the maps which are sized at load time.
+pub static TRACKER_MAP_NAMES: [&str; 4] = [
+    "INGRESS_TRACKER_0",
+    "INGRESS_TRACKER_1",
+    "EGRESS_TRACKER_0",
+    "EGRESS_TRACKER_1",
+];
+
+/// All the histogram maps, which follow the tracker maps of the same direction and buffer
+/// and are sized along with them.
+pub static HISTOGRAM_MAP_NAMES: [&str; 4] = [
+    "INGRESS_HISTOGRAMS_0",
+    "INGRESS_HISTOGRAMS_1",
+    "EGRESS_HISTOGRAMS_0",
+    "EGRESS_HISTOGRAMS_1",
+];
+
+/// Version of the layout of the maps shared between the ebpf and user space program, stored
+/// in the `VERSION` map. Maps pinned by a program with a different version can't be re-used,
+/// so bump it whenever a key or value type of a map changes.
+pub const MAPS_LAYOUT_VERSION: u32 = 10;
+
+/// Default number of flows each tracker map can hold. The user space program can override
+/// it before the ebpf program is loaded.
+pub const DEFAULT_MAX_FLOWS: u32 = 10240;
 
 pub static TCP: u8 = 0;
 pub static UDP: u8 = 1;
 
 /// Struct defining the key for each flow by 5-tuple.
 /// 
-/// Align the struct according to the cache line. Rust ensures remaining would be padded automatically.
-/// For now setting it up for x86_64 and aarch64.
-/// https://github.com/crossbeam-rs/crossbeam/blob/983d56b6007ca4c22b56a665a7785f40f55c2a53/crossbeam-utils/src/cache_padded.rs#L80-L88
+/// The key is packed to 16 bytes with explicit padding. Keys are never written concurrently
+/// so there is no need to align them to the cache line, and padding them would only add to
+/// the memory locked by each tracker map and the bytes copied while draining them. Padding
+/// is explicit (and always zeroed) so that there are no uninitialized bytes in the key which
+/// keeps it safe to be used as `Pod` and hashed by the kernel.
 #[repr(C)]
-#[repr(align(128))]
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FlowKey {
     pub src_addr: u32,
     pub dest_addr: u32,
     pub src_port: u16,
     pub dest_port: u16,
     pub protocol: u8,
+    pub _pad: [u8; 3],
 }
 
+const _: () = assert!(core::mem::size_of::<FlowKey>() == 16);
+
 impl FlowKey {
     pub fn new(
         src_addr: u32,
@@ -42,28 +86,424 @@ impl FlowKey {
             src_port,
             dest_port,
             protocol,
+            _pad: [0; 3],
         }
     }
 }
 
+/// Process which owns a socket, i.e. the process which first sent or received data on it.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Owner {
+    pub pid: u32,
+    pub tid: u32,
+    pub comm: [u8; 16],
+}
+
+impl Owner {
+    pub fn new(pid_tgid: u64, comm: [u8; 16]) -> Owner {
+        Self {
+            pid: (pid_tgid >> 32) as u32,
+            tid: pid_tgid as u32,
+            comm,
+        }
+    }
+
+    /// Owner as returned by `bpf_get_current_pid_tgid`.
+    pub fn pid_tgid(&self) -> u64 {
+        ((self.pid as u64) << 32) | self.tid as u64
+    }
+
+    /// Command name of the owner without the trailing NUL bytes.
+    pub fn comm(&self) -> &str {
+        let len = self.comm.iter().position(|c| *c == 0).unwrap_or(self.comm.len());
+        core::str::from_utf8(&self.comm[..len]).unwrap_or_default()
+    }
+}
+
+pub const EAGAIN: i32 = 11;
+
+/// Errnos counted separately for each flow, any other errno is counted as the last slot.
+pub const TRACKED_ERRNOS: [i32; 7] = [
+    EAGAIN,
+    32,  // EPIPE
+    90,  // EMSGSIZE
+    104, // ECONNRESET
+    105, // ENOBUFS
+    110, // ETIMEDOUT
+    111, // ECONNREFUSED
+];
+
+/// Names of the errno slots, in the order of `TRACKED_ERRNOS` followed by the other slot.
+pub static ERRNO_NAMES: [&str; ERRNO_SLOTS] = [
+    "EAGAIN", "EPIPE", "EMSGSIZE", "ECONNRESET", "ENOBUFS", "ETIMEDOUT", "ECONNREFUSED", "OTHER",
+];
+
+pub const ERRNO_SLOTS: usize = TRACKED_ERRNOS.len() + 1;
+
+/// Slot of the errno in the error counters of the flow.
+#[inline(always)]
+pub fn errno_slot(errno: i32) -> usize {
+    let mut slot = 0;
+    while slot < TRACKED_ERRNOS.len() {
+        if TRACKED_ERRNOS[slot] == errno {
+            return slot;
+        }
+        slot += 1;
+    }
+
+    TRACKED_ERRNOS.len()
+}
+
+/// Number of log2 buckets of the message size histogram. Bucket `i` counts messages of size
+/// in `[2^i, 2^(i+1))` and the last bucket counts anything larger.
+pub const SIZE_BUCKETS: usize = 20;
+
+/// Number of log2 buckets of the latency histogram in microseconds, the last bucket counts
+/// anything above ~8 seconds.
+pub const LATENCY_BUCKETS: usize = 24;
+
+/// Bucket of the message size histogram for the size.
+#[inline(always)]
+pub fn size_bucket(size: u64) -> usize {
+    log2_bucket(size, SIZE_BUCKETS)
+}
+
+/// Bucket of the latency histogram for the duration in nanoseconds.
+#[inline(always)]
+pub fn latency_bucket(duration_ns: u64) -> usize {
+    log2_bucket(duration_ns / 1000, LATENCY_BUCKETS)
+}
+
+/// Log2 of the value, capped to the last of the buckets.
+#[inline(always)]
+fn log2_bucket(value: u64, buckets: usize) -> usize {
+    // Unrolled log2, as a loop or leading_zeros is not friendly to the verifier.
+    let mut value = value;
+    let mut bucket = 0;
+    if value >= 1 << 32 {
+        value >>= 32;
+        bucket += 32;
+    }
+    if value >= 1 << 16 {
+        value >>= 16;
+        bucket += 16;
+    }
+    if value >= 1 << 8 {
+        value >>= 8;
+        bucket += 8;
+    }
+    if value >= 1 << 4 {
+        value >>= 4;
+        bucket += 4;
+    }
+    if value >= 1 << 2 {
+        value >>= 2;
+        bucket += 2;
+    }
+    if value >= 1 << 1 {
+        bucket += 1;
+    }
+
+    if bucket >= buckets {
+        buckets - 1
+    } else {
+        bucket
+    }
+}
+
+/// Value tracked for each flow in the tracker maps.
+///
+/// Owner is written on every update as the value is per cpu, and the slot of the cpu
+/// might not have been written before.
+///
+/// TCP health metrics are sampled from `tcp_sock` and the latest sample (by `sampled_ns`)
+/// across the CPUs is the one to use. `srtt_us` is stored as in the kernel, i.e. shifted
+/// left by 3. Retransmits, errors (by errno slot), resets and messages are counted in the
+/// interval, the histograms of the messages are kept apart in `FlowHistograms`.
+///
+/// Socket buffer occupancy and sizes are sampled on every send and receive, and similarly
+/// the latest sample (by `buffers_sampled_ns`) is the one to use.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FlowStats {
+    pub bytes: u64,
+    pub owner: Owner,
+    pub sampled_ns: u64,
+    pub srtt_us: u32,
+    pub snd_cwnd: u32,
+    pub total_retrans: u32,
+    pub retransmits: u32,
+    pub errors: [u32; ERRNO_SLOTS],
+    pub resets_sent: u32,
+    pub resets_received: u32,
+    pub messages: u32,
+    pub buffers_sampled_ns: u64,
+    pub wmem_queued: u32,
+    pub sndbuf: u32,
+    pub rmem_alloc: u32,
+    pub rcvbuf: u32,
+}
+
+impl FlowStats {
+    /// Merge stats of the flow from another CPU.
+    pub fn merge(&mut self, other: &FlowStats) {
+        self.bytes += other.bytes;
+        self.retransmits += other.retransmits;
+        self.resets_sent += other.resets_sent;
+        self.resets_received += other.resets_received;
+        self.messages += other.messages;
+        for (errors, other_errors) in self.errors.iter_mut().zip(other.errors) {
+            *errors += other_errors;
+        }
+
+        if other.owner.pid != 0 {
+            self.owner = other.owner;
+        }
+
+        if other.sampled_ns > self.sampled_ns {
+            self.sampled_ns = other.sampled_ns;
+            self.srtt_us = other.srtt_us;
+            self.snd_cwnd = other.snd_cwnd;
+            self.total_retrans = other.total_retrans;
+        }
+
+        if other.buffers_sampled_ns > self.buffers_sampled_ns {
+            self.buffers_sampled_ns = other.buffers_sampled_ns;
+            self.wmem_queued = other.wmem_queued;
+            self.sndbuf = other.sndbuf;
+            self.rmem_alloc = other.rmem_alloc;
+            self.rcvbuf = other.rcvbuf;
+        }
+    }
+}
+
+/// Histograms of the message sizes and the time spent in the calls (log2 buckets) of a flow
+/// in the interval.
+///
+/// Histograms are stored in their own maps, which are not per cpu, as a copy of them on every
+/// CPU would take most of the locked memory of the trackers. The buckets are incremented
+/// atomically instead.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FlowHistograms {
+    pub sizes: [u32; SIZE_BUCKETS],
+    pub latencies: [u32; LATENCY_BUCKETS],
+}
+
+/// Offsets of the fields in `tcp_sock` resolved by the user space program from the kernel
+/// BTF, as `tcp_sock` is not part of the generated bindings. `valid` is set only if all the
+/// offsets were resolved.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TcpSockOffsets {
+    pub srtt_us: u32,
+    pub snd_cwnd: u32,
+    pub total_retrans: u32,
+    pub valid: u32,
+}
+
+/// Offsets of `task_struct.signal` and `signal_struct.live` resolved by the user space program
+/// from the kernel BTF, which tell whether an exiting thread is the last one of its process.
+/// `valid` is set only if all the offsets were resolved.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TaskOffsets {
+    pub signal: u32,
+    pub live: u32,
+    pub valid: u32,
+}
+
+/// Socket is tracked.
+pub const VERDICT_TRACK: u8 = 0;
+/// Socket is skipped due to the filters.
+pub const VERDICT_SKIP: u8 = 1;
+/// Socket is skipped as it is not an IpV4 socket, whatever the filters.
+pub const VERDICT_UNSUPPORTED: u8 = 3;
+
+/// Value cached for each socket on first sight.
+///
+/// `key_resolved` is cleared when the socket is connected to another peer, in which case the
+/// flow key is resolved again on the next lookup, while the owner and its user are kept from
+/// the first sight. The filter verdict is evaluated again for the owner once the filter
+/// changes, i.e. when `generation` is not the one of the current filter.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SockInfo {
+    pub flow_key: FlowKey,
+    pub owner: Owner,
+    pub key_resolved: u8,
+    pub verdict: u8,
+    pub _pad: [u8; 2],
+    pub generation: u32,
+    pub uid: u32,
+}
+
+/// Connection is established, either by connect or accept.
+pub const CONN_OPEN: u8 = 0;
+/// Connection is closed.
+pub const CONN_CLOSE: u8 = 1;
+
+/// Connection was closed gracefully, local side sent the FIN first.
+pub const CLOSE_LOCAL: u8 = 0;
+/// Connection was closed gracefully, remote side sent the FIN first.
+pub const CLOSE_REMOTE: u8 = 1;
+/// Connection was reset or aborted while established.
+pub const CLOSE_RESET: u8 = 2;
+/// Connection was closed before the handshake completed, e.g. connection refused or timed out.
+pub const CLOSE_FAILED: u8 = 3;
+
+/// Start of a TCP connection tracked until it is closed, keyed by the address of the `sock`.
+///
+/// Owner of an accepted connection is not known until it is accepted by the process, in
+/// which case `verdict` is left as `VERDICT_PENDING`. As for the sockets, the verdict is
+/// evaluated again for the owner and its user once the filter changes.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConnStart {
+    pub start_ns: u64,
+    pub flow_key: FlowKey,
+    pub owner: Owner,
+    pub active: u8,
+    pub verdict: u8,
+    pub _pad: [u8; 6],
+    pub generation: u32,
+    pub uid: u32,
+}
+
+/// Verdict of an accepted connection which is not accepted by the process yet.
+pub const VERDICT_PENDING: u8 = 2;
+
+/// Event sent to the user space program when a TCP connection is opened or closed.
+///
+/// Destination of the flow key is always the remote side of the connection. `duration_ns`
+/// and `close_reason` are only set for `CONN_CLOSE`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConnEvent {
+    pub flow_key: FlowKey,
+    pub owner: Owner,
+    pub duration_ns: u64,
+    pub kind: u8,
+    pub active: u8,
+    pub close_reason: u8,
+    pub _pad: [u8; 5],
+}
+
 /// Key for the config to pass to ebpf program which can be used to filter out the data captured from ebpf.
 /// 
-/// This would take 1 byte as it is just 2 values for now and it is fine 
-/// if it is not padded as the use case of this key is minimal,
-///     1. Key is used to setup the initial configuration after which user space program
-///        will not be updating it further.
+/// The key is a `u32` as the kernel refuses hash maps with keys of 0 bytes, which a fieldless
+/// enum of a single variant would otherwise be. The use case of this key is minimal,
+///     1. Key is used to setup the configuration, which the user space program can replace
+///        at any time.
 ///     2. Ebpf program uses it to filter out the data which if needed we can define a
-///        PerCpu hash map for it. But considering only 2 values, keeping it simple for
+///        PerCpu hash map for it. But considering only 1 value, keeping it simple for
 ///        now.
+#[repr(u32)]
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum ConfigKey {
-    PID,
-    TID,
+    FILTER,
+}
+
+const _: () = assert!(core::mem::size_of::<ConfigKey>() == 4);
+
+/// Maximum number of values of each kind of filter.
+pub const MAX_FILTER_VALUES: usize = 16;
+
+/// Only track the processes in the traced map, i.e. the command launched by the user program
+/// and its descendants.
+pub const FILTER_TRACED: u32 = 1;
+
+/// Only track the processes in the allowed map, i.e. the processes matching the name and
+/// executable patterns of the user program and their children.
+pub const FILTER_ALLOWED: u32 = 2;
+
+/// Maximum number of processes of the traced command.
+pub const MAX_TRACED: u32 = 8192;
+
+/// Maximum number of processes allowed by the patterns.
+pub const MAX_ALLOWED: u32 = 8192;
+
+/// Filter provided by the user, stored in the config under `ConfigKey::FILTER`.
+///
+/// The config is a hash map so the filter is replaced as a whole by the kernel and the
+/// programs never see a partially updated filter. Only the first `nr_*` values of each kind
+/// are set, and a kind without values doesn't filter anything. `flags` (`FILTER_*`) select the
+/// filters which depend on other maps. `generation` is bumped on every update so that the
+/// verdicts cached for the sockets are evaluated again.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FilterConfig {
+    pub pids: [u32; MAX_FILTER_VALUES],
+    pub tids: [u32; MAX_FILTER_VALUES],
+    pub uids: [u32; MAX_FILTER_VALUES],
+    pub nr_pids: u32,
+    pub nr_tids: u32,
+    pub nr_uids: u32,
+    pub flags: u32,
+    pub generation: u32,
+}
+
+impl FilterConfig {
+    /// Whether no filter is set, in which case everything is tracked.
+    pub fn is_empty(&self) -> bool {
+        self.nr_pids == 0 && self.nr_tids == 0 && self.nr_uids == 0 && self.flags == 0
+    }
+
+    /// Whether the thread (as returned by `bpf_get_current_pid_tgid`) of the user passes the
+    /// filter. The process is the upper half of `pid_tgid` and the thread the lower half, so
+    /// a process filter covers all of its threads.
+    #[inline(always)]
+    pub fn matches(&self, pid_tgid: u64, uid: u32) -> bool {
+        allowed(&self.pids, self.nr_pids, (pid_tgid >> 32) as u32)
+            && allowed(&self.tids, self.nr_tids, pid_tgid as u32)
+            && allowed(&self.uids, self.nr_uids, uid)
+    }
+}
+
+/// Whether the value is one of the first `len` values, or there are no values to check.
+#[inline(always)]
+fn allowed(values: &[u32; MAX_FILTER_VALUES], len: u32, value: u32) -> bool {
+    if len == 0 {
+        return true;
+    }
+
+    let mut index = 0;
+    while index < MAX_FILTER_VALUES && (index as u32) < len {
+        if values[index] == value {
+            return true;
+        }
+        index += 1;
+    }
+
+    false
 }
 
-/// Impl Pod for the keys used in ebpf HashMap.
+/// Impl Pod for the keys and values used in ebpf maps.
 #[cfg(feature = "with-aya")]
 unsafe impl aya::Pod for FlowKey {}
 
 #[cfg(feature = "with-aya")]
-unsafe impl aya::Pod for ConfigKey {}
\ No newline at end of file
+unsafe impl aya::Pod for ConfigKey {}
+
+#[cfg(feature = "with-aya")]
+unsafe impl aya::Pod for FilterConfig {}
+
+#[cfg(feature = "with-aya")]
+unsafe impl aya::Pod for FlowStats {}
+
+#[cfg(feature = "with-aya")]
+unsafe impl aya::Pod for FlowHistograms {}
+
+#[cfg(feature = "with-aya")]
+unsafe impl aya::Pod for SockInfo {}
+
+#[cfg(feature = "with-aya")]
+unsafe impl aya::Pod for TcpSockOffsets {}
+
+#[cfg(feature = "with-aya")]
+unsafe impl aya::Pod for TaskOffsets {}
+
+#[cfg(feature = "with-aya")]
+unsafe impl aya::Pod for ConnStart {}
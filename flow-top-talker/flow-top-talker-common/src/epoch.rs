@@ -0,0 +1,57 @@
+//! Epoch handshake between the ebpf writers and the user space reader of the double buffer.
+//!
+//! The user space program flips the flag to move the writers to the other buffer and drains
+//! the previous one. A writer which read the previous flag value could still be updating the
+//! previous buffer while it is being drained, which would lose (or leak into the next cycle)
+//! whatever it writes. To avoid it, each writer announces itself on the buffer it is about to
+//! update by incrementing the in-flight writer counter of that buffer and re-reads the flag.
+//!
+//! ```text
+//! Writer                              Reader
+//! ------                              ------
+//! buffer = flag                       flag = next
+//! writers[buffer] += 1                wait until writers[prev] == 0
+//! if flag != buffer, move to flag     drain prev
+//! update buffer
+//! writers[buffer] -= 1
+//! ```
+//!
+//! Since the increment is a full barrier, either the writer sees the flipped flag and moves
+//! to the new buffer, or the reader sees the writer on the previous buffer and waits for it.
+
+/// Shared state of the double buffer as seen by the writers.
+pub trait EpochState {
+    /// Buffer which is currently active.
+    fn active(&self) -> Option<u32>;
+
+    /// Add `delta` (wrapping) to the in-flight writers of the buffer.
+    ///
+    /// This must act as a full memory barrier.
+    fn add_writers(&self, buffer: u32, delta: u64) -> Option<()>;
+}
+
+/// Announce a writer and return the buffer it should update.
+///
+/// Every successful call must be paired with [`exit`] on the returned buffer.
+#[inline(always)]
+pub fn enter<S: EpochState>(state: &S) -> Option<u32> {
+    let buffer = state.active()?;
+    state.add_writers(buffer, 1)?;
+
+    let active = state.active()?;
+    if active == buffer {
+        return Some(buffer);
+    }
+
+    // Flag was flipped before the writer was announced, so the reader may already be
+    // draining the buffer. Move to the new one which won't be flipped for another interval.
+    state.add_writers(buffer, u64::MAX)?;
+    state.add_writers(active, 1)?;
+    Some(active)
+}
+
+/// Leave the buffer returned by [`enter`].
+#[inline(always)]
+pub fn exit<S: EpochState>(state: &S, buffer: u32) {
+    let _ = state.add_writers(buffer, u64::MAX);
+}
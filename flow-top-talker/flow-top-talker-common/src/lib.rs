@@ -1,3 +1,4 @@
 #![no_std]
 
-pub mod common_types;
\ No newline at end of file
+pub mod common_types;
+pub mod epoch;
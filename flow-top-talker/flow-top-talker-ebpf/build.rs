@@ -14,4 +14,10 @@ use which::which;
 fn main() {
     let bpf_linker = which("bpf-linker").unwrap();
     println!("cargo:rerun-if-changed={}", bpf_linker.to_str().unwrap());
+
+    // The socket storage map is defined in the `.maps` section, which is only described by the
+    // BTF of the object.
+    if std::env::var("CARGO_CFG_TARGET_ARCH").as_deref() == Ok("bpf") {
+        println!("cargo:rustc-link-arg=--btf");
+    }
 }
@@ -0,0 +1,194 @@
+use aya_ebpf::{
+    helpers::{bpf_get_current_comm, bpf_get_current_pid_tgid, bpf_ktime_get_ns, bpf_probe_read_kernel},
+    macros::map,
+    maps::{LruHashMap, RingBuf},
+    programs::BtfTracePointContext,
+};
+use flow_top_talker_common::common_types::{
+    ConnEvent, ConnStart, FilterConfig, Owner, CLOSE_FAILED, CLOSE_LOCAL, CLOSE_REMOTE, CLOSE_RESET,
+    CONN_CLOSE, CONN_OPEN, DEFAULT_MAX_FLOWS, TCP, VERDICT_PENDING, VERDICT_SKIP, VERDICT_TRACK,
+};
+
+use crate::{
+    bindings::sock,
+    current_filter, current_uid,
+    sock_cache::{self, generation, verdict},
+    AF_INET,
+};
+
+// TCP states as defined in include/net/tcp_states.h.
+const TCP_ESTABLISHED: i32 = 1;
+const TCP_SYN_SENT: i32 = 2;
+const TCP_SYN_RECV: i32 = 3;
+const TCP_FIN_WAIT1: i32 = 4;
+const TCP_FIN_WAIT2: i32 = 5;
+const TCP_CLOSE: i32 = 7;
+const TCP_LAST_ACK: i32 = 9;
+const TCP_CLOSING: i32 = 11;
+
+const IPPROTO_TCP: u16 = 6;
+
+/// Connections in progress keyed by the address of the `sock`, removed when they are closed.
+#[map(name = "CONN_STARTS")]
+static CONN_STARTS: LruHashMap<u64, ConnStart> = LruHashMap::with_max_entries(DEFAULT_MAX_FLOWS, 0);
+
+/// Open and close events of the connections, consumed by the user program.
+#[map(name = "CONN_EVENTS")]
+static CONN_EVENTS: RingBuf = RingBuf::with_byte_size(1024 * 1024, 0);
+
+/// Track the state changes of TCP sockets to find when connections are opened and closed.
+///
+/// Connect moves the socket to `SYN_SENT` in the context of the process, so the owner is
+/// known right away. Accepted connections move to `ESTABLISHED` in softirq context, so they
+/// are only reported once the process accepts them.
+///
+/// The tracepoint is attached as tp_btf, so that the socket is given by BTF for its storage.
+/// https://github.com/torvalds/linux/blob/1a1d569a75f3ab2923cb62daf356d102e4df2b86/include/trace/events/sock.h#L141
+pub fn try_inet_sock_set_state(ctx: BtfTracePointContext) -> Result<u32, u32> {
+    let sk = unsafe { ctx.arg::<*const sock>(0) } as *mut sock;
+    let (family, protocol) = unsafe {
+        let family = bpf_probe_read_kernel(&(*sk).__sk_common.skc_family).map_err(|_| 1u32)?;
+        (family, bpf_probe_read_kernel(&(*sk).sk_protocol).map_err(|_| 1u32)?)
+    };
+    if family != AF_INET || protocol != IPPROTO_TCP {
+        return Ok(0);
+    }
+
+    let old_state: i32 = unsafe { ctx.arg(1) };
+    let new_state: i32 = unsafe { ctx.arg(2) };
+    let sk_addr = sk as u64;
+
+    match (old_state, new_state) {
+        (_, TCP_SYN_SENT) => {
+            let pid_tgid = bpf_get_current_pid_tgid();
+            let filter = current_filter();
+            let uid = current_uid();
+            let start = ConnStart {
+                start_ns: unsafe { bpf_ktime_get_ns() },
+                flow_key: sock_cache::read_flow_key(sk, TCP).ok_or(1u32)?,
+                owner: Owner::new(pid_tgid, bpf_get_current_comm().unwrap_or_default()),
+                active: 1,
+                verdict: verdict(filter, pid_tgid, uid),
+                generation: generation(filter),
+                uid,
+                ..Default::default()
+            };
+            let _ = CONN_STARTS.insert(&sk_addr, &start, 0);
+        }
+        (TCP_SYN_SENT, TCP_ESTABLISHED) => {
+            if let Some(start) = CONN_STARTS.get_ptr_mut(&sk_addr) {
+                let start = unsafe { &mut *start };
+                refresh_verdict(start, current_filter());
+                if start.verdict == VERDICT_TRACK {
+                    send_event(start, CONN_OPEN, 0, 0);
+                }
+            }
+        }
+        (TCP_SYN_RECV, TCP_ESTABLISHED) => {
+            let start = ConnStart {
+                start_ns: unsafe { bpf_ktime_get_ns() },
+                flow_key: sock_cache::read_flow_key(sk, TCP).ok_or(1u32)?,
+                verdict: VERDICT_PENDING,
+                ..Default::default()
+            };
+            let _ = CONN_STARTS.insert(&sk_addr, &start, 0);
+        }
+        (_, TCP_CLOSE) => {
+            let mut start = match unsafe { CONN_STARTS.get(&sk_addr) } {
+                Some(start) => *start,
+                // Connection was opened before the program was attached.
+                None => return Ok(0),
+            };
+            let _ = CONN_STARTS.remove(&sk_addr);
+
+            refresh_verdict(&mut start, current_filter());
+            if start.verdict == VERDICT_PENDING {
+                // Closed before it was accepted, use the owner from the socket cache if it
+                // was seen at all. Otherwise there is no owner and the flow is tracked only
+                // when no filters are configured.
+                match sock_cache::get(sk) {
+                    Some(info) => {
+                        start.owner = info.owner;
+                        start.verdict = info.verdict;
+                    }
+                    None if current_filter().is_some_and(|filter| !filter.is_empty()) => {
+                        start.verdict = VERDICT_SKIP
+                    }
+                    None => start.verdict = VERDICT_TRACK,
+                }
+            }
+
+            if start.verdict == VERDICT_TRACK {
+                let duration_ns = unsafe { bpf_ktime_get_ns() } - start.start_ns;
+                send_event(&start, CONN_CLOSE, duration_ns, close_reason(old_state));
+            }
+        }
+        _ => {}
+    }
+
+    Ok(0)
+}
+
+/// Connection is accepted by the process, which becomes the owner of the connection.
+pub fn accepted(sk: *mut sock) {
+    let start = match CONN_STARTS.get_ptr_mut(&(sk as u64)) {
+        Some(start) => unsafe { &mut *start },
+        None => return,
+    };
+
+    if start.verdict != VERDICT_PENDING {
+        return;
+    }
+
+    let pid_tgid = bpf_get_current_pid_tgid();
+    let filter = current_filter();
+    start.owner = Owner::new(pid_tgid, bpf_get_current_comm().unwrap_or_default());
+    start.uid = current_uid();
+    start.verdict = verdict(filter, pid_tgid, start.uid);
+    start.generation = generation(filter);
+    if start.verdict == VERDICT_TRACK {
+        send_event(start, CONN_OPEN, 0, 0);
+    }
+}
+
+/// Decide the verdict of the owner again if the filter changed since it was decided, as for the
+/// sockets. Connections which are not accepted yet have no owner to decide it for.
+#[inline(always)]
+fn refresh_verdict(start: &mut ConnStart, filter: Option<&FilterConfig>) {
+    if start.verdict == VERDICT_PENDING || start.generation == generation(filter) {
+        return;
+    }
+
+    start.verdict = verdict(filter, start.owner.pid_tgid(), start.uid);
+    start.generation = generation(filter);
+}
+
+/// Reason of the close based on the state the socket was in before it was closed.
+///
+/// Graceful close goes through `TIME_WAIT` (local side closed first) or `LAST_ACK` (remote
+/// side closed first), while a reset closes the socket from whichever state it was in.
+#[inline(always)]
+fn close_reason(old_state: i32) -> u8 {
+    match old_state {
+        TCP_SYN_SENT | TCP_SYN_RECV => CLOSE_FAILED,
+        TCP_FIN_WAIT1 | TCP_FIN_WAIT2 | TCP_CLOSING => CLOSE_LOCAL,
+        TCP_LAST_ACK => CLOSE_REMOTE,
+        _ => CLOSE_RESET,
+    }
+}
+
+#[inline(always)]
+fn send_event(start: &ConnStart, kind: u8, duration_ns: u64, close_reason: u8) {
+    let event = ConnEvent {
+        flow_key: start.flow_key,
+        owner: start.owner,
+        duration_ns,
+        kind,
+        active: start.active,
+        close_reason,
+        ..Default::default()
+    };
+
+    // Event is dropped if the user program is not keeping up.
+    let _ = CONN_EVENTS.output(&event, 0);
+}
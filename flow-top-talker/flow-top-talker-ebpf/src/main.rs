@@ -8,20 +8,80 @@
 #[allow(dead_code)]
 
 mod bindings;
+mod conn_tracker;
+mod sock_cache;
+
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 
 use aya_ebpf::{
-    helpers::{bpf_get_current_pid_tgid, bpf_probe_read_kernel},
-    macros::{kprobe, map},
-    maps::{Array, HashMap, PerCpuHashMap},
-    programs::ProbeContext,
+    bindings::BPF_NOEXIST,
+    cty::c_void,
+    helpers::{
+        bpf_get_current_pid_tgid, bpf_get_current_task, bpf_get_current_uid_gid, bpf_get_func_ret,
+        bpf_ktime_get_ns, bpf_probe_read_kernel,
+    },
+    macros::{btf_tracepoint, fentry, fexit, map, tracepoint},
+    maps::{Array, HashMap, LruHashMap, PerCpuArray, PerCpuHashMap},
+    programs::{BtfTracePointContext, FEntryContext, FExitContext, TracePointContext},
+    EbpfContext,
 };
 
 use bindings::*;
-use flow_top_talker_common::common_types::{ConfigKey, FlowKey, TCP, UDP};
+use flow_top_talker_common::{
+    common_types::{
+        errno_slot, latency_bucket, size_bucket, ConfigKey, FilterConfig, FlowHistograms, FlowKey,
+        FlowStats, Owner, TaskOffsets, TcpSockOffsets, DEFAULT_MAX_FLOWS, EAGAIN, FILTER_ALLOWED, FILTER_TRACED, MAX_ALLOWED, MAX_TRACED,
+        TCP, UDP, VERDICT_TRACK,
+    },
+    epoch::{self, EpochState},
+};
 
-// IpV4 and IpV6.
+// IpV4.
 const AF_INET: u16 = 2;
-const AF_INET6: u16 = 10;
+
+/// `struct sockaddr_in` as passed in `msg_name` for IpV4 sockets.
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct SockaddrIn {
+    sin_family: u16,
+    sin_port: u16,
+    sin_addr: u32,
+    sin_zero: [u8; 8],
+}
+
+/// Offset of `pid` in the `task_newtask` tracepoint (after the 8 bytes of common fields).
+/// https://github.com/torvalds/linux/blob/1a1d569a75f3ab2923cb62daf356d102e4df2b86/include/trace/events/task.h
+const TASK_NEWTASK_PID_OFFSET: usize = 8;
+
+/// Offset of `clone_flags` in the `task_newtask` tracepoint (after `pid` and `comm`, aligned).
+const TASK_NEWTASK_CLONE_FLAGS_OFFSET: usize = 32;
+
+/// The new task is a thread of the same process.
+const CLONE_THREAD: u64 = 0x10000;
+
+/// TCP health metrics sampled from `tcp_sock`.
+#[derive(Copy, Clone)]
+struct TcpHealth {
+    srtt_us: u32,
+    snd_cwnd: u32,
+    total_retrans: u32,
+}
+
+/// Occupancy and size of the socket buffers.
+#[derive(Copy, Clone)]
+struct SockBuffers {
+    wmem_queued: u32,
+    sndbuf: u32,
+    rmem_alloc: u32,
+    rcvbuf: u32,
+}
+
+/// Direction of the flow, each has its own pair of trackers.
+#[derive(Copy, Clone)]
+enum Direction {
+    Ingress,
+    Egress,
+}
 
 /// Maintain 2 sets of maps to track the current throughput for ingress and egress.
 /// 
@@ -29,157 +89,664 @@ const AF_INET6: u16 = 10;
 /// the map by the user program while the ebpf program continues to track the throughput.
 /// 
 #[map(name = "INGRESS_TRACKER_0")]
-static INGRESS_TRACKER_0: PerCpuHashMap<FlowKey, u64> = PerCpuHashMap::with_max_entries(10240, 0);
+static INGRESS_TRACKER_0: PerCpuHashMap<FlowKey, FlowStats> = PerCpuHashMap::with_max_entries(DEFAULT_MAX_FLOWS, 0);
 
 #[map(name = "INGRESS_TRACKER_1")]
-static INGRESS_TRACKER_1: PerCpuHashMap<FlowKey, u64> = PerCpuHashMap::with_max_entries(10240, 0);
+static INGRESS_TRACKER_1: PerCpuHashMap<FlowKey, FlowStats> = PerCpuHashMap::with_max_entries(DEFAULT_MAX_FLOWS, 0);
 
 #[map(name = "EGRESS_TRACKER_0")]
-static EGRESS_TRACKER_0: PerCpuHashMap<FlowKey, u64> = PerCpuHashMap::with_max_entries(10240, 0);
+static EGRESS_TRACKER_0: PerCpuHashMap<FlowKey, FlowStats> = PerCpuHashMap::with_max_entries(DEFAULT_MAX_FLOWS, 0);
 
 #[map(name = "EGRESS_TRACKER_1")]
-static EGRESS_TRACKER_1: PerCpuHashMap<FlowKey, u64> = PerCpuHashMap::with_max_entries(10240, 0);
+static EGRESS_TRACKER_1: PerCpuHashMap<FlowKey, FlowStats> = PerCpuHashMap::with_max_entries(DEFAULT_MAX_FLOWS, 0);
+
+/// Histograms of the flows in the trackers of the same direction and buffer. They are not per
+/// cpu to keep the locked memory in check, so the buckets are incremented atomically.
+#[map(name = "INGRESS_HISTOGRAMS_0")]
+static INGRESS_HISTOGRAMS_0: HashMap<FlowKey, FlowHistograms> = HashMap::with_max_entries(DEFAULT_MAX_FLOWS, 0);
+
+#[map(name = "INGRESS_HISTOGRAMS_1")]
+static INGRESS_HISTOGRAMS_1: HashMap<FlowKey, FlowHistograms> = HashMap::with_max_entries(DEFAULT_MAX_FLOWS, 0);
+
+#[map(name = "EGRESS_HISTOGRAMS_0")]
+static EGRESS_HISTOGRAMS_0: HashMap<FlowKey, FlowHistograms> = HashMap::with_max_entries(DEFAULT_MAX_FLOWS, 0);
+
+#[map(name = "EGRESS_HISTOGRAMS_1")]
+static EGRESS_HISTOGRAMS_1: HashMap<FlowKey, FlowHistograms> = HashMap::with_max_entries(DEFAULT_MAX_FLOWS, 0);
 
 // Flag use to reset between the 2 tracker.
 #[map(name = "FLAG")]
 static FLAG: Array<u32> = Array::with_max_entries(1, 0);
 
-// HashMap used to maintain config provided by the user.
+// In-flight writers for each of the 2 trackers. Used by the user program to wait for the
+// writers to leave the previous tracker before draining it.
+#[map(name = "INFLIGHT")]
+static INFLIGHT: PerCpuArray<u64> = PerCpuArray::with_max_entries(2, 0);
+
+// HashMap used to maintain config provided by the user. The user program replaces the
+// filter as a whole whenever it changes.
 #[map(name = "CONFIG")]
-static CONFIG: HashMap<ConfigKey, u64> = HashMap::with_max_entries(2, 0);
-
-macro_rules! process_kprobe_func {
-    ($fn_name:ident, $tracker0:expr, $tracker1:expr, $prot:expr) => {
-        fn $fn_name(ctx: ProbeContext) -> Result<u32, u32> {
-            if let Some((flow_key, size)) = unwrap_flow_info(&ctx, $prot) {
-                let flag_ptr = FLAG.get_ptr_mut(0).ok_or(1u32)?;
-                let flag = unsafe { core::ptr::read_volatile(flag_ptr) };
-    
-                let tracker = if flag == 0 {
-                    &$tracker0
-                } else {
-                    &$tracker1
-                };
-    
-                match tracker.get_ptr_mut(&flow_key) {
-                    Some(val) => {
-                        unsafe { *val += size as u64; }
-                    },
-                    None => {
-                        let _ = tracker.insert(&flow_key, &(size as u64), 0);
-                    }
-                }
-            }
-    
-            return Ok(0);
+static CONFIG: HashMap<ConfigKey, FilterConfig> = HashMap::with_max_entries(1, 0);
+
+// Offsets of the fields in tcp_sock, resolved by the user program from the kernel BTF.
+#[map(name = "TCP_SOCK_OFFSETS")]
+static TCP_SOCK_OFFSETS: Array<TcpSockOffsets> = Array::with_max_entries(1, 0);
+
+// Offsets of the fields in task_struct and signal_struct, resolved by the user program from the
+// kernel BTF.
+#[map(name = "TASK_OFFSETS")]
+static TASK_OFFSETS: Array<TaskOffsets> = Array::with_max_entries(1, 0);
+
+// Layout version of the maps, written by the user program so that it can check whether the
+// maps it finds pinned can be re-used.
+#[map(name = "VERSION")]
+static VERSION: Array<u32> = Array::with_max_entries(1, 0);
+
+// Processes of the command launched by the user program and their descendants, keyed by
+// pid with the pid of the parent as value. The user program adds the command, its
+// descendants are added as they are forked and removed once all their threads exited.
+#[map(name = "TRACED")]
+static TRACED: HashMap<u32, u32> = HashMap::with_max_entries(MAX_TRACED, 0);
+
+// Processes matching the name and executable patterns of the user program, keyed by pid with
+// the pid of the parent as value (0 if added by the user program). The user program keeps it
+// in sync with the running processes every interval, in between the children are added as
+// they are forked and the processes removed as they exit.
+#[map(name = "ALLOWED")]
+static ALLOWED: HashMap<u32, u32> = HashMap::with_max_entries(MAX_ALLOWED, 0);
+
+// Entry time of the calls in progress keyed by the thread (pid_tgid), for their latency on
+// return. Entries of threads which exit mid-call are evicted by the newer calls.
+#[map(name = "ACTIVE_CALLS")]
+static ACTIVE_CALLS: LruHashMap<u64, u64> = LruHashMap::with_max_entries(DEFAULT_MAX_FLOWS, 0);
+
+/// Epoch state of the double buffer backed by `FLAG` and `INFLIGHT` maps.
+struct Epoch;
+
+impl EpochState for Epoch {
+    #[inline(always)]
+    fn active(&self) -> Option<u32> {
+        let flag_ptr = FLAG.get_ptr_mut(0)?;
+        Some(unsafe { core::ptr::read_volatile(flag_ptr) })
+    }
+
+    #[inline(always)]
+    fn add_writers(&self, buffer: u32, delta: u64) -> Option<()> {
+        // Counter is per cpu and the program doesn't migrate while running, so there is no
+        // contention. Atomic add is used for the barrier it provides.
+        let writers = INFLIGHT.get_ptr_mut(buffer)?;
+        unsafe { AtomicU64::from_ptr(writers).fetch_add(delta, Ordering::SeqCst) };
+        Some(())
+    }
+}
+
+/// Tracker for the direction based on the active buffer.
+#[inline(always)]
+fn tracker(direction: Direction, buffer: u32) -> &'static PerCpuHashMap<FlowKey, FlowStats> {
+    match (direction, buffer) {
+        (Direction::Ingress, 0) => &INGRESS_TRACKER_0,
+        (Direction::Ingress, _) => &INGRESS_TRACKER_1,
+        (Direction::Egress, 0) => &EGRESS_TRACKER_0,
+        (Direction::Egress, _) => &EGRESS_TRACKER_1,
+    }
+}
+
+/// Histograms for the direction based on the active buffer.
+#[inline(always)]
+fn histograms(direction: Direction, buffer: u32) -> &'static HashMap<FlowKey, FlowHistograms> {
+    match (direction, buffer) {
+        (Direction::Ingress, 0) => &INGRESS_HISTOGRAMS_0,
+        (Direction::Ingress, _) => &INGRESS_HISTOGRAMS_1,
+        (Direction::Egress, 0) => &EGRESS_HISTOGRAMS_0,
+        (Direction::Egress, _) => &EGRESS_HISTOGRAMS_1,
+    }
+}
+
+/// Update stats of the flow in the active tracker, adding the flow if it is not present.
+#[inline(always)]
+fn update_flow<F: FnOnce(&mut FlowStats)>(
+    direction: Direction,
+    flow_key: &FlowKey,
+    update: F,
+) -> Result<(), u32> {
+    let buffer = epoch::enter(&Epoch).ok_or(1u32)?;
+    let tracker = tracker(direction, buffer);
+
+    match tracker.get_ptr_mut(flow_key) {
+        Some(stats) => {
+            update(unsafe { &mut *stats });
+        },
+        None => {
+            let mut stats = FlowStats::default();
+            update(&mut stats);
+            let _ = tracker.insert(flow_key, &stats, 0);
+        }
+    }
+
+    epoch::exit(&Epoch, buffer);
+    Ok(())
+}
+
+/// Count the call in the active histograms of the flow, along with the size of the message
+/// if it succeeded. The histograms are shared by the CPUs, another CPU may add the flow first.
+#[inline(always)]
+fn update_histograms(
+    direction: Direction,
+    flow_key: &FlowKey,
+    latency_bucket: usize,
+    size_bucket: Option<usize>,
+) -> Result<(), u32> {
+    let buffer = epoch::enter(&Epoch).ok_or(1u32)?;
+    let histograms = histograms(direction, buffer);
+
+    let value = match histograms.get_ptr_mut(flow_key) {
+        Some(value) => Some(value),
+        None => {
+            let _ = histograms.insert(flow_key, &FlowHistograms::default(), BPF_NOEXIST as u64);
+            histograms.get_ptr_mut(flow_key)
+        }
+    };
+    if let Some(value) = value {
+        let value = unsafe { &mut *value };
+        if let Some(count) = value.latencies.get_mut(latency_bucket) {
+            unsafe { AtomicU32::from_ptr(count).fetch_add(1, Ordering::Relaxed) };
+        }
+        if let Some(count) = size_bucket.and_then(|bucket| value.sizes.get_mut(bucket)) {
+            unsafe { AtomicU32::from_ptr(count).fetch_add(1, Ordering::Relaxed) };
         }
+    }
+
+    epoch::exit(&Epoch, buffer);
+    Ok(())
+}
+
+/// Count the message on entry with the length passed to the call, so that it is counted even
+/// if the return is missed, and save the time of the call for its latency. `udp_recvmsg` is
+/// counted on return instead, as the peer of an unconnected socket is only in `msg_name` by
+/// then.
+///
+/// tcp_sendmsg: https://github.com/torvalds/linux/blob/1a1d569a75f3ab2923cb62daf356d102e4df2b86/net/ipv4/tcp.c#L1361
+/// tcp_recvmsg: https://github.com/torvalds/linux/blob/1a1d569a75f3ab2923cb62daf356d102e4df2b86/net/ipv4/tcp.c#L2863
+///
+/// udp_sendmsg: https://github.com/torvalds/linux/blob/1a1d569a75f3ab2923cb62daf356d102e4df2b86/net/ipv4/udp.c#L1270
+/// udp_recvmsg: https://github.com/torvalds/linux/blob/1a1d569a75f3ab2923cb62daf356d102e4df2b86/net/ipv4/udp.c#L2025
+fn try_enter_call(ctx: FEntryContext, direction: Direction, prot: u8) -> Result<u32, u32> {
+    let sk = unsafe { ctx.arg::<*const sock>(0) } as *mut sock;
+    let msg = unsafe { ctx.arg::<*const msghdr>(1) } as *mut msghdr;
+    let len: usize = unsafe { ctx.arg(2) };
+    let (mut flow_key, owner) = match flow_of(sk, prot) {
+        Some(flow) => flow,
+        None => return Ok(0),
+    };
+
+    let now = unsafe { bpf_ktime_get_ns() };
+    let _ = ACTIVE_CALLS.insert(&bpf_get_current_pid_tgid(), &now, 0);
+    if prot == UDP && matches!(direction, Direction::Ingress) {
+        return Ok(0);
+    }
+
+    // Unconnected UDP sockets provide the peer of every message sent in `msg_name`.
+    if prot == UDP {
+        if let Some((addr, port)) = read_msg_name(msg) {
+            flow_key.dest_addr = addr;
+            flow_key.dest_port = port;
+        }
+    }
+
+    count_message(direction, &flow_key, owner, sk, prot, len as u64, now)
+}
+
+/// Count the errors and the latency of the call using the return value, which is either the
+/// size of the message or the errno, and the messages received by `udp_recvmsg`. This runs
+/// as fexit, which unlike kretprobes has no limit on the calls in progress.
+///
+/// Unconnected UDP sockets provide the peer with every message in `msg_name`, so use it as
+/// the destination of the flow when present. For `udp_recvmsg` it is only filled by the time
+/// the call returns successfully.
+///
+/// `EAGAIN` is not counted for the received messages, as it is how non-blocking sockets
+/// report that there is no data to read.
+fn try_return_call(ctx: FExitContext, direction: Direction, prot: u8) -> Result<u32, u32> {
+    let pid_tgid = bpf_get_current_pid_tgid();
+    let start_ns = unsafe { ACTIVE_CALLS.get(&pid_tgid) }.copied();
+    let _ = ACTIVE_CALLS.remove(&pid_tgid);
+
+    let now = unsafe { bpf_ktime_get_ns() };
+    let sk = unsafe { ctx.arg::<*const sock>(0) } as *mut sock;
+    let msg = unsafe { ctx.arg::<*const msghdr>(1) } as *mut msghdr;
+    // The functions return an int, the upper half of the register is undefined.
+    let ret = func_ret(&ctx).ok_or(1u32)? as i32;
+    let (mut flow_key, owner) = match flow_of(sk, prot) {
+        Some(flow) => flow,
+        None => return Ok(0),
+    };
+
+    if prot == UDP && (ret > 0 || matches!(direction, Direction::Egress)) {
+        if let Some((addr, port)) = read_msg_name(msg) {
+            flow_key.dest_addr = addr;
+            flow_key.dest_port = port;
+        }
+    }
+
+    if ret < 0 && -ret == EAGAIN && matches!(direction, Direction::Ingress) {
+        return Ok(0);
+    }
+
+    if prot == UDP && matches!(direction, Direction::Ingress) && ret > 0 {
+        count_message(direction, &flow_key, owner, sk, prot, ret as u64, now)?;
+    } else if ret < 0 {
+        update_flow(direction, &flow_key, |stats| {
+            stats.owner = owner;
+            stats.errors[errno_slot(-ret)] += 1;
+        })?;
+    }
+
+    // The time of the call is missing if it started before the socket was tracked, or was
+    // evicted by newer calls.
+    if let Some(start_ns) = start_ns {
+        let size = (ret > 0).then(|| size_bucket(ret as u64));
+        update_histograms(direction, &flow_key, latency_bucket(now - start_ns), size)?;
+    }
+
+    Ok(0)
+}
+
+/// Count the message against the flow, along with the health metrics and buffers of the
+/// socket sampled at the same time.
+#[inline(always)]
+fn count_message(
+    direction: Direction,
+    flow_key: &FlowKey,
+    owner: Owner,
+    sk: *mut sock,
+    prot: u8,
+    bytes: u64,
+    now: u64,
+) -> Result<u32, u32> {
+    let health = if prot == TCP { read_tcp_health(sk) } else { None };
+    let buffers = read_sock_buffers(sk, prot);
+    update_flow(direction, flow_key, |stats| {
+        stats.owner = owner;
+        stats.bytes += bytes;
+        stats.messages += 1;
+
+        if let Some(health) = health {
+            stats.sampled_ns = now;
+            stats.srtt_us = health.srtt_us;
+            stats.snd_cwnd = health.snd_cwnd;
+            stats.total_retrans = health.total_retrans;
+        }
+
+        if let Some(buffers) = buffers {
+            stats.buffers_sampled_ns = now;
+            stats.wmem_queued = buffers.wmem_queued;
+            stats.sndbuf = buffers.sndbuf;
+            stats.rmem_alloc = buffers.rmem_alloc;
+            stats.rcvbuf = buffers.rcvbuf;
+        }
+    })?;
+
+    Ok(0)
+}
+
+/// Return value of the function the fexit program is attached to.
+#[inline(always)]
+fn func_ret(ctx: &FExitContext) -> Option<u64> {
+    let mut ret = 0u64;
+    (unsafe { bpf_get_func_ret(ctx.as_ptr(), &mut ret) } == 0).then_some(ret)
+}
+
+/// Count the retransmit against the egress flow of the socket. This can run in softirq
+/// context, so only sockets already seen by the other probes are considered.
+///
+/// The tcp tracepoints are attached as tp_btf, which give the socket by BTF for its storage.
+/// https://github.com/torvalds/linux/blob/1a1d569a75f3ab2923cb62daf356d102e4df2b86/include/trace/events/tcp.h
+fn try_tcp_retransmit_skb(ctx: BtfTracePointContext) -> Result<u32, u32> {
+    let sk = unsafe { ctx.arg::<*const sock>(0) } as *mut sock;
+    let info = match sock_cache::get(sk) {
+        Some(info) if info.verdict == VERDICT_TRACK => info,
+        _ => return Ok(0),
     };
+
+    update_flow(Direction::Egress, &info.flow_key, |stats| {
+        stats.retransmits += 1;
+        stats.owner = info.owner;
+    })?;
+
+    Ok(0)
 }
 
-process_kprobe_func!(try_tcp_sendmsg_kprobe, EGRESS_TRACKER_0, EGRESS_TRACKER_1, TCP);
-process_kprobe_func!(try_tcp_recvmsg_kprobe, INGRESS_TRACKER_0, INGRESS_TRACKER_1, TCP);
-process_kprobe_func!(try_udp_sendmsg_kprobe, EGRESS_TRACKER_0, EGRESS_TRACKER_1, UDP);
-process_kprobe_func!(try_udp_recvmsg_kprobe, INGRESS_TRACKER_0, INGRESS_TRACKER_1, UDP);
+/// Count the reset sent or received against the flow of the socket. Resets are sent for
+/// segments without a socket as well, which are skipped.
+fn try_tcp_reset(ctx: BtfTracePointContext, direction: Direction) -> Result<u32, u32> {
+    let sk = unsafe { ctx.arg::<*const sock>(0) } as *mut sock;
+    if sk.is_null() {
+        return Ok(0);
+    }
+
+    let info = match sock_cache::get(sk) {
+        Some(info) if info.verdict == VERDICT_TRACK => info,
+        _ => return Ok(0),
+    };
 
-#[kprobe]
-pub fn tcp_sendmsg_kprobe(ctx: ProbeContext) -> u32 {
-    match try_tcp_sendmsg_kprobe(ctx) {
+    update_flow(direction, &info.flow_key, |stats| {
+        match direction {
+            Direction::Egress => stats.resets_sent += 1,
+            Direction::Ingress => stats.resets_received += 1,
+        }
+        stats.owner = info.owner;
+    })?;
+
+    Ok(0)
+}
+
+#[fentry(function = "tcp_sendmsg")]
+pub fn tcp_sendmsg_fentry(ctx: FEntryContext) -> u32 {
+    match try_enter_call(ctx, Direction::Egress, TCP) {
         Ok(ret) => ret,
         Err(ret) => ret,
     }
 }
 
-#[kprobe]
-pub fn tcp_recvmsg_kprobe(ctx: ProbeContext) -> u32 {
-    match try_tcp_recvmsg_kprobe(ctx) {
+#[fexit(function = "tcp_sendmsg")]
+pub fn tcp_sendmsg_fexit(ctx: FExitContext) -> u32 {
+    match try_return_call(ctx, Direction::Egress, TCP) {
         Ok(ret) => ret,
         Err(ret) => ret,
     }
 }
 
-#[kprobe]
-pub fn udp_sendmsg_kprobe(ctx: ProbeContext) -> u32 {
-    match try_udp_sendmsg_kprobe(ctx) {
+#[fentry(function = "tcp_recvmsg")]
+pub fn tcp_recvmsg_fentry(ctx: FEntryContext) -> u32 {
+    match try_enter_call(ctx, Direction::Ingress, TCP) {
         Ok(ret) => ret,
         Err(ret) => ret,
     }
 }
 
-#[kprobe]
-pub fn udp_recvmsg_kprobe(ctx: ProbeContext) -> u32 {
-    match try_udp_recvmsg_kprobe(ctx) {
+#[fexit(function = "tcp_recvmsg")]
+pub fn tcp_recvmsg_fexit(ctx: FExitContext) -> u32 {
+    match try_return_call(ctx, Direction::Ingress, TCP) {
         Ok(ret) => ret,
         Err(ret) => ret,
     }
 }
 
-/// Unwrap flow info from TCP/UDP send and recv msg. In all of the APIs the 3rd parameter
-/// denotes the size.
-/// 
-/// tcp_sendmsg: https://github.com/torvalds/linux/blob/1a1d569a75f3ab2923cb62daf356d102e4df2b86/net/ipv4/tcp.c#L1361
-/// tcp_recvmsg: https://github.com/torvalds/linux/blob/1a1d569a75f3ab2923cb62daf356d102e4df2b86/net/ipv4/tcp.c#L2863
-/// 
-/// udp_sendmsg: https://github.com/torvalds/linux/blob/1a1d569a75f3ab2923cb62daf356d102e4df2b86/net/ipv4/udp.c#L1270
-/// udp_recvmsg: https://github.com/torvalds/linux/blob/1a1d569a75f3ab2923cb62daf356d102e4df2b86/net/ipv4/udp.c#L2025
-fn unwrap_flow_info(ctx: &ProbeContext, prot: u8) -> Option<(FlowKey, usize)> {
-    let sock: *mut sock = ctx.arg(0)?;
-    let len: usize = ctx.arg(2)?;
-    let sk_common = unsafe {
-        bpf_probe_read_kernel(&(*sock).__sk_common as *const sock_common).ok()?
-    };
+#[fentry(function = "udp_sendmsg")]
+pub fn udp_sendmsg_fentry(ctx: FEntryContext) -> u32 {
+    match try_enter_call(ctx, Direction::Egress, UDP) {
+        Ok(ret) => ret,
+        Err(ret) => ret,
+    }
+}
 
+#[fexit(function = "udp_sendmsg")]
+pub fn udp_sendmsg_fexit(ctx: FExitContext) -> u32 {
+    match try_return_call(ctx, Direction::Egress, UDP) {
+        Ok(ret) => ret,
+        Err(ret) => ret,
+    }
+}
 
-    if let Some(pid) = CONFIG.get_ptr(&ConfigKey::PID) {
-        unsafe {
-            if *pid as u32 != bpf_get_current_pid_tgid() as u32 {
-                return None;
-            }
-        }
+#[fentry(function = "udp_recvmsg")]
+pub fn udp_recvmsg_fentry(ctx: FEntryContext) -> u32 {
+    match try_enter_call(ctx, Direction::Ingress, UDP) {
+        Ok(ret) => ret,
+        Err(ret) => ret,
+    }
+}
+
+#[fexit(function = "udp_recvmsg")]
+pub fn udp_recvmsg_fexit(ctx: FExitContext) -> u32 {
+    match try_return_call(ctx, Direction::Ingress, UDP) {
+        Ok(ret) => ret,
+        Err(ret) => ret,
     }
+}
+
+#[btf_tracepoint(function = "tcp_retransmit_skb")]
+pub fn tcp_retransmit_skb(ctx: BtfTracePointContext) -> u32 {
+    match try_tcp_retransmit_skb(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => ret,
+    }
+}
+
+#[btf_tracepoint(function = "tcp_send_reset")]
+pub fn tcp_send_reset(ctx: BtfTracePointContext) -> u32 {
+    match try_tcp_reset(ctx, Direction::Egress) {
+        Ok(ret) => ret,
+        Err(ret) => ret,
+    }
+}
+
+#[btf_tracepoint(function = "tcp_receive_reset")]
+pub fn tcp_receive_reset(ctx: BtfTracePointContext) -> u32 {
+    match try_tcp_reset(ctx, Direction::Ingress) {
+        Ok(ret) => ret,
+        Err(ret) => ret,
+    }
+}
+
+#[btf_tracepoint(function = "inet_sock_set_state")]
+pub fn inet_sock_set_state(ctx: BtfTracePointContext) -> u32 {
+    match conn_tracker::try_inet_sock_set_state(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => ret,
+    }
+}
 
-    if let Some(tid) = CONFIG.get_ptr(&ConfigKey::TID) {
-        unsafe {
-            if *tid as u32 != (bpf_get_current_pid_tgid() >> 32) as u32 {
-                return None;
-            }
+/// Connection is accepted by the process.
+///
+/// inet_csk_accept: https://github.com/torvalds/linux/blob/1a1d569a75f3ab2923cb62daf356d102e4df2b86/net/ipv4/inet_connection_sock.c#L659
+#[fexit(function = "inet_csk_accept")]
+pub fn inet_csk_accept_fexit(ctx: FExitContext) -> u32 {
+    if let Some(sock) = func_ret(&ctx) {
+        if sock != 0 {
+            conn_tracker::accepted(sock as *mut sock);
         }
     }
 
-    match sk_common.skc_family {
-        AF_INET6 => {
-            // Skipping handling Ipv6 traffic for now. 
-            return None;
-        },
-        AF_INET => {
+    0
+}
 
-            // Network stores in big endian. Not sure if the values in kernel are defined as
-            // big endian or native endian. So converting all of them as it would be no-op 
-            // if it is already big endian.
-            let src_addr = u32::from_be(unsafe {
-                sk_common.__bindgen_anon_1.__bindgen_anon_1.skc_rcv_saddr
-            });
+/// Flow key and owner of the socket if it is tracked.
+#[inline(always)]
+fn flow_of(sock: *mut sock, prot: u8) -> Option<(FlowKey, Owner)> {
+    let info = sock_cache::lookup(sock, prot)?;
+    if info.verdict != VERDICT_TRACK {
+        return None;
+    }
 
-            let dest_addr = u32::from_be(unsafe {
-                sk_common.__bindgen_anon_1.__bindgen_anon_1.skc_daddr
-            });
+    Some((info.flow_key, info.owner))
+}
+
+/// Read the IpV4 address and port from `msg_name` of the message if present.
+///
+/// `msg_name` points to a kernel copy of the address by the time it reaches the protocol.
+#[inline(always)]
+fn read_msg_name(msg: *mut msghdr) -> Option<(u32, u16)> {
+    let name = unsafe { bpf_probe_read_kernel(&(*msg).msg_name as *const *mut c_void).ok()? };
+    if name.is_null() {
+        return None;
+    }
 
-            let src_port = u16::from_be(unsafe {
-                sk_common.__bindgen_anon_3.__bindgen_anon_1.skc_num
-            });
+    let addr = unsafe { bpf_probe_read_kernel(name as *const SockaddrIn).ok()? };
+    if addr.sin_family != AF_INET {
+        return None;
+    }
 
-            let dest_port = u16::from_be(unsafe {
-                sk_common.__bindgen_anon_3.__bindgen_anon_1.skc_dport
-            });
+    Some((u32::from_be(addr.sin_addr), u16::from_be(addr.sin_port)))
+}
 
-            let flow_key = FlowKey::new(src_addr, dest_addr, src_port, dest_port, prot);
-            return Some((flow_key, len));
-        },
-        _ => {
-            return None;
+/// Sample the health metrics of the TCP socket using the offsets resolved by the user program.
+#[inline(always)]
+fn read_tcp_health(sk: *mut sock) -> Option<TcpHealth> {
+    let offsets = TCP_SOCK_OFFSETS.get(0)?;
+    if offsets.valid == 0 {
+        return None;
+    }
+
+    let base = sk as *const u8;
+    let read = |offset: u32| unsafe {
+        bpf_probe_read_kernel(base.add(offset as usize) as *const u32).ok()
+    };
+
+    Some(TcpHealth {
+        srtt_us: read(offsets.srtt_us)?,
+        snd_cwnd: read(offsets.snd_cwnd)?,
+        total_retrans: read(offsets.total_retrans)?,
+    })
+}
+
+/// Sample the occupancy and size of the socket buffers.
+///
+/// TCP accounts the data waiting to be sent (or acked) in `sk_wmem_queued`, while UDP only
+/// accounts the data handed to the device in `sk_wmem_alloc`, which starts at 1.
+#[inline(always)]
+fn read_sock_buffers(sk: *mut sock, prot: u8) -> Option<SockBuffers> {
+    unsafe {
+        let wmem_queued = if prot == TCP {
+            bpf_probe_read_kernel(&(*sk).sk_wmem_queued).ok()? as u32
+        } else {
+            let wmem_alloc = bpf_probe_read_kernel(&(*sk).sk_wmem_alloc.refs.counter).ok()?;
+            wmem_alloc.saturating_sub(1) as u32
+        };
+
+        Some(SockBuffers {
+            wmem_queued,
+            sndbuf: bpf_probe_read_kernel(&(*sk).sk_sndbuf).ok()? as u32,
+            rmem_alloc: bpf_probe_read_kernel(&(*sk).sk_backlog.rmem_alloc.counter).ok()? as u32,
+            rcvbuf: bpf_probe_read_kernel(&(*sk).sk_rcvbuf).ok()? as u32,
+        })
+    }
+}
+
+/// Filter provided by the user, None until it is set. The filter is read in place, as it is
+/// too large for the stack, and the value stays valid for the duration of the program even
+/// if the user program replaces it in the meantime.
+#[inline(always)]
+fn current_filter() -> Option<&'static FilterConfig> {
+    unsafe { CONFIG.get(&ConfigKey::FILTER) }
+}
+
+/// User of the current task.
+#[inline(always)]
+fn current_uid() -> u32 {
+    bpf_get_current_uid_gid() as u32
+}
+
+/// Check if the thread of the user should be filtered out based on the filter provided by
+/// the user.
+#[inline(always)]
+fn filter_out(filter: Option<&FilterConfig>, pid_tgid: u64, uid: u32) -> bool {
+    filter.is_some_and(|filter| {
+        !filter.matches(pid_tgid, uid)
+            || (filter.flags & FILTER_TRACED != 0 && !contains(&TRACED, pid_tgid))
+            || (filter.flags & FILTER_ALLOWED != 0 && !contains(&ALLOWED, pid_tgid))
+    })
+}
+
+/// Whether the process of the thread is in the set of processes.
+#[inline(always)]
+fn contains(processes: &HashMap<u32, u32>, pid_tgid: u64) -> bool {
+    unsafe { processes.get(&((pid_tgid >> 32) as u32)) }.is_some()
+}
+
+/// Add the child to the traced and allowed processes when its parent is. The tracepoint also
+/// fires for new threads, which are skipped as the processes are looked up by the pid of the
+/// thread group.
+fn try_task_newtask(ctx: TracePointContext) -> Result<u32, u32> {
+    let pid_tgid = bpf_get_current_pid_tgid();
+    let traced = contains(&TRACED, pid_tgid);
+    let allowed = contains(&ALLOWED, pid_tgid);
+    if !traced && !allowed {
+        return Ok(0);
+    }
+
+    let clone_flags: u64 = unsafe { ctx.read_at(TASK_NEWTASK_CLONE_FLAGS_OFFSET).map_err(|_| 1u32)? };
+    if clone_flags & CLONE_THREAD != 0 {
+        return Ok(0);
+    }
+
+    let parent = (pid_tgid >> 32) as u32;
+    let child: i32 = unsafe { ctx.read_at(TASK_NEWTASK_PID_OFFSET).map_err(|_| 1u32)? };
+    if traced {
+        TRACED.insert(&(child as u32), &parent, 0).map_err(|_| 1u32)?;
+    }
+    if allowed {
+        ALLOWED.insert(&(child as u32), &parent, 0).map_err(|_| 1u32)?;
+    }
+    Ok(0)
+}
+
+#[tracepoint]
+pub fn task_newtask(ctx: TracePointContext) -> u32 {
+    match try_task_newtask(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => ret,
+    }
+}
+
+/// Thread is exiting, forget its process if it was traced or allowed once it is the last
+/// thread of the process.
+#[tracepoint]
+pub fn sched_process_exit(_ctx: TracePointContext) -> u32 {
+    let pid_tgid = bpf_get_current_pid_tgid();
+    if !group_dead(pid_tgid) {
+        return 0;
+    }
+
+    let pid = (pid_tgid >> 32) as u32;
+    let _ = TRACED.remove(&pid);
+    let _ = ALLOWED.remove(&pid);
+    0
+}
+
+/// Whether the exiting thread is the last one of its process. The count of live threads of the
+/// process is decremented before the tracepoint fires, so it is 0 for the last one.
+///
+/// Without the offsets the process is deemed gone with its leader thread, which only exits
+/// before the other threads when it calls `pthread_exit`.
+///
+/// do_exit: https://github.com/torvalds/linux/blob/1a1d569a75f3ab2923cb62daf356d102e4df2b86/kernel/exit.c
+#[inline(always)]
+fn group_dead(pid_tgid: u64) -> bool {
+    let leader = pid_tgid as u32 == (pid_tgid >> 32) as u32;
+    let offsets = match TASK_OFFSETS.get(0) {
+        Some(offsets) if offsets.valid != 0 => offsets,
+        _ => return leader,
+    };
+
+    unsafe {
+        let task = bpf_get_current_task() as *const u8;
+        let signal = match bpf_probe_read_kernel(task.add(offsets.signal as usize) as *const *const u8) {
+            Ok(signal) => signal,
+            Err(_) => return leader,
+        };
+        match bpf_probe_read_kernel(signal.add(offsets.live as usize) as *const i32) {
+            Ok(live) => live == 0,
+            Err(_) => leader,
         }
     }
 }
 
+/// UDP socket is being connected or disconnected, so its flow key would change.
+///
+/// ip4_datagram_connect: https://github.com/torvalds/linux/blob/1a1d569a75f3ab2923cb62daf356d102e4df2b86/net/ipv4/datagram.c#L85
+/// udp_disconnect: https://github.com/torvalds/linux/blob/1a1d569a75f3ab2923cb62daf356d102e4df2b86/net/ipv4/udp.c#L2113
+#[fentry(function = "ip4_datagram_connect")]
+pub fn ip4_datagram_connect_fentry(ctx: FEntryContext) -> u32 {
+    sock_cache::unresolve(unsafe { ctx.arg::<*const sock>(0) } as *mut sock);
+    0
+}
+
+#[fentry(function = "udp_disconnect")]
+pub fn udp_disconnect_fentry(ctx: FEntryContext) -> u32 {
+    sock_cache::unresolve(unsafe { ctx.arg::<*const sock>(0) } as *mut sock);
+    0
+}
+
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(_info: &core::panic::PanicInfo) -> ! {
@@ -0,0 +1,177 @@
+use core::ptr;
+
+use aya_ebpf::{
+    bindings::{bpf_map_type::BPF_MAP_TYPE_SK_STORAGE, BPF_F_NO_PREALLOC, BPF_SK_STORAGE_GET_F_CREATE},
+    cty::c_void,
+    helpers::{bpf_get_current_comm, bpf_get_current_pid_tgid, bpf_probe_read_kernel, bpf_sk_storage_get},
+};
+use flow_top_talker_common::common_types::{
+    FilterConfig, FlowKey, Owner, SockInfo, VERDICT_SKIP, VERDICT_TRACK, VERDICT_UNSUPPORTED,
+};
+
+use crate::{bindings::*, current_filter, current_uid, filter_out, AF_INET};
+
+/// Definition of the `BPF_MAP_TYPE_SK_STORAGE` map in the `.maps` section, which aya-ebpf has
+/// no map type for. The map is only described by the BTF of the fields: the value of `type`
+/// and `map_flags` is the length of the array they point to, the key and value are the types
+/// pointed to.
+#[repr(C)]
+pub struct SockStorage {
+    r#type: *const [i32; BPF_MAP_TYPE_SK_STORAGE as usize],
+    map_flags: *const [i32; BPF_F_NO_PREALLOC as usize],
+    key: *const i32,
+    value: *const SockInfo,
+}
+
+unsafe impl Sync for SockStorage {}
+
+/// Cache of the flow key, filter verdict and owner of each socket, stored along with the socket
+/// by the kernel and freed with it.
+///
+/// Socket storage is only reachable from the programs given the `sock` by BTF (fentry, fexit
+/// and tp_btf), so these are used by every program which looks the socket up.
+#[link_section = ".maps"]
+#[export_name = "SOCK_CACHE"]
+static SOCK_CACHE: SockStorage = SockStorage {
+    r#type: ptr::null(),
+    map_flags: ptr::null(),
+    key: ptr::null(),
+    value: ptr::null(),
+};
+
+/// Lookup info of the socket, populating the cache on first sight.
+///
+/// Owner is decided on first sight, so traffic of the socket is attributed to the same
+/// process even when it is later used by another thread. The filter verdict is decided for
+/// that owner, again whenever the filter changes.
+///
+/// Peer of a socket which is not connected (unconnected UDP) is left empty in the key, and
+/// is expected to be filled by the caller from the message.
+#[inline(always)]
+pub fn lookup(sk: *mut sock, prot: u8) -> Option<SockInfo> {
+    let filter = current_filter();
+    if let Some(info) = storage(sk, ptr::null_mut(), 0) {
+        refresh_verdict(info, filter);
+        if info.key_resolved == 0 && info.verdict == VERDICT_TRACK {
+            // Socket was connected to another peer since the key was cached.
+            info.flow_key = read_flow_key(sk, prot)?;
+            info.key_resolved = 1;
+        }
+
+        return Some(*info);
+    }
+
+    let pid_tgid = bpf_get_current_pid_tgid();
+    let uid = current_uid();
+    let mut info = SockInfo {
+        owner: Owner::new(pid_tgid, bpf_get_current_comm().unwrap_or_default()),
+        verdict: verdict(filter, pid_tgid, uid),
+        key_resolved: 1,
+        generation: generation(filter),
+        uid,
+        ..Default::default()
+    };
+
+    match read_flow_key(sk, prot) {
+        Some(flow_key) => info.flow_key = flow_key,
+        None => info.verdict = VERDICT_UNSUPPORTED,
+    }
+
+    let _ = storage(sk, &mut info, BPF_SK_STORAGE_GET_F_CREATE as u64);
+    Some(info)
+}
+
+/// Lookup info of the socket without populating the cache.
+///
+/// Used by the probes which can run in softirq context, where the current process is not the
+/// owner of the socket.
+#[inline(always)]
+pub fn get(sk: *mut sock) -> Option<SockInfo> {
+    let info = storage(sk, ptr::null_mut(), 0)?;
+    refresh_verdict(info, current_filter());
+    Some(*info)
+}
+
+/// Drop the cached flow key as the socket is being connected to another peer.
+#[inline(always)]
+pub fn unresolve(sk: *mut sock) {
+    if let Some(info) = storage(sk, ptr::null_mut(), 0) {
+        info.key_resolved = 0;
+    }
+}
+
+/// Storage of the socket, created from the value if it is not there yet and the flags ask for
+/// it. The socket must be the one given by BTF to the program.
+#[inline(always)]
+fn storage(sk: *mut sock, value: *mut SockInfo, flags: u64) -> Option<&'static mut SockInfo> {
+    let info = unsafe {
+        bpf_sk_storage_get(
+            &SOCK_CACHE as *const SockStorage as *mut c_void,
+            sk as *mut c_void,
+            value as *mut c_void,
+            flags,
+        )
+    };
+
+    unsafe { (info as *mut SockInfo).as_mut() }
+}
+
+/// Verdict of the filter for the thread of the user.
+#[inline(always)]
+pub fn verdict(filter: Option<&FilterConfig>, pid_tgid: u64, uid: u32) -> u8 {
+    if filter_out(filter, pid_tgid, uid) { VERDICT_SKIP } else { VERDICT_TRACK }
+}
+
+/// Generation of the filter, the filter which was never set is the generation 0.
+#[inline(always)]
+pub fn generation(filter: Option<&FilterConfig>) -> u32 {
+    filter.map_or(0, |filter| filter.generation)
+}
+
+/// Decide the verdict of the owner again if the filter changed since it was cached.
+#[inline(always)]
+fn refresh_verdict(info: &mut SockInfo, filter: Option<&FilterConfig>) {
+    if info.generation == generation(filter) {
+        return;
+    }
+
+    if info.verdict != VERDICT_UNSUPPORTED {
+        info.verdict = verdict(filter, info.owner.pid_tgid(), info.uid);
+    }
+    info.generation = generation(filter);
+}
+
+/// Read the flow key from `sock_common`. Returns None if the socket is not an IpV4 socket.
+#[inline(always)]
+pub fn read_flow_key(sk: *mut sock, prot: u8) -> Option<FlowKey> {
+    let sk_common = unsafe {
+        bpf_probe_read_kernel(&(*sk).__sk_common as *const sock_common).ok()?
+    };
+
+    if sk_common.skc_family != AF_INET {
+        // Skipping handling Ipv6 traffic for now.
+        return None;
+    }
+
+    // Network stores in big endian. Not sure if the values in kernel are defined as
+    // big endian or native endian. So converting all of them as it would be no-op
+    // if it is already big endian.
+    let src_addr = u32::from_be(unsafe {
+        sk_common.__bindgen_anon_1.__bindgen_anon_1.skc_rcv_saddr
+    });
+
+    let dest_addr = u32::from_be(unsafe {
+        sk_common.__bindgen_anon_1.__bindgen_anon_1.skc_daddr
+    });
+
+    // Unlike the rest, the local port is stored in host byte order.
+    let src_port = unsafe {
+        sk_common.__bindgen_anon_3.__bindgen_anon_1.skc_num
+    };
+
+    let dest_port = u16::from_be(unsafe {
+        sk_common.__bindgen_anon_3.__bindgen_anon_1.skc_dport
+    });
+
+    Some(FlowKey::new(src_addr, dest_addr, src_port, dest_port, prot))
+}
@@ -0,0 +1,230 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use anyhow::anyhow;
+
+/// Path of the BTF of the running kernel.
+pub const VMLINUX_BTF_PATH: &str = "/sys/kernel/btf/vmlinux";
+
+const BTF_MAGIC: u16 = 0xeb9f;
+const HEADER_LEN: usize = 24;
+const TYPE_LEN: usize = 12;
+
+const BTF_KIND_STRUCT: u32 = 4;
+const BTF_KIND_UNION: u32 = 5;
+
+/// Minimal reader of the kernel BTF, which is used to resolve offsets of the fields of kernel
+/// structures which are not part of the generated bindings.
+///
+/// Bindings are generated for a specific kernel, while BTF describes the running kernel, so it
+/// allows reading fields of big structures like `tcp_sock` without depending on its layout.
+/// https://docs.kernel.org/bpf/btf.html
+pub struct Btf {
+    data: Vec<u8>,
+    strings: usize,
+    /// Offset of each type in data, indexed by the type id (type id 0 is void).
+    types: Vec<usize>,
+}
+
+impl Btf {
+    pub fn from_sys_fs() -> anyhow::Result<Btf> {
+        Self::from_file(VMLINUX_BTF_PATH)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> anyhow::Result<Btf> {
+        Self::parse(fs::read(path)?)
+    }
+
+    pub fn parse(data: Vec<u8>) -> anyhow::Result<Btf> {
+        if data.len() < HEADER_LEN || u16::from_ne_bytes([data[0], data[1]]) != BTF_MAGIC {
+            return Err(anyhow!("Not a native endian BTF blob"));
+        }
+
+        let hdr_len = read_u32(&data, 4)? as usize;
+        let type_start = hdr_len + read_u32(&data, 8)? as usize;
+        let type_end = type_start + read_u32(&data, 12)? as usize;
+        let strings = hdr_len + read_u32(&data, 16)? as usize;
+
+        let mut types = vec![0];
+        let mut offset = type_start;
+        while offset < type_end {
+            types.push(offset);
+            let info = read_u32(&data, offset + 4)?;
+            offset += TYPE_LEN + trailing_len(kind(info), vlen(info))?;
+        }
+
+        Ok(Btf { data, strings, types })
+    }
+
+    /// Byte offsets of the requested members of the struct. Members of anonymous structs and
+    /// unions inside the struct are looked up as well.
+    pub fn member_offsets(
+        &self,
+        struct_name: &str,
+        members: &[&str],
+    ) -> anyhow::Result<HashMap<String, u32>> {
+        let type_id = (1..self.types.len())
+            .find(|id| {
+                let info = self.info(*id);
+                kind(info) == BTF_KIND_STRUCT && self.name(*id) == Some(struct_name)
+            })
+            .ok_or(anyhow!("Struct {} not found in BTF", struct_name))?;
+
+        let mut offsets = HashMap::new();
+        self.collect_members(type_id, 0, members, &mut offsets, 0);
+        Ok(offsets)
+    }
+
+    fn collect_members(
+        &self,
+        type_id: usize,
+        base_bits: u32,
+        members: &[&str],
+        offsets: &mut HashMap<String, u32>,
+        depth: usize,
+    ) {
+        // Anonymous members don't nest deeply, this is just a guard against bad input.
+        if depth > 8 {
+            return;
+        }
+
+        let offset = self.types[type_id];
+        let info = self.info(type_id);
+        for index in 0..vlen(info) {
+            let member = offset + TYPE_LEN + index * 12;
+            let (Ok(name_off), Ok(member_type), Ok(member_offset)) = (
+                read_u32(&self.data, member),
+                read_u32(&self.data, member + 4),
+                read_u32(&self.data, member + 8),
+            ) else {
+                return;
+            };
+
+            // With kind flag set, the upper 8 bits hold the bitfield size.
+            let bits = if info >> 31 == 1 { member_offset & 0xff_ffff } else { member_offset };
+            let bits = base_bits + bits;
+
+            if name_off == 0 {
+                if let Some(anon_id) = self.resolve_composite(member_type as usize) {
+                    self.collect_members(anon_id, bits, members, offsets, depth + 1);
+                }
+                continue;
+            }
+
+            if let Some(name) = self.string(name_off) {
+                if members.contains(&name) && bits.is_multiple_of(8) {
+                    offsets.insert(name.to_string(), bits / 8);
+                }
+            }
+        }
+    }
+
+    /// Resolve the type to a struct or union.
+    fn resolve_composite(&self, type_id: usize) -> Option<usize> {
+        if type_id == 0 || type_id >= self.types.len() {
+            return None;
+        }
+
+        match kind(self.info(type_id)) {
+            BTF_KIND_STRUCT | BTF_KIND_UNION => Some(type_id),
+            _ => None,
+        }
+    }
+
+    fn info(&self, type_id: usize) -> u32 {
+        read_u32(&self.data, self.types[type_id] + 4).unwrap_or_default()
+    }
+
+    fn name(&self, type_id: usize) -> Option<&str> {
+        self.string(read_u32(&self.data, self.types[type_id]).ok()?)
+    }
+
+    fn string(&self, offset: u32) -> Option<&str> {
+        let start = self.strings + offset as usize;
+        let len = self.data.get(start..)?.iter().position(|c| *c == 0)?;
+        std::str::from_utf8(&self.data[start..start + len]).ok()
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> anyhow::Result<u32> {
+    let bytes = data.get(offset..offset + 4).ok_or(anyhow!("Truncated BTF"))?;
+    Ok(u32::from_ne_bytes(bytes.try_into()?))
+}
+
+fn kind(info: u32) -> u32 {
+    (info >> 24) & 0x1f
+}
+
+fn vlen(info: u32) -> usize {
+    (info & 0xffff) as usize
+}
+
+/// Length of the data following the common type header for each kind.
+fn trailing_len(kind: u32, vlen: usize) -> anyhow::Result<usize> {
+    match kind {
+        // Int, Var and Decl tag.
+        1 | 14 | 17 => Ok(4),
+        // Array.
+        3 => Ok(12),
+        // Struct, Union, Datasec and Enum64.
+        4 | 5 | 15 | 19 => Ok(vlen * 12),
+        // Enum and Func proto.
+        6 | 13 => Ok(vlen * 8),
+        // Ptr, Fwd, Typedef, Volatile, Const, Restrict, Func, Float and Type tag.
+        2 | 7..=12 | 16 | 18 => Ok(0),
+        _ => Err(anyhow!("Unknown BTF kind {}", kind)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::btf::Btf;
+
+    /// Build a BTF blob with an int, an anonymous union and the struct using both.
+    fn sample_btf() -> Vec<u8> {
+        let strings = b"\0int\0tcp_sock\0srtt_us\0snd_cwnd\0total_retrans\0";
+        let name = |s: &str| {
+            let needle = format!("\0{}\0", s);
+            strings.windows(needle.len()).position(|w| w == needle.as_bytes()).unwrap() as u32 + 1
+        };
+
+        let mut types: Vec<u32> = Vec::new();
+        // [1] int, 4 bytes.
+        types.extend([name("int"), 1 << 24, 4, 32]);
+        // [2] anonymous union { u32 snd_cwnd; }
+        types.extend([0, (5 << 24) | 1, 4]);
+        types.extend([name("snd_cwnd"), 1, 0]);
+        // [3] struct tcp_sock { u32 srtt_us @ 1024; union @ 2048; u32 total_retrans:8 @ 4096 }
+        types.extend([name("tcp_sock"), (1 << 31) | (4 << 24) | 3, 1024]);
+        types.extend([name("srtt_us"), 1, 1024 * 8]);
+        types.extend([0, 2, 2048 * 8]);
+        types.extend([name("total_retrans"), 1, (8 << 24) | (4096 * 8)]);
+
+        let type_bytes: Vec<u8> = types.iter().flat_map(|t| t.to_ne_bytes()).collect();
+        let mut data = Vec::new();
+        data.extend(0xeb9fu16.to_ne_bytes());
+        data.extend([1, 0]);
+        for value in [24u32, 0, type_bytes.len() as u32, type_bytes.len() as u32, strings.len() as u32] {
+            data.extend(value.to_ne_bytes());
+        }
+        data.extend(type_bytes);
+        data.extend(strings);
+        data
+    }
+
+    #[test]
+    fn resolve_member_offsets() {
+        let btf = Btf::parse(sample_btf()).unwrap();
+        let offsets = btf.member_offsets("tcp_sock", &["srtt_us", "snd_cwnd", "total_retrans", "missing"]).unwrap();
+
+        assert_eq!(offsets.get("srtt_us"), Some(&1024));
+        assert_eq!(offsets.get("snd_cwnd"), Some(&2048));
+        assert_eq!(offsets.get("total_retrans"), Some(&4096));
+        assert_eq!(offsets.get("missing"), None);
+    }
+
+    #[test]
+    fn reject_bad_input() {
+        assert!(Btf::parse(vec![0; 4]).is_err());
+        assert!(Btf::parse(sample_btf()).unwrap().member_offsets("sock", &["srtt_us"]).is_err());
+    }
+}
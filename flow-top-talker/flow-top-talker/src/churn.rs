@@ -0,0 +1,141 @@
+use std::{collections::HashMap, time::Duration};
+
+use flow_top_talker_common::common_types::{
+    ConnEvent, Owner, CLOSE_FAILED, CLOSE_RESET, CONN_CLOSE, CONN_OPEN,
+};
+use serde::{Deserialize, Serialize};
+
+/// Key to aggregate the connections by, the owning process and the remote endpoint.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ChurnKey {
+    pub owner: Owner,
+    pub remote_addr: u32,
+    pub remote_port: u16,
+}
+
+/// Connections opened and closed in the interval for a process and remote endpoint.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChurnStats {
+    pub opened: u32,
+    pub closed: u32,
+    pub short_lived: u32,
+    pub resets: u32,
+    pub failed: u32,
+    pub total_duration_ns: u64,
+}
+
+impl ChurnStats {
+    /// Average lifetime of the connections closed in the interval.
+    pub fn avg_duration(&self) -> Option<Duration> {
+        if self.closed == 0 {
+            return None;
+        }
+
+        Some(Duration::from_nanos(self.total_duration_ns / self.closed as u64))
+    }
+}
+
+/// Aggregates the connection events received in the interval.
+pub struct ChurnTracker {
+    short_lived: Duration,
+    endpoints: HashMap<ChurnKey, ChurnStats>,
+}
+
+impl ChurnTracker {
+    /// Connections closed within `short_lived` of being opened are counted as short-lived.
+    pub fn new(short_lived: Duration) -> Self {
+        Self {
+            short_lived,
+            endpoints: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, event: &ConnEvent) {
+        let key = ChurnKey {
+            owner: event.owner,
+            remote_addr: event.flow_key.dest_addr,
+            remote_port: event.flow_key.dest_port,
+        };
+        let stats = self.endpoints.entry(key).or_default();
+
+        match event.kind {
+            CONN_OPEN => stats.opened += 1,
+            CONN_CLOSE => {
+                stats.closed += 1;
+                stats.total_duration_ns += event.duration_ns;
+                // Connections which failed to open are not counted as short-lived.
+                match event.close_reason {
+                    CLOSE_FAILED => stats.failed += 1,
+                    CLOSE_RESET => stats.resets += 1,
+                    _ => {}
+                }
+
+                if event.close_reason != CLOSE_FAILED
+                    && Duration::from_nanos(event.duration_ns) < self.short_lived
+                {
+                    stats.short_lived += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Top N endpoints by the number of connections opened and closed in the interval.
+    pub fn top(&self, top_n: usize) -> Vec<(ChurnKey, ChurnStats)> {
+        let mut endpoints: Vec<_> = self.endpoints.iter().map(|(key, stats)| (*key, *stats)).collect();
+        endpoints.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.opened + stats.closed));
+        endpoints.truncate(top_n);
+        endpoints
+    }
+
+    pub fn clear(&mut self) {
+        self.endpoints.clear()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use flow_top_talker_common::common_types::{
+        ConnEvent, FlowKey, CLOSE_FAILED, CLOSE_LOCAL, CLOSE_RESET, CONN_CLOSE, CONN_OPEN, TCP,
+    };
+
+    use crate::churn::ChurnTracker;
+
+    fn event(dest_port: u16, kind: u8, duration_ms: u64, close_reason: u8) -> ConnEvent {
+        ConnEvent {
+            flow_key: FlowKey::new(1, 2, 40000, dest_port, TCP),
+            duration_ns: duration_ms * 1_000_000,
+            kind,
+            close_reason,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn aggregate_by_endpoint() {
+        let mut tracker = ChurnTracker::new(Duration::from_secs(1));
+        tracker.record(&event(443, CONN_OPEN, 0, 0));
+        tracker.record(&event(443, CONN_OPEN, 0, 0));
+        tracker.record(&event(443, CONN_CLOSE, 10, CLOSE_LOCAL));
+        tracker.record(&event(443, CONN_CLOSE, 3000, CLOSE_RESET));
+        tracker.record(&event(80, CONN_CLOSE, 10, CLOSE_FAILED));
+
+        let top = tracker.top(10);
+        assert_eq!(top.len(), 2);
+
+        let (key, stats) = top[0];
+        assert_eq!(key.remote_port, 443);
+        assert_eq!((stats.opened, stats.closed, stats.short_lived, stats.resets), (2, 2, 1, 1));
+        assert_eq!(stats.avg_duration(), Some(Duration::from_millis(1505)));
+
+        let (key, stats) = top[1];
+        assert_eq!(key.remote_port, 80);
+        assert_eq!((stats.closed, stats.failed, stats.short_lived), (1, 1, 0));
+
+        assert_eq!(tracker.top(1).len(), 1);
+        tracker.clear();
+        assert!(tracker.top(10).is_empty());
+    }
+}
@@ -1,22 +1,189 @@
-use clap::Parser;
+use std::{path::PathBuf, time::Duration};
+
+use clap::{CommandFactory, FromArgMatches, Parser};
+use flow_top_talker_common::common_types::DEFAULT_MAX_FLOWS;
+
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::{
+    config,
+    connection::SortMetric,
+    report::ReportFormat,
+    theme::ThemeName,
+    ui::View,
+    units::{parse_duration, parse_rate},
+};
+
+/// Socket the daemon listens on by default.
+pub const DEFAULT_SOCKET: &str = "/run/flow-top-talker.sock";
+
+/// Whether the tool collects the flows itself, for its clients, or views the ones collected
+/// by a daemon. A report collects the flows for a while and prints the top ones.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    Local,
+    Daemon,
+    Client,
+    Report,
+}
 
 /// Arguments which can be passed to the tool to provide the top N flows.
 #[derive(Parser, Debug)]
 #[command(version, about)]
 pub struct Cli {
-    /// Provide the top N flows. 
-    #[arg(short = 'n', long)]
+    /// Provide the top N flows.
+    #[arg(short = 'n', long, default_value_t = 10)]
     pub top_n: usize,
 
-    /// Filter by process id.
-    #[arg(short = 'p', long)]
-    pub pid: Option<u64>,
+    /// Filter by process id, covering all of its threads. Can be repeated or comma separated.
+    #[arg(short = 'p', long, value_delimiter = ',')]
+    pub pid: Vec<u32>,
+
+    /// Filter by thread id. Can be repeated or comma separated.
+    #[arg(short = 't', long, value_delimiter = ',')]
+    pub tid: Vec<u32>,
+
+    /// Filter by user id. Can be repeated or comma separated.
+    #[arg(long, value_delimiter = ',')]
+    pub uid: Vec<u32>,
+
+    /// Filter by user name, resolved to its user id. Can be repeated or comma separated.
+    #[arg(long, value_delimiter = ',')]
+    pub user: Vec<String>,
+
+    /// Filter by process name, a glob (`nginx*`) or a regex prefixed with `re:`. Can be repeated.
+    #[arg(long, value_name = "PATTERN")]
+    pub comm: Vec<String>,
 
-    /// Filter by thread id.
-    #[arg(short = 't', long)]
-    pub tid: Option<u64>,
+    /// Filter by executable path, a glob (`/usr/sbin/*`) or a regex prefixed with `re:`. Can be
+    /// repeated.
+    #[arg(long, value_name = "PATTERN")]
+    pub exe: Vec<String>,
 
     /// Display hostname. By default Ip address would be displayed.
     #[arg(short = 'x', long, default_value_t = false)]
     pub host_name: bool,
-}
\ No newline at end of file
+
+    /// Maximum number of flows each tracker map can hold.
+    #[arg(short = 'm', long, default_value_t = DEFAULT_MAX_FLOWS)]
+    pub max_flows: u32,
+
+    /// Budget for locked memory used by the tracker maps in MiB.
+    #[arg(short = 'b', long)]
+    pub memory_budget: Option<u64>,
+
+    /// Shrink max flows to fit the memory budget instead of exiting.
+    #[arg(long, default_value_t = false)]
+    pub auto_shrink: bool,
+
+    /// Connections closed within this many milliseconds are counted as short-lived.
+    #[arg(long, default_value_t = 1000)]
+    pub short_lived_ms: u64,
+
+    /// Interval at which the flows are refreshed in milliseconds, rates are still shown per
+    /// second.
+    #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(u64).range(100..))]
+    pub interval_ms: u64,
+
+    /// View to start with, can be switched with `v`.
+    #[arg(long, value_enum, default_value_t = View::Split)]
+    pub view: View,
+
+    /// Metric to rank the connections by in the combined view, can be switched with `s`.
+    #[arg(long, value_enum, default_value_t = SortMetric::Total)]
+    pub sort: SortMetric,
+
+    /// Aggregate the connections by service in the combined view, can be switched with `a`.
+    #[arg(long, default_value_t = false)]
+    pub by_service: bool,
+
+    /// Display throughput in bits per second instead of bytes per second.
+    #[arg(long, default_value_t = false)]
+    pub bits: bool,
+
+    /// Throughput in bytes per second above which it is highlighted as a warning, accepts
+    /// K, M and G suffixes.
+    #[arg(long, value_parser = parse_rate, default_value = "10000")]
+    pub warn_rate: u64,
+
+    /// Throughput in bytes per second above which it is highlighted as critical, accepts
+    /// K, M and G suffixes.
+    #[arg(long, value_parser = parse_rate, default_value = "100000")]
+    pub crit_rate: u64,
+
+    /// Theme of the TUI, monochrome is also used when `NO_COLOR` is set.
+    #[arg(long, value_enum, default_value_t = ThemeName::Color)]
+    pub theme: ThemeName,
+
+    /// Name of a host (ADDR=NAME) or an endpoint (ADDR:PORT=NAME), can be repeated.
+    #[arg(long, value_name = "LABEL")]
+    pub label: Vec<String>,
+
+    /// Collect and view the flows locally, serve them on the socket as a daemon, view the ones
+    /// served by the daemon as a client, or print a report of the top flows once collected for
+    /// the duration or iterations.
+    #[arg(long, value_enum, default_value_t = Mode::Local)]
+    pub mode: Mode,
+
+    /// Collect for this long in report mode, e.g. 30s, 5m or 1h.
+    #[arg(long, value_parser = parse_duration, conflicts_with = "iterations")]
+    pub duration: Option<Duration>,
+
+    /// Number of intervals to collect for in report mode.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub iterations: Option<u64>,
+
+    /// Format of the report printed in report mode, or once the traced command exits.
+    #[arg(long, value_enum, default_value_t = ReportFormat::Table)]
+    pub format: ReportFormat,
+
+    /// Socket the daemon listens on and the client connects to.
+    #[arg(long, default_value = DEFAULT_SOCKET)]
+    pub socket: PathBuf,
+
+    /// Group whose members can connect to the daemon, only its user can otherwise.
+    #[arg(long)]
+    pub socket_group: Option<String>,
+
+    /// Directory in bpffs to pin the maps and programs in, so collection continues while the
+    /// tool is not running. Maps and programs already pinned there are re-used.
+    #[arg(long)]
+    pub pin_path: Option<PathBuf>,
+
+    /// Remove the maps and programs pinned in the pin path, which stops the collection, and exit.
+    #[arg(long, requires = "pin_path")]
+    pub unpin: bool,
+
+    /// Config file, read after the system (/etc/flow-top-talker/config.toml) and user
+    /// (~/.config/flow-top-talker/config.toml) config files.
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+
+    /// Profile of the config files to merge over their defaults.
+    #[arg(long)]
+    pub profile: Option<String>,
+
+    /// Command to run, only its flows and the ones of the processes it forks are tracked. The
+    /// tool exits with a report of its flows once it finishes.
+    #[arg(last = true, value_name = "COMMAND")]
+    pub command: Vec<String>,
+}
+
+impl Cli {
+    /// Parse the flags and merge them over the options of the config files, so the flags
+    /// provided on the command line win.
+    pub fn load() -> anyhow::Result<Cli> {
+        let matches = Cli::command().get_matches();
+        let mut cli = Cli::from_arg_matches(&matches)?;
+        let options = config::load(cli.config.as_deref(), cli.profile.as_deref())?;
+        options.apply(&mut cli, &matches)?;
+        Ok(cli)
+    }
+
+    /// Rate per second of the value counted over an interval.
+    pub fn per_second(&self, value: u64) -> u64 {
+        (value as u128 * 1000 / self.interval_ms as u128) as u64
+    }
+}
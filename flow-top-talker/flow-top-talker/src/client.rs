@@ -0,0 +1,74 @@
+use std::{
+    fs,
+    io::BufReader,
+    os::unix::{fs::MetadataExt, net::UnixStream},
+    path::Path,
+    sync::mpsc::{self, Receiver, TryRecvError},
+    thread,
+};
+
+use anyhow::{anyhow, bail, Context};
+
+use crate::{
+    filter::Filter,
+    protocol::{receive, send, Message, Request},
+};
+
+/// Client of the daemon, which receives the snapshots on a separate thread so the TUI stays
+/// responsive.
+pub struct Client {
+    stream: UnixStream,
+    messages: Receiver<anyhow::Result<Message>>,
+    pub interval_ms: u64,
+    pub top_n: usize,
+    pub filter: Filter,
+}
+
+impl Client {
+    /// Connect to the daemon listening on the socket.
+    ///
+    /// The socket must be owned by root or the current user, so another user can't pose as
+    /// the daemon.
+    pub fn connect(path: &Path) -> anyhow::Result<Self> {
+        let owner = fs::metadata(path)
+            .with_context(|| format!("Failed to read {}, is the daemon running?", path.display()))?
+            .uid();
+        if owner != 0 && owner != unsafe { libc::geteuid() } {
+            bail!("{} is owned by uid {}, not by root or the current user", path.display(), owner);
+        }
+
+        let stream = UnixStream::connect(path)
+            .with_context(|| format!("Failed to connect to {}", path.display()))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let (interval_ms, top_n, filter) = match receive(&mut reader)? {
+            Some(Message::Hello { interval_ms, top_n, filter }) => (interval_ms, top_n, filter),
+            _ => bail!("Daemon didn't greet the client"),
+        };
+
+        let (sender, messages) = mpsc::channel();
+        thread::spawn(move || loop {
+            let message = receive(&mut reader)
+                .and_then(|message| message.ok_or_else(|| anyhow!("Daemon closed the connection")));
+            let failed = message.is_err();
+            if sender.send(message).is_err() || failed {
+                return;
+            }
+        });
+
+        Ok(Self { stream, messages, interval_ms, top_n, filter })
+    }
+
+    /// Ask the daemon to change the filter, the reply is received as a message.
+    pub fn set_filter(&mut self, filter: Filter) -> anyhow::Result<()> {
+        send(&mut self.stream, &Request::SetFilter { filter })
+    }
+
+    /// Next message received from the daemon, if any.
+    pub fn try_receive(&self) -> anyhow::Result<Option<Message>> {
+        match self.messages.try_recv() {
+            Ok(message) => message.map(Some),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(anyhow!("Daemon closed the connection")),
+        }
+    }
+}
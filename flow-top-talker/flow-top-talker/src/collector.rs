@@ -0,0 +1,102 @@
+use std::time::Duration;
+
+use log::warn;
+
+use crate::{
+    churn::ChurnTracker,
+    cli::Cli,
+    connection::ConnectionTable,
+    ebpf_handler::EbpfHandler,
+    filter::Filter,
+    flow_info::{FlowInfo, LimitedMaxHeap},
+    roles::Listeners,
+    rotation::WRITERS_TIMEOUT,
+    ui::Snapshot,
+};
+
+/// Loads and attaches the eBPF program, and collects a snapshot of the flows every interval.
+pub struct Collector {
+    ebpf_handler: EbpfHandler,
+    ingress_heap: LimitedMaxHeap,
+    egress_heap: LimitedMaxHeap,
+    connections: ConnectionTable,
+    churn: ChurnTracker,
+    listeners: Listeners,
+    top_n: usize,
+    flag: u32,
+}
+
+impl Collector {
+    pub fn new(cli: &Cli) -> anyhow::Result<Self> {
+        let mut ebpf_handler = EbpfHandler::init(cli)?;
+        ebpf_handler.set_filter(&Filter::from_cli(cli)?)?;
+        ebpf_handler.attach()?;
+        // Pinned programs carry on with the tracker they were writing to.
+        let flag = ebpf_handler.flag()?;
+
+        Ok(Self {
+            ebpf_handler,
+            ingress_heap: LimitedMaxHeap::new(cli.top_n),
+            egress_heap: LimitedMaxHeap::new(cli.top_n),
+            connections: ConnectionTable::default(),
+            churn: ChurnTracker::new(Duration::from_millis(cli.short_lived_ms)),
+            listeners: Listeners::default(),
+            top_n: cli.top_n,
+            flag,
+        })
+    }
+
+    pub fn ebpf_handler(&mut self) -> &mut EbpfHandler {
+        &mut self.ebpf_handler
+    }
+
+    /// Rotate the tracker maps and collect the flows of the interval which just ended.
+    pub fn collect(&mut self) -> anyhow::Result<Snapshot> {
+        self.churn.clear();
+        let writers_left = self.ebpf_handler.rotate_data(
+            &mut self.ingress_heap,
+            self.flag,
+            &mut self.egress_heap,
+            &mut self.connections,
+        )?;
+        self.ebpf_handler.drain_conn_events(&mut self.churn);
+        if let Err(e) = self.ebpf_handler.refresh_allowed() {
+            warn!("Failed to refresh the processes matching the filter: {}", e);
+        }
+
+        // Keep the previous listeners if they can't be scanned.
+        match Listeners::scan() {
+            Ok(listeners) => self.listeners = listeners,
+            Err(e) => warn!("Failed to scan listening sockets: {}", e),
+        }
+
+        let snapshot = Snapshot {
+            ingress: sorted_flows(&self.ingress_heap),
+            egress: sorted_flows(&self.egress_heap),
+            connections: std::mem::take(&mut self.connections),
+            listeners: self.listeners.clone(),
+            ingress_totals: self.ingress_heap.totals(),
+            egress_totals: self.egress_heap.totals(),
+            churn: self.churn.top(self.top_n),
+            warning: (!writers_left).then(|| {
+                format!("Writers didn't leave tracker {} in {:?}, drained anyway", self.flag, WRITERS_TIMEOUT)
+            }),
+        };
+
+        self.ingress_heap.clear();
+        self.egress_heap.clear();
+        self.flag ^= 0x1;
+        Ok(snapshot)
+    }
+}
+
+/// Flows on the heap sorted by throughput.
+fn sorted_flows(heap: &LimitedMaxHeap) -> Vec<FlowInfo> {
+    let mut top_flow_info: Vec<FlowInfo> = heap
+        .liter()
+        .cloned()
+        .collect();
+
+    top_flow_info.sort_by(|f1, f2| f2.throughput.cmp(&f1.throughput));
+    top_flow_info
+}
@@ -0,0 +1,192 @@
+use std::{
+    ffi::CString,
+    io,
+    os::unix::process::ExitStatusExt,
+    process::ExitStatus,
+    ptr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context};
+
+/// Command launched by the tool, of which the flows are traced.
+///
+/// The command is forked and waits for its pid to be traced before it execs, so that none of
+/// its flows are missed. It runs without a terminal, its input and output are redirected to
+/// `/dev/null` so that they don't get in the way of the TUI.
+pub struct Command {
+    args: Vec<String>,
+    pid: libc::pid_t,
+    started: Instant,
+}
+
+impl Command {
+    /// Spawn the command, `trace` is called with its pid before the command runs. The command
+    /// exits with 127 if it can't be run, as with a shell.
+    pub fn spawn(args: &[String], trace: impl FnOnce(u32) -> anyhow::Result<()>) -> anyhow::Result<Command> {
+        // Everything the child needs is allocated before the fork.
+        let argv = args
+            .iter()
+            .map(|arg| CString::new(arg.as_str()))
+            .collect::<Result<Vec<CString>, _>>()?;
+        let program = argv.first().ok_or_else(|| anyhow!("No command to run"))?;
+        let mut argv_ptrs: Vec<*const libc::c_char> = argv.iter().map(|arg| arg.as_ptr()).collect();
+        argv_ptrs.push(ptr::null());
+
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error()).context("Failed to create a pipe");
+        }
+        let [ready, go] = fds;
+
+        let pid = unsafe { libc::fork() };
+        if pid < 0 {
+            return Err(io::Error::last_os_error()).context("Failed to fork");
+        }
+        if pid == 0 {
+            unsafe {
+                libc::close(go);
+                exec(ready, program, &argv_ptrs);
+            }
+        }
+
+        unsafe { libc::close(ready) };
+        let command = Command { args: args.to_vec(), pid, started: Instant::now() };
+        // The command exits without running when the pipe is closed without writing to it.
+        let started = trace(pid as u32).and_then(|()| {
+            if unsafe { libc::write(go, [1u8].as_ptr().cast(), 1) } != 1 {
+                return Err(io::Error::last_os_error()).context("Failed to start the command");
+            }
+            Ok(())
+        });
+        unsafe { libc::close(go) };
+
+        if let Err(e) = started {
+            command.wait()?;
+            return Err(e.context(format!("Failed to run `{}`", command)));
+        }
+
+        Ok(command)
+    }
+
+    /// Exit status of the command if it exited, without waiting.
+    pub fn try_wait(&self) -> anyhow::Result<Option<ExitStatus>> {
+        let mut status = 0;
+        match unsafe { libc::waitpid(self.pid, &mut status, libc::WNOHANG) } {
+            0 => Ok(None),
+            pid if pid < 0 => Err(io::Error::last_os_error()).context("Failed to wait for the command"),
+            _ => Ok(Some(ExitStatus::from_raw(status))),
+        }
+    }
+
+    /// Ask the command to terminate and wait for it.
+    pub fn terminate(&self) -> anyhow::Result<ExitStatus> {
+        unsafe { libc::kill(self.pid, libc::SIGTERM) };
+        self.wait()
+    }
+
+    /// Time since the command was started.
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    fn wait(&self) -> anyhow::Result<ExitStatus> {
+        let mut status = 0;
+        loop {
+            if unsafe { libc::waitpid(self.pid, &mut status, 0) } >= 0 {
+                return Ok(ExitStatus::from_raw(status));
+            }
+
+            let error = io::Error::last_os_error();
+            if error.kind() != io::ErrorKind::Interrupted {
+                return Err(error).context("Failed to wait for the command");
+            }
+        }
+    }
+}
+
+impl Drop for Command {
+    fn drop(&mut self) {
+        // The command doesn't outlive the tool when it exits early, e.g. on an error.
+        if let Ok(None) = self.try_wait() {
+            let _ = self.terminate();
+        }
+    }
+}
+
+impl std::fmt::Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.args.join(" "))
+    }
+}
+
+/// Wait until the parent traced the command and exec it, in the forked child.
+///
+/// Only async-signal-safe calls can be made between the fork and the exec.
+unsafe fn exec(ready: libc::c_int, program: &CString, argv: &[*const libc::c_char]) -> ! {
+    let mut byte = 0u8;
+    if libc::read(ready, (&mut byte as *mut u8).cast(), 1) == 1 {
+        let null = libc::open(c"/dev/null".as_ptr(), libc::O_RDWR);
+        if null >= 0 {
+            for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+                libc::dup2(null, fd);
+            }
+        }
+
+        libc::execvp(program.as_ptr(), argv.as_ptr());
+    }
+
+    libc::_exit(127)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, thread, time::Duration};
+
+    use anyhow::anyhow;
+
+    use crate::command::Command;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn spawn_after_trace() {
+        let traced = Cell::new(0);
+        let command = Command::spawn(&args(&["sh", "-c", "exit 3"]), |pid| {
+            traced.set(pid);
+            Ok(())
+        }).unwrap();
+        assert_eq!(command.pid as u32, traced.get());
+        assert_eq!(command.to_string(), "sh -c exit 3");
+
+        let status = loop {
+            match command.try_wait().unwrap() {
+                Some(status) => break status,
+                None => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        assert_eq!(status.code(), Some(3));
+
+        // The command doesn't run when it can't be traced.
+        let marker = std::env::temp_dir().join(format!("flow-top-talker-untraced-{}", std::process::id()));
+        let script = format!("touch {}", marker.display());
+        let error = Command::spawn(&args(&["sh", "-c", &script]), |_| Err(anyhow!("map is full"))).err().unwrap();
+        assert_eq!(error.to_string(), format!("Failed to run `sh -c {}`", script));
+        assert!(!marker.exists());
+
+        let command = Command::spawn(&args(&["no-such-command-flow-top-talker"]), |_| Ok(())).unwrap();
+        assert_eq!(command.wait().unwrap().code(), Some(127));
+    }
+
+    #[test]
+    fn terminate_on_drop() {
+        let command = Command::spawn(&args(&["sleep", "30"]), |_| Ok(())).unwrap();
+        let pid = command.pid;
+        drop(command);
+
+        // The command was terminated and reaped.
+        assert_eq!(unsafe { libc::kill(pid, 0) }, -1);
+    }
+}
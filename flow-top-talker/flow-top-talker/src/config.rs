@@ -0,0 +1,426 @@
+use std::{
+    collections::HashMap,
+    fs, mem,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::{anyhow, Context};
+use clap::{parser::ValueSource, ArgMatches, CommandFactory};
+use serde::{de, Deserialize, Deserializer};
+
+use crate::{
+    cli::{Cli, Mode},
+    connection::SortMetric,
+    report::ReportFormat,
+    theme::ThemeName,
+    ui::View,
+    units::{parse_duration, parse_rate},
+};
+
+/// Set by the SIGHUP handler, see `reload_requested`.
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+/// Config file shared by all the users of the host.
+const SYSTEM_CONFIG: &str = "/etc/flow-top-talker/config.toml";
+/// Name of the config file in the config directory of the user.
+const USER_CONFIG: &str = "flow-top-talker/config.toml";
+
+/// Options which can be set in a config file, named after the long flags.
+///
+/// Every option is optional, the options set override the ones of the config files read
+/// before, while the flags provided on the command line override all of them.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Options {
+    pub top_n: Option<usize>,
+    #[serde(deserialize_with = "one_or_many")]
+    pub pid: Option<Vec<u32>>,
+    #[serde(deserialize_with = "one_or_many")]
+    pub tid: Option<Vec<u32>>,
+    #[serde(deserialize_with = "one_or_many")]
+    pub uid: Option<Vec<u32>>,
+    #[serde(deserialize_with = "one_or_many")]
+    pub user: Option<Vec<String>>,
+    #[serde(deserialize_with = "one_or_many")]
+    pub comm: Option<Vec<String>>,
+    #[serde(deserialize_with = "one_or_many")]
+    pub exe: Option<Vec<String>>,
+    pub host_name: Option<bool>,
+    pub max_flows: Option<u32>,
+    pub memory_budget: Option<u64>,
+    pub auto_shrink: Option<bool>,
+    pub short_lived_ms: Option<u64>,
+    pub interval_ms: Option<u64>,
+    pub view: Option<View>,
+    pub sort: Option<SortMetric>,
+    pub by_service: Option<bool>,
+    pub bits: Option<bool>,
+    #[serde(deserialize_with = "rate")]
+    pub warn_rate: Option<u64>,
+    #[serde(deserialize_with = "rate")]
+    pub crit_rate: Option<u64>,
+    pub theme: Option<ThemeName>,
+    pub mode: Option<Mode>,
+    /// Duration as with the flag, e.g. `"5m"`.
+    pub duration: Option<String>,
+    pub iterations: Option<u64>,
+    pub format: Option<ReportFormat>,
+    pub socket: Option<PathBuf>,
+    pub socket_group: Option<String>,
+    pub pin_path: Option<PathBuf>,
+    /// Names of the hosts (`ADDR`) and endpoints (`ADDR:PORT`), which are added to the labels
+    /// of the config files read before.
+    pub labels: HashMap<String, String>,
+}
+
+/// Config file, the defaults apply to every invocation while a profile only applies when
+/// selected with `--profile`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    defaults: Options,
+    profiles: HashMap<String, Options>,
+}
+
+impl Options {
+    /// Merge the options over these ones.
+    fn merge(&mut self, other: Options) {
+        self.top_n = other.top_n.or(self.top_n);
+        self.pid = other.pid.or(self.pid.take());
+        self.tid = other.tid.or(self.tid.take());
+        self.uid = other.uid.or(self.uid.take());
+        self.user = other.user.or(self.user.take());
+        self.comm = other.comm.or(self.comm.take());
+        self.exe = other.exe.or(self.exe.take());
+        self.host_name = other.host_name.or(self.host_name);
+        self.max_flows = other.max_flows.or(self.max_flows);
+        self.memory_budget = other.memory_budget.or(self.memory_budget);
+        self.auto_shrink = other.auto_shrink.or(self.auto_shrink);
+        self.short_lived_ms = other.short_lived_ms.or(self.short_lived_ms);
+        self.interval_ms = other.interval_ms.or(self.interval_ms);
+        self.view = other.view.or(self.view);
+        self.sort = other.sort.or(self.sort);
+        self.by_service = other.by_service.or(self.by_service);
+        self.bits = other.bits.or(self.bits);
+        self.warn_rate = other.warn_rate.or(self.warn_rate);
+        self.crit_rate = other.crit_rate.or(self.crit_rate);
+        self.theme = other.theme.or(self.theme);
+        self.mode = other.mode.or(self.mode);
+        // Both set the length of the report, so the one set last replaces the other.
+        if other.duration.is_some() || other.iterations.is_some() {
+            self.duration = other.duration;
+            self.iterations = other.iterations;
+        }
+        self.format = other.format.or(self.format);
+        self.socket = other.socket.or(self.socket.take());
+        self.socket_group = other.socket_group.or(self.socket_group.take());
+        self.pin_path = other.pin_path.or(self.pin_path.take());
+        self.labels.extend(other.labels);
+    }
+
+    /// Apply the options to the arguments which were not provided on the command line.
+    ///
+    /// Values are checked by the parsers of the flags, so that the config files can't set a
+    /// value the command line would refuse, e.g. an interval below 100ms.
+    pub fn apply(self, cli: &mut Cli, matches: &ArgMatches) -> anyhow::Result<()> {
+        check("--top-n", self.top_n)?;
+        check("--max-flows", self.max_flows)?;
+        check("--memory-budget", self.memory_budget)?;
+        check("--short-lived-ms", self.short_lived_ms)?;
+        check("--interval-ms", self.interval_ms)?;
+        check("--duration", self.duration.as_deref())?;
+        check("--iterations", self.iterations)?;
+        if self.duration.is_some() && self.iterations.is_some() {
+            return Err(anyhow!("Invalid option in the config files: `duration` can't be set along with `iterations`"));
+        }
+        let duration = self.duration.as_deref().map(parse_duration).transpose().map_err(|e| anyhow!(e))?;
+
+        let unset = |id: &str| matches.value_source(id) != Some(ValueSource::CommandLine);
+        fn set<T>(field: &mut T, value: Option<T>, unset: bool) {
+            if let (Some(value), true) = (value, unset) {
+                *field = value;
+            }
+        }
+
+        set(&mut cli.top_n, self.top_n, unset("top_n"));
+        set(&mut cli.pid, self.pid, unset("pid"));
+        set(&mut cli.tid, self.tid, unset("tid"));
+        set(&mut cli.uid, self.uid, unset("uid"));
+        set(&mut cli.user, self.user, unset("user"));
+        set(&mut cli.comm, self.comm, unset("comm"));
+        set(&mut cli.exe, self.exe, unset("exe"));
+        set(&mut cli.host_name, self.host_name, unset("host_name"));
+        set(&mut cli.max_flows, self.max_flows, unset("max_flows"));
+        set(&mut cli.memory_budget, self.memory_budget.map(Some), unset("memory_budget"));
+        set(&mut cli.auto_shrink, self.auto_shrink, unset("auto_shrink"));
+        set(&mut cli.short_lived_ms, self.short_lived_ms, unset("short_lived_ms"));
+        set(&mut cli.interval_ms, self.interval_ms, unset("interval_ms"));
+        set(&mut cli.view, self.view, unset("view"));
+        set(&mut cli.sort, self.sort, unset("sort"));
+        set(&mut cli.by_service, self.by_service, unset("by_service"));
+        set(&mut cli.bits, self.bits, unset("bits"));
+        set(&mut cli.warn_rate, self.warn_rate, unset("warn_rate"));
+        set(&mut cli.crit_rate, self.crit_rate, unset("crit_rate"));
+        set(&mut cli.theme, self.theme, unset("theme"));
+        set(&mut cli.mode, self.mode, unset("mode"));
+        // Either flag on the command line overrides both options.
+        let limit_unset = unset("duration") && unset("iterations");
+        set(&mut cli.duration, duration.map(Some), limit_unset);
+        set(&mut cli.iterations, self.iterations.map(Some), limit_unset);
+        set(&mut cli.format, self.format, unset("format"));
+        set(&mut cli.socket, self.socket, unset("socket"));
+        set(&mut cli.socket_group, self.socket_group.map(Some), unset("socket_group"));
+        set(&mut cli.pin_path, self.pin_path.map(Some), unset("pin_path"));
+
+        // Labels provided on the command line come last so they take precedence.
+        let mut labels: Vec<String> = self.labels
+            .into_iter()
+            .map(|(endpoint, name)| format!("{}={}", endpoint, name))
+            .collect();
+        labels.sort();
+        labels.append(&mut cli.label);
+        cli.label = labels;
+        Ok(())
+    }
+}
+
+/// Check the value of an option with the parser of its flag.
+fn check(flag: &str, value: Option<impl ToString>) -> anyhow::Result<()> {
+    let Some(value) = value else {
+        return Ok(());
+    };
+
+    match Cli::command().try_get_matches_from(["flow-top-talker", flag, &value.to_string()]) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            // Only keep the reason from the message, which is meant for the command line.
+            let message = e.to_string();
+            let reason = message.lines().next().unwrap_or_default().trim_start_matches("error: ");
+            Err(anyhow!("Invalid option in the config files: {}", reason))
+        }
+    }
+}
+
+/// Load the options from the system and user config files, and the one provided with
+/// `--config`, with the profile merged over their defaults.
+///
+/// The system and user config files are optional, while the one provided must exist.
+pub fn load(config: Option<&Path>, profile: Option<&str>) -> anyhow::Result<Options> {
+    let mut files = Vec::new();
+    for path in [Some(PathBuf::from(SYSTEM_CONFIG)), user_config()].into_iter().flatten() {
+        if path.exists() {
+            files.push(read(&path)?);
+        }
+    }
+
+    if let Some(path) = config {
+        files.push(read(path)?);
+    }
+
+    merge(files, profile)
+}
+
+/// Ask for the config files to be reloaded on SIGHUP, which is then reported by
+/// `reload_requested`.
+pub fn reload_on_sighup() -> anyhow::Result<()> {
+    extern "C" fn request_reload(_: libc::c_int) {
+        RELOAD.store(true, Ordering::Relaxed);
+    }
+
+    let mut action: libc::sigaction = unsafe { mem::zeroed() };
+    action.sa_sigaction = request_reload as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    // Restart the system calls interrupted by the signal, e.g. the poll of the TUI.
+    action.sa_flags = libc::SA_RESTART;
+    if unsafe { libc::sigaction(libc::SIGHUP, &action, std::ptr::null_mut()) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("Failed to handle SIGHUP");
+    }
+
+    Ok(())
+}
+
+/// Whether SIGHUP was received since the last call.
+pub fn reload_requested() -> bool {
+    RELOAD.swap(false, Ordering::Relaxed)
+}
+
+fn merge(files: Vec<ConfigFile>, profile: Option<&str>) -> anyhow::Result<Options> {
+    let mut options = Options::default();
+    let mut profiles: HashMap<String, Options> = HashMap::new();
+    for file in files {
+        options.merge(file.defaults);
+        for (name, other) in file.profiles {
+            profiles.entry(name).or_default().merge(other);
+        }
+    }
+
+    if let Some(name) = profile {
+        let other = profiles.remove(name).ok_or_else(|| {
+            let mut names: Vec<String> = profiles.into_keys().collect();
+            names.sort();
+            anyhow!("Unknown profile `{}`, available profiles: [{}]", name, names.join(", "))
+        })?;
+        options.merge(other);
+    }
+
+    Ok(options)
+}
+
+fn read(path: &Path) -> anyhow::Result<ConfigFile> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file {}", path.display()))?;
+    toml::from_str(&content)
+        .with_context(|| format!("Failed to parse config file {}", path.display()))
+}
+
+/// Config file of the user, under `$XDG_CONFIG_HOME` or `~/.config`.
+fn user_config() -> Option<PathBuf> {
+    let dir = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(dir.join(USER_CONFIG))
+}
+
+/// Rates can be either in bytes per second or a string with a suffix, as with the flags.
+fn rate<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Rate {
+        Bytes(u64),
+        Text(String),
+    }
+
+    match Rate::deserialize(deserializer)? {
+        Rate::Bytes(bytes) => Ok(Some(bytes)),
+        Rate::Text(text) => parse_rate(&text).map(Some).map_err(de::Error::custom),
+    }
+}
+
+/// Filters take either a single value or a list of values, as the flags can be repeated.
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Option<Vec<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => Ok(Some(vec![value])),
+        OneOrMany::Many(values) => Ok(Some(values)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use clap::{CommandFactory, FromArgMatches};
+
+    use crate::{
+        cli::Cli,
+        config::{merge, ConfigFile},
+        ui::View,
+    };
+
+    const SYSTEM: &str = r#"
+[defaults]
+top-n = 20
+warn-rate = "1M"
+labels = { "10.0.0.5" = "db-primary" }
+
+[profiles.prod-db]
+pid = 1234
+user = ["postgres", "backup"]
+view = "combined"
+"#;
+
+    const USER: &str = r#"
+[defaults]
+crit-rate = 10485760
+
+[profiles.prod-db]
+top-n = 5
+comm = "postgres*"
+labels = { "10.0.0.5:5432" = "postgres" }
+"#;
+
+    fn files() -> Vec<ConfigFile> {
+        vec![toml::from_str(SYSTEM).unwrap(), toml::from_str(USER).unwrap()]
+    }
+
+    #[test]
+    fn merge_profiles_and_flags() {
+        let options = merge(files(), None).unwrap();
+        assert_eq!((options.top_n, options.pid), (Some(20), None));
+        assert_eq!((options.warn_rate, options.crit_rate), (Some(1 << 20), Some(10 << 20)));
+
+        let options = merge(files(), Some("prod-db")).unwrap();
+        assert_eq!((options.top_n, options.pid.clone(), options.view), (Some(5), Some(vec![1234]), Some(View::Combined)));
+        assert_eq!(options.user.as_deref(), Some(&["postgres".to_string(), "backup".to_string()][..]));
+        assert_eq!(options.labels.len(), 2);
+
+        let error = merge(files(), Some("prod-web")).unwrap_err();
+        assert_eq!(error.to_string(), "Unknown profile `prod-web`, available profiles: [prod-db]");
+
+        // Flags override the config files.
+        let matches = Cli::command().get_matches_from(["flow-top-talker", "-n", "3", "--label", "10.0.0.6=cache"]);
+        let mut cli = Cli::from_arg_matches(&matches).unwrap();
+        options.apply(&mut cli, &matches).unwrap();
+        assert_eq!((cli.top_n, cli.pid, cli.view), (3, vec![1234], View::Combined));
+        assert_eq!((cli.user, cli.comm), (vec!["postgres".to_string(), "backup".to_string()], vec!["postgres*".to_string()]));
+        assert_eq!(cli.label, ["10.0.0.5:5432=postgres", "10.0.0.5=db-primary", "10.0.0.6=cache"]);
+    }
+
+    #[test]
+    fn reject_unknown_options() {
+        assert!(toml::from_str::<ConfigFile>("[defaults]\ntop_n = 5").is_err());
+        assert!(toml::from_str::<ConfigFile>("[defaults]\nwarn-rate = \"fast\"").is_err());
+    }
+
+    #[test]
+    fn reject_values_refused_by_flags() {
+        let file: ConfigFile = toml::from_str("[defaults]\ninterval-ms = 0").unwrap();
+        let options = merge(vec![file], None).unwrap();
+        let matches = Cli::command().get_matches_from(["flow-top-talker"]);
+        let mut cli = Cli::from_arg_matches(&matches).unwrap();
+        let error = options.apply(&mut cli, &matches).unwrap_err();
+        assert!(error.to_string().starts_with("Invalid option in the config files: invalid value '0' for '--interval-ms"));
+        assert_eq!(cli.interval_ms, 1000);
+
+        let file: ConfigFile = toml::from_str("[defaults]\nduration = \"5 minutes\"").unwrap();
+        let error = merge(vec![file], None).unwrap().apply(&mut cli, &matches).unwrap_err();
+        assert!(error.to_string().starts_with("Invalid option in the config files: invalid value '5 minutes' for '--duration"));
+        let file: ConfigFile = toml::from_str("[defaults]\niterations = 0").unwrap();
+        let error = merge(vec![file], None).unwrap().apply(&mut cli, &matches).unwrap_err();
+        assert!(error.to_string().starts_with("Invalid option in the config files: invalid value '0' for '--iterations"));
+    }
+
+    #[test]
+    fn report_length() {
+        let files = vec![
+            toml::from_str("[defaults]\nduration = \"5m\"").unwrap(),
+            toml::from_str("[defaults]\niterations = 10").unwrap(),
+        ];
+        let options = merge(files, None).unwrap();
+        assert_eq!((options.duration.as_deref(), options.iterations), (None, Some(10)));
+
+        let file: ConfigFile = toml::from_str("[defaults]\nduration = \"5m\"").unwrap();
+        let matches = Cli::command().get_matches_from(["flow-top-talker"]);
+        let mut cli = Cli::from_arg_matches(&matches).unwrap();
+        merge(vec![file], None).unwrap().apply(&mut cli, &matches).unwrap();
+        assert_eq!((cli.duration, cli.iterations), (Some(Duration::from_secs(300)), None));
+
+        // Iterations on the command line override the duration of the config files.
+        let file: ConfigFile = toml::from_str("[defaults]\nduration = \"5m\"").unwrap();
+        let matches = Cli::command().get_matches_from(["flow-top-talker", "--iterations", "3"]);
+        let mut cli = Cli::from_arg_matches(&matches).unwrap();
+        merge(vec![file], None).unwrap().apply(&mut cli, &matches).unwrap();
+        assert_eq!((cli.duration, cli.iterations), (None, Some(3)));
+    }
+}
@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+
+use clap::ValueEnum;
+use flow_top_talker_common::common_types::{FlowKey, Owner};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    flow_info::FlowInfo,
+    roles::{Listener, Listeners, Process, Role},
+    ui::Section,
+};
+
+/// Metric to rank the connections by in the combined view.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortMetric {
+    Total,
+    Tx,
+    Rx,
+}
+
+impl SortMetric {
+    pub fn next(&self) -> SortMetric {
+        match self {
+            SortMetric::Total => SortMetric::Tx,
+            SortMetric::Tx => SortMetric::Rx,
+            SortMetric::Rx => SortMetric::Total,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SortMetric::Total => "total",
+            SortMetric::Tx => "TX",
+            SortMetric::Rx => "RX",
+        }
+    }
+}
+
+/// Traffic of a connection in both directions, or of all the connections of a service when
+/// aggregated by service.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectionInfo {
+    pub flow_key: FlowKey,
+    pub owner: Owner,
+    pub tx: u64,
+    pub rx: u64,
+    pub connections: u32,
+}
+
+impl ConnectionInfo {
+    pub fn total(&self) -> u64 {
+        self.tx + self.rx
+    }
+
+    pub fn metric(&self, metric: SortMetric) -> u64 {
+        match metric {
+            SortMetric::Total => self.total(),
+            SortMetric::Tx => self.tx,
+            SortMetric::Rx => self.rx,
+        }
+    }
+}
+
+/// Traffic of all the connections accepted by a local listener.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServiceInfo {
+    pub listener: Listener,
+    pub process: Option<Process>,
+    pub connections: u32,
+    pub tx: u64,
+    pub rx: u64,
+}
+
+impl ServiceInfo {
+    pub fn total(&self) -> u64 {
+        self.tx + self.rx
+    }
+
+    pub fn metric(&self, metric: SortMetric) -> u64 {
+        match metric {
+            SortMetric::Total => self.total(),
+            SortMetric::Tx => self.tx,
+            SortMetric::Rx => self.rx,
+        }
+    }
+}
+
+/// Joins the ingress and egress flows of the same socket into a connection.
+///
+/// Both directions of a socket have the same flow key, as the source is always the local side
+/// and the destination the remote side, so the flows are joined on the key.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(from = "Vec<ConnectionInfo>", into = "Vec<ConnectionInfo>")]
+pub struct ConnectionTable {
+    connections: HashMap<FlowKey, ConnectionInfo>,
+}
+
+impl From<Vec<ConnectionInfo>> for ConnectionTable {
+    fn from(connections: Vec<ConnectionInfo>) -> Self {
+        Self { connections: connections.into_iter().map(|c| (c.flow_key, c)).collect() }
+    }
+}
+
+impl From<ConnectionTable> for Vec<ConnectionInfo> {
+    fn from(table: ConnectionTable) -> Self {
+        table.connections.into_values().collect()
+    }
+}
+
+impl ConnectionTable {
+    pub fn iter(&self) -> impl Iterator<Item = &ConnectionInfo> {
+        self.connections.values()
+    }
+
+    pub fn add(&mut self, section: Section, flow: &FlowInfo) {
+        let flow_key = flow.flow_key();
+        let connection = self.connections.entry(flow_key).or_insert(ConnectionInfo {
+            flow_key,
+            owner: flow.owner,
+            tx: 0,
+            rx: 0,
+            connections: 1,
+        });
+
+        match section {
+            Section::Ingress => connection.rx += flow.throughput,
+            Section::Egress => connection.tx += flow.throughput,
+        }
+
+        if connection.owner.pid == 0 {
+            connection.owner = flow.owner;
+        }
+    }
+
+    /// Connections aggregated by service, the port of the client side is zeroed so all the
+    /// connections between the same client address and service end up in the same row.
+    pub fn by_service(&self, listeners: &Listeners) -> ConnectionTable {
+        let mut services = ConnectionTable::default();
+        for connection in self.connections.values() {
+            let mut flow_key = connection.flow_key;
+            match listeners.role(&flow_key) {
+                Role::Server => flow_key.dest_port = 0,
+                Role::Client => flow_key.src_port = 0,
+            }
+
+            services.connections
+                .entry(flow_key)
+                .and_modify(|service| {
+                    service.tx += connection.tx;
+                    service.rx += connection.rx;
+                    service.connections += connection.connections;
+                    if service.owner.pid == 0 {
+                        service.owner = connection.owner;
+                    }
+                })
+                .or_insert(ConnectionInfo { flow_key, ..*connection });
+        }
+
+        services
+    }
+
+    /// All the local listeners ranked by the traffic of the connections they accepted, the
+    /// listeners without traffic in the interval are ranked last.
+    pub fn services(&self, listeners: &Listeners, metric: SortMetric) -> Vec<ServiceInfo> {
+        let mut services: HashMap<Listener, ServiceInfo> = listeners
+            .iter()
+            .map(|listener| {
+                let service = ServiceInfo {
+                    listener: *listener,
+                    process: None,
+                    connections: 0,
+                    tx: 0,
+                    rx: 0,
+                };
+                (*listener, service)
+            })
+            .collect();
+
+        for connection in self.connections.values() {
+            let Some(service) = listeners
+                .listener(&connection.flow_key)
+                .and_then(|listener| services.get_mut(listener))
+            else {
+                continue;
+            };
+
+            service.connections += connection.connections;
+            service.tx += connection.tx;
+            service.rx += connection.rx;
+        }
+
+        let mut services: Vec<ServiceInfo> = services.into_values().collect();
+        services.sort_by_key(|s| (std::cmp::Reverse(s.metric(metric)), s.listener.port));
+        services
+    }
+
+    /// Top N connections by the metric.
+    pub fn top(&self, top_n: usize, metric: SortMetric) -> Vec<ConnectionInfo> {
+        let mut connections: Vec<ConnectionInfo> = self.connections.values().copied().collect();
+        connections.sort_by_key(|c| std::cmp::Reverse(c.metric(metric)));
+        connections.truncate(top_n);
+        connections
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use flow_top_talker_common::common_types::{FlowKey, FlowStats};
+
+    use crate::{
+        connection::{ConnectionTable, SortMetric},
+        flow_info::FlowInfo,
+        roles::Listeners,
+        ui::Section,
+    };
+
+    fn flow(dest_port: u16, bytes: u64) -> FlowInfo {
+        let stats = FlowStats { bytes, ..Default::default() };
+        FlowInfo::new(&FlowKey::new(1, 2, 40000, dest_port, 0), &stats)
+    }
+
+    #[test]
+    fn join_and_rank() {
+        let mut table = ConnectionTable::default();
+        table.add(Section::Egress, &flow(443, 100));
+        table.add(Section::Ingress, &flow(443, 50));
+        table.add(Section::Ingress, &flow(80, 120));
+
+        let top = table.top(10, SortMetric::Total);
+        assert_eq!(top.len(), 2);
+        assert_eq!((top[0].flow_key.dest_port, top[0].tx, top[0].rx), (443, 100, 50));
+        assert_eq!((top[1].flow_key.dest_port, top[1].total()), (80, 120));
+
+        assert_eq!(table.top(10, SortMetric::Rx)[0].flow_key.dest_port, 80);
+        assert_eq!(table.top(1, SortMetric::Tx)[0].flow_key.dest_port, 443);
+        assert_eq!(table.top(1, SortMetric::Tx).len(), 1);
+    }
+
+    #[test]
+    fn aggregate_by_service() {
+        let tcp = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 00000000:01BB 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 101 1 0
+";
+        let listeners = Listeners::parse(tcp, "", 32768..=60999);
+
+        let mut table = ConnectionTable::default();
+        let stats = FlowStats { bytes: 100, ..Default::default() };
+        // Two clients of the local service and two connections to a remote service.
+        table.add(Section::Ingress, &FlowInfo::new(&FlowKey::new(1, 2, 443, 40000, 0), &stats));
+        table.add(Section::Ingress, &FlowInfo::new(&FlowKey::new(1, 2, 443, 40001, 0), &stats));
+        table.add(Section::Egress, &FlowInfo::new(&FlowKey::new(1, 3, 40002, 80, 0), &stats));
+        table.add(Section::Egress, &FlowInfo::new(&FlowKey::new(1, 3, 40003, 80, 0), &stats));
+
+        let top = table.by_service(&listeners).top(10, SortMetric::Total);
+        assert_eq!(top.len(), 2);
+        for service in top {
+            assert_eq!(service.connections, 2);
+            assert_eq!(service.total(), 200);
+            let key = service.flow_key;
+            assert!(key == FlowKey::new(1, 2, 443, 0, 0) || key == FlowKey::new(1, 3, 0, 80, 0));
+        }
+
+        // Only the connections accepted by the listener count towards the service.
+        let services = table.services(&listeners, SortMetric::Rx);
+        assert_eq!(services.len(), 1);
+        assert_eq!((services[0].listener.port, services[0].connections, services[0].rx), (443, 2, 200));
+    }
+}
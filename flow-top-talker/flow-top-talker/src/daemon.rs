@@ -0,0 +1,356 @@
+use std::{
+    ffi::CString,
+    fs::{self, Permissions},
+    io::{BufReader, Write},
+    mem,
+    net::Shutdown,
+    os::{
+        fd::AsRawFd,
+        unix::{
+            fs::{FileTypeExt, PermissionsExt},
+            net::{UnixListener, UnixStream},
+        },
+    },
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context};
+use log::{info, warn};
+
+use crate::{
+    cli::Cli,
+    collector::Collector,
+    config,
+    filter::Filter,
+    protocol::{encode, receive, Message, Request},
+};
+
+/// Messages queued for a client, which is disconnected once its queue is full so that a stuck
+/// client can't stall the collection.
+const QUEUE_SIZE: usize = 8;
+
+/// Clients served at once, further connections are refused.
+const MAX_CLIENTS: usize = 64;
+
+/// Client connected to the daemon. Its messages are queued for a thread writing them to its
+/// stream, and it is disconnected once dropped.
+struct Client {
+    id: u64,
+    uid: u32,
+    queue: SyncSender<Arc<[u8]>>,
+    stream: UnixStream,
+}
+
+impl Client {
+    fn new(id: u64, uid: u32, stream: UnixStream) -> std::io::Result<Client> {
+        let (queue, queued) = mpsc::sync_channel::<Arc<[u8]>>(QUEUE_SIZE);
+        let mut writer = stream.try_clone()?;
+        thread::spawn(move || {
+            for line in queued {
+                if writer.write_all(&line).is_err() {
+                    return;
+                }
+            }
+        });
+
+        Ok(Client { id, uid, queue, stream })
+    }
+
+    /// Queue the message, false if the client is gone or doesn't keep up with its messages.
+    fn send(&self, message: &Message) -> anyhow::Result<bool> {
+        Ok(self.queue.try_send(encode(message)?.into()).is_ok())
+    }
+
+    /// Queue an already encoded message, shared by all the clients.
+    fn send_encoded(&self, line: &Arc<[u8]>) -> bool {
+        self.queue.try_send(line.clone()).is_ok()
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        // Unblocks the writer and reader threads of the client.
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+/// Events of the clients handled by the collection loop.
+enum Event {
+    Connected(Client),
+    Request(u64, Request),
+}
+
+/// Collect the flows and serve the snapshots to the clients connected to the socket.
+///
+/// Anyone who can connect to the socket can view the snapshots, while only root and the user
+/// the daemon runs as can change the filters. The filters are also reloaded from the config
+/// files on SIGHUP, and every change is sent to all the clients.
+pub fn run(cli: &Cli) -> anyhow::Result<()> {
+    let mut collector = Collector::new(cli)?;
+    println!("{}", collector.ebpf_handler().map_sizing());
+    config::reload_on_sighup()?;
+    let listener = bind(&cli.socket, cli.socket_group.as_deref())?;
+    info!("Listening on {}", cli.socket.display());
+
+    let (events, received) = mpsc::channel();
+    thread::spawn(move || accept(listener, events));
+
+    let mut clients: Vec<Client> = Vec::new();
+    let mut filter = Filter::from_cli(cli)?;
+    let interval = Duration::from_millis(cli.interval_ms);
+    let mut next_rotation = Instant::now() + interval;
+    loop {
+        match next_event(&received, next_rotation)? {
+            Some(Event::Connected(client)) => {
+                let hello = Message::Hello { interval_ms: cli.interval_ms, top_n: cli.top_n, filter: filter.clone() };
+                if client.send(&hello)? {
+                    clients.push(client);
+                }
+            }
+            Some(Event::Request(id, Request::SetFilter { filter: requested })) => {
+                let Some(client) = clients.iter().find(|client| client.id == id) else {
+                    continue;
+                };
+
+                if client.uid != 0 && client.uid != unsafe { libc::geteuid() } {
+                    let message = "Permission denied, only root or the user of the daemon can change the filters";
+                    client.send(&Message::Error { message: message.to_string() })?;
+                    continue;
+                }
+
+                let uid = client.uid;
+                match set_filter(&mut collector, &mut clients, requested.clone()) {
+                    Ok(()) => {
+                        info!("Filter changed to {} by uid {}", requested, uid);
+                        filter = requested;
+                    }
+                    Err(e) => {
+                        let message = format!("Failed to change the filter: {}", e);
+                        if let Some(client) = clients.iter().find(|client| client.id == id) {
+                            client.send(&Message::Error { message })?;
+                        }
+                    }
+                }
+            }
+            None => {
+                let snapshot = collector.collect()?;
+                if let Some(warning) = &snapshot.warning {
+                    warn!("{}", warning);
+                }
+                let snapshot: Arc<[u8]> = encode(&Message::Snapshot(Box::new(snapshot)))?.into();
+                retain_sent(&mut clients, &snapshot);
+                next_rotation += interval;
+            }
+        }
+
+        if config::reload_requested() {
+            let reloaded = Cli::load().and_then(|cli| Filter::from_cli(&cli))
+                .and_then(|reloaded| set_filter(&mut collector, &mut clients, reloaded.clone()).map(|()| reloaded));
+            match reloaded {
+                Ok(reloaded) => {
+                    info!("Filter changed to {} on reload", reloaded);
+                    filter = reloaded;
+                }
+                Err(e) => warn!("Failed to reload the filter: {}", e),
+            }
+        }
+    }
+}
+
+/// Change the filter of the collector and let all the clients know.
+fn set_filter(collector: &mut Collector, clients: &mut Vec<Client>, filter: Filter) -> anyhow::Result<()> {
+    collector.ebpf_handler().set_filter(&filter)?;
+
+    let message: Arc<[u8]> = encode(&Message::Filter { filter })?.into();
+    retain_sent(clients, &message);
+    Ok(())
+}
+
+/// Queue the message for all the clients, disconnecting the ones which are gone or whose queue
+/// is full.
+fn retain_sent(clients: &mut Vec<Client>, message: &Arc<[u8]>) {
+    clients.retain(|client| {
+        let sent = client.send_encoded(message);
+        if !sent {
+            info!("Client {} disconnected", client.id);
+        }
+        sent
+    });
+}
+
+/// Wait for the next event of the clients, None once it is time to rotate.
+fn next_event(received: &Receiver<Event>, next_rotation: Instant) -> anyhow::Result<Option<Event>> {
+    let timeout = next_rotation.saturating_duration_since(Instant::now());
+    match received.recv_timeout(timeout) {
+        Ok(event) => Ok(Some(event)),
+        Err(RecvTimeoutError::Timeout) => Ok(None),
+        Err(RecvTimeoutError::Disconnected) => Err(anyhow!("Stopped accepting clients")),
+    }
+}
+
+/// Accept the clients and read their requests, each on its own thread, up to `MAX_CLIENTS`
+/// at once.
+fn accept(listener: UnixListener, events: Sender<Event>) {
+    let connected = Arc::new(AtomicUsize::new(0));
+    for (id, stream) in listener.incoming().enumerate() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Failed to accept client: {}", e);
+                continue;
+            }
+        };
+
+        if connected.load(Ordering::Relaxed) >= MAX_CLIENTS {
+            warn!("Refused client, {} clients are already connected", MAX_CLIENTS);
+            continue;
+        }
+
+        let id = id as u64;
+        let client = peer_uid(&stream).and_then(|uid| Ok((stream.try_clone()?, Client::new(id, uid, stream)?)));
+        let (reader, client) = match client {
+            Ok(client) => client,
+            Err(e) => {
+                warn!("Failed to identify client: {}", e);
+                continue;
+            }
+        };
+
+        info!("Client {} connected with uid {}", id, client.uid);
+        if events.send(Event::Connected(client)).is_err() {
+            return;
+        }
+
+        // The reader exits once the client is gone or dropped by the collection loop.
+        connected.fetch_add(1, Ordering::Relaxed);
+        let connected = connected.clone();
+        let events = events.clone();
+        thread::spawn(move || {
+            let mut reader = BufReader::new(reader);
+            while let Ok(Some(request)) = receive::<Request>(&mut reader) {
+                if events.send(Event::Request(id, request)).is_err() {
+                    break;
+                }
+            }
+            connected.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+}
+
+/// Bind the socket, which is only accessible by the user of the daemon or, if provided, the
+/// members of the group.
+fn bind(path: &Path, group: Option<&str>) -> anyhow::Result<UnixListener> {
+    // Socket left behind by a daemon which didn't exit cleanly is replaced.
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            bail!("{} exists and is not a socket", path.display());
+        }
+
+        if UnixStream::connect(path).is_ok() {
+            bail!("Another daemon is listening on {}", path.display());
+        }
+
+        fs::remove_file(path)?;
+    }
+
+    // Socket is created without permissions for others, so nobody can connect before the
+    // permissions are set.
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(umask) };
+    let listener = listener.with_context(|| format!("Failed to bind {}", path.display()))?;
+
+    if let Some(group) = group {
+        std::os::unix::fs::chown(path, None, Some(group_id(group)?))?;
+        fs::set_permissions(path, Permissions::from_mode(0o660))?;
+    }
+
+    Ok(listener)
+}
+
+/// Id of the group by its name or id.
+fn group_id(group: &str) -> anyhow::Result<u32> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+
+    let name = CString::new(group)?;
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        bail!("Unknown group {}", group);
+    }
+
+    Ok(unsafe { (*entry).gr_gid })
+}
+
+/// User id of the process on the other side of the socket.
+fn peer_uid(stream: &UnixStream) -> std::io::Result<u32> {
+    let mut cred: libc::ucred = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(cred.uid)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        os::unix::{fs::PermissionsExt, net::UnixStream},
+        sync::Arc,
+    };
+
+    use crate::daemon::{bind, peer_uid, Client, QUEUE_SIZE};
+
+    #[test]
+    fn bind_private_socket() {
+        let dir = std::env::temp_dir().join(format!("flow-top-talker-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("daemon.sock");
+
+        let listener = bind(&path, None).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(bind(&path, None).is_err());
+
+        let client = UnixStream::connect(&path).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        assert_eq!(peer_uid(&server).unwrap(), unsafe { libc::geteuid() });
+        drop((client, server, listener));
+
+        // Stale socket is replaced, while other files are left alone.
+        assert!(bind(&path, None).is_ok());
+        std::fs::write(dir.join("file"), "").unwrap();
+        assert!(bind(&dir.join("file"), None).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn drop_stuck_client() {
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        let client = Client::new(0, 0, stream).unwrap();
+
+        // The peer never reads, so the writer blocks and the queue fills up without blocking
+        // the sender.
+        let line: Arc<[u8]> = vec![b'x'; 1 << 20].into();
+        let sent = (0..QUEUE_SIZE + 16).take_while(|_| client.send_encoded(&line)).count();
+        assert!(sent < QUEUE_SIZE + 16);
+    }
+}
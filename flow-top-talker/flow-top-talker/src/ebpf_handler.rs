@@ -1,33 +1,68 @@
+use std::{collections::HashSet, time::Instant};
+
 use aya::{
     maps::{
-        Array, HashMap, MapData, MapError, PerCpuHashMap
-    }, programs::KProbe, util::nr_cpus, Ebpf
+        Array, HashMap, Map, MapData, MapError, PerCpuArray, PerCpuHashMap, RingBuf
+    }, programs::{links::FdLink, BtfTracePoint, FEntry, FExit, TracePoint}, util::nr_cpus, Ebpf, EbpfLoader
 };
 #[rustfmt::skip]
 use log::{debug, warn};
 
-use crate::{cli::Cli, flow_info::LimitedMaxHeap};
+use crate::{
+    btf::Btf,
+    churn::ChurnTracker,
+    cli::Cli,
+    connection::ConnectionTable,
+    filter::Filter,
+    flow_info::{FlowInfo, LimitedMaxHeap},
+    map_sizing::{budget_bytes, MapSizing},
+    matcher::ProcessMatcher,
+    pinning::{self, Pins},
+    rotation::{wait_for_writers, WRITERS_TIMEOUT},
+    ui::Section,
+};
 
 use flow_top_talker_common::common_types::{
-    ConfigKey, FlowKey,
-    CONFIG_MAP_NAME, EGRESS_TRACKER_0_MAP_NAME,
-    EGRESS_TRACKER_1_MAP_NAME, FLAG_MAP_NAME, INGRESS_TRACKER_0_MAP_NAME,
-    INGRESS_TRACKER_1_MAP_NAME,
+    ConfigKey, ConnEvent, FilterConfig, FlowHistograms, FlowKey, FlowStats, TaskOffsets, TcpSockOffsets,
+    ACTIVE_CALLS_MAP_NAME, ALLOWED_MAP_NAME, CONFIG_MAP_NAME, CONN_EVENTS_MAP_NAME, CONN_STARTS_MAP_NAME,
+    EGRESS_HISTOGRAMS_0_MAP_NAME, EGRESS_HISTOGRAMS_1_MAP_NAME, EGRESS_TRACKER_0_MAP_NAME,
+    EGRESS_TRACKER_1_MAP_NAME, FLAG_MAP_NAME, HISTOGRAM_MAP_NAMES, INFLIGHT_MAP_NAME,
+    INGRESS_HISTOGRAMS_0_MAP_NAME, INGRESS_HISTOGRAMS_1_MAP_NAME, INGRESS_TRACKER_0_MAP_NAME,
+    INGRESS_TRACKER_1_MAP_NAME, MAPS_LAYOUT_VERSION, TASK_OFFSETS_MAP_NAME, TCP_SOCK_OFFSETS_MAP_NAME,
+    MAX_ALLOWED, TRACED_MAP_NAME, TRACKER_MAP_NAMES, VERSION_MAP_NAME,
 };
-use anyhow::anyhow;
+use anyhow::{anyhow, Context};
 
 /// Handler to ebpf.
+///
+/// The maps used by the user space program are taken out of the loaded program, or opened
+/// from their pins when the program is already loaded and attached by a previous run.
 pub struct EbpfHandler {
-    ebpf: Ebpf,
+    ebpf: Option<Ebpf>,
+    maps: std::collections::HashMap<&'static str, Map>,
+    pins: Option<Pins>,
     nr_cpus: usize,
+    map_sizing: MapSizing,
+    conn_events: RingBuf<MapData>,
+    filter: Filter,
+    matcher: Option<ProcessMatcher>,
+    traces_command: bool,
 }
 
 impl EbpfHandler {
     /// Init the ebpf handler by loading by the ebpf program and determining number of ncpus
     /// which would be used later to capture the results.
     /// 
-    /// Bump the memlock rlimit as recommended by aya.
-    pub fn init() -> anyhow::Result<EbpfHandler> {
+    /// The tracker maps are sized based on the max flows and memory budget provided by the
+    /// user before the program is loaded. Bump the memlock rlimit as recommended by aya. If
+    /// the maps are pinned in the pin path by a previous run, they are re-used instead.
+    pub fn init(cli: &Cli) -> anyhow::Result<EbpfHandler> {
+        let nr_cpus = nr_cpus();
+        if nr_cpus.is_err() {
+            return Err(anyhow!("Not able to get possible CPU. Exiting early.."));
+        }
+        let nr_cpus = nr_cpus.unwrap();
+
         // Bump the memlock rlimit. This is needed for older kernels that don't use the
         // new memcg based accounting, see https://lwn.net/Articles/837122/
         let rlim = libc::rlimit {
@@ -39,11 +74,33 @@ impl EbpfHandler {
             debug!("remove limit on locked memory failed, ret is: {}", ret);
         }
 
+        let pins = cli.pin_path.as_deref().map(Pins::new);
+        let mut handler = match pins {
+            Some(pins) if pins.exist() => Self::reuse(cli, nr_cpus, pins)?,
+            pins => Self::load(cli, nr_cpus, pins)?,
+        };
+        handler.traces_command = !cli.command.is_empty();
+        Ok(handler)
+    }
+
+    /// Load the ebpf program with the tracker maps sized for the CLI, and pin its maps if a
+    /// pin path is provided. The version map is only pinned once the programs are attached.
+    fn load(cli: &Cli, nr_cpus: usize, pins: Option<Pins>) -> anyhow::Result<EbpfHandler> {
+        let budget = cli.memory_budget.map(budget_bytes).transpose()?;
+        let map_sizing = MapSizing::resolve(cli.max_flows, nr_cpus, budget, cli.auto_shrink)?;
+
+        let mut loader = EbpfLoader::new();
+        for map_name in TRACKER_MAP_NAMES.into_iter().chain(HISTOGRAM_MAP_NAMES) {
+            loader.set_max_entries(map_name, map_sizing.max_flows);
+        }
+        loader.set_max_entries(CONN_STARTS_MAP_NAME, map_sizing.max_flows);
+        loader.set_max_entries(ACTIVE_CALLS_MAP_NAME, map_sizing.max_flows);
+
         // This will include your eBPF object file as raw bytes at compile-time and load it at
         // runtime. This approach is recommended for most real-world use cases. If you would
         // like to specify the eBPF program at runtime rather than at compile-time, you can
         // reach for `Bpf::load_file` instead.
-        let mut ebpf = aya::Ebpf::load(aya::include_bytes_aligned!(concat!(
+        let mut ebpf = loader.load(aya::include_bytes_aligned!(concat!(
             env!("OUT_DIR"),
             "/flow-top-talker"
         )))?;
@@ -53,33 +110,179 @@ impl EbpfHandler {
         }
         
         Self::init_flag(&mut ebpf)?;
+        Self::init_tcp_sock_offsets(&mut ebpf)?;
+        Self::init_task_offsets(&mut ebpf)?;
+        Self::init_version(&mut ebpf)?;
 
-        let nr_cpus = nr_cpus();
-        if nr_cpus.is_err() {
-            return Err(anyhow!("Not able to get possible CPU. Exiting early.."));
+        if let Some(pins) = &pins {
+            // Pins left by a run which failed to attach can't be re-used, start over.
+            if pins.map(FLAG_MAP_NAME).exists() {
+                warn!("Removing the incomplete pins left by a previous run");
+                pins.remove()?;
+            }
+
+            pins.create_dirs()?;
+            for (name, map) in ebpf.maps().filter(|(name, _)| *name != VERSION_MAP_NAME) {
+                map.pin(pins.map(name))?;
+            }
         }
-        
-        Ok(EbpfHandler { ebpf, nr_cpus: nr_cpus.unwrap() })
+
+        let mut maps = std::collections::HashMap::new();
+        for name in pinning::map_names() {
+            let map = ebpf.take_map(name).ok_or_else(|| anyhow!("Failed to read {} map name", name))?;
+            maps.insert(name, map);
+        }
+
+        Self::new(Some(ebpf), maps, pins, nr_cpus, map_sizing)
     }
 
-    /// Add config provided by the user to the ebpf program.
-    pub fn add_config(
-        &mut self,
-        cli: &Cli
-    ) -> anyhow::Result<()> {
-        match self.ebpf.map_mut(CONFIG_MAP_NAME) {
+    /// Re-use the maps and programs pinned by a previous run, which are still collecting.
+    fn reuse(cli: &Cli, nr_cpus: usize, pins: Pins) -> anyhow::Result<EbpfHandler> {
+        let maps = pins.open_maps()?;
+
+        let max_flows = pins.max_flows()?;
+        if max_flows != cli.max_flows {
+            warn!("Pinned tracker maps hold {} flows, ignoring the requested {}", max_flows, cli.max_flows);
+        }
+        let map_sizing = MapSizing::resolve(max_flows, nr_cpus, None, false)?;
+
+        Self::new(None, maps, Some(pins), nr_cpus, map_sizing)
+    }
+
+    fn new(
+        ebpf: Option<Ebpf>,
+        mut maps: std::collections::HashMap<&'static str, Map>,
+        pins: Option<Pins>,
+        nr_cpus: usize,
+        map_sizing: MapSizing,
+    ) -> anyhow::Result<EbpfHandler> {
+        let conn_events = match maps.remove(CONN_EVENTS_MAP_NAME) {
+            Some(map) => RingBuf::try_from(map)?,
+            None => return Err(anyhow!("Failed to read connection events map name")),
+        };
+
+        Ok(EbpfHandler {
+            ebpf,
+            maps,
+            pins,
+            nr_cpus,
+            map_sizing,
+            conn_events,
+            filter: Filter::default(),
+            matcher: None,
+            traces_command: false,
+        })
+    }
+
+    /// Whether the program was loaded and attached by a previous run, of which the pins are
+    /// re-used.
+    pub fn reused(&self) -> bool {
+        self.ebpf.is_none()
+    }
+
+    /// Tracker which the ebpf program is currently writing to.
+    pub fn flag(&self) -> anyhow::Result<u32> {
+        match self.maps.get(FLAG_MAP_NAME) {
             Some(map) => {
-                let mut map_data: HashMap<&mut MapData, ConfigKey, u64> =
-                    HashMap::try_from(map).unwrap();
-                
-                if let Some(pid) = cli.pid {
-                    map_data.insert(ConfigKey::PID, pid, 0)?;
-                }
-    
-                if let Some(tid) = cli.tid {
-                    map_data.insert(ConfigKey::TID, tid, 0)?;
-                }
-    
+                let array: Array<&MapData, u32> = Array::try_from(map)?;
+                Ok(array.get(&0, 0)?)
+            },
+            None => Err(anyhow!("Failed to read flag map name")),
+        }
+    }
+
+    /// Size of the tracker maps the program was loaded with.
+    pub fn map_sizing(&self) -> &MapSizing {
+        &self.map_sizing
+    }
+
+    /// Filter applied by the last call to `set_filter`, as it returned it.
+    pub fn filter(&self) -> &Filter {
+        &self.filter
+    }
+
+    /// Pass the filter to the ebpf program. The filter can be changed while the program is
+    /// attached, it is replaced in a single update so the program never sees a partial one.
+    ///
+    /// The processes matching the name and executable patterns are allowed before the filter
+    /// relies on them. `traced` is refused unless a command is traced, in which case the filter
+    /// is always restricted to it. Returns the filter applied.
+    pub fn set_filter(&mut self, filter: &Filter) -> anyhow::Result<Filter> {
+        if filter.traced && !self.traces_command {
+            return Err(anyhow!("No command is traced, `traced` only applies to a command run after --"));
+        }
+        let filter = &Filter { traced: self.traces_command, ..filter.clone() };
+
+        filter.config(0)?;
+        let matcher = filter.matcher()?;
+        self.sync_allowed(matcher.as_ref())?;
+        self.write_filter(filter)?;
+
+        self.filter = filter.clone();
+        self.matcher = matcher;
+        Ok(filter.clone())
+    }
+
+    /// Allow the processes matching the patterns of the filter which started since the last
+    /// refresh, and forget the ones which no longer match. Called every interval.
+    pub fn refresh_allowed(&mut self) -> anyhow::Result<()> {
+        let Some(matcher) = self.matcher.clone() else {
+            return Ok(());
+        };
+
+        // Sockets of the processes newly allowed may have been skipped with the verdict cached
+        // before, have them decided again.
+        if self.sync_allowed(Some(&matcher))? {
+            let filter = self.filter.clone();
+            self.write_filter(&filter)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sync the allowed processes with the ones matching, all of them are removed without a
+    /// matcher. Returns whether processes were added.
+    ///
+    /// Only the processes allowed before the scan are removed, so that the children added by
+    /// the ebpf program in the meantime are kept until the next scan.
+    fn sync_allowed(&mut self, matcher: Option<&ProcessMatcher>) -> anyhow::Result<bool> {
+        let map = self.maps.get_mut(ALLOWED_MAP_NAME).ok_or_else(|| anyhow!("Failed to read allowed map name"))?;
+        let mut allowed: HashMap<&mut MapData, u32, u32> = HashMap::try_from(map)?;
+        let before = allowed.keys().collect::<Result<HashSet<u32>, MapError>>()?;
+        let pids = matcher.map(ProcessMatcher::scan).unwrap_or_default();
+
+        for pid in before.difference(&pids) {
+            // Processes exiting in the meantime are removed by the ebpf program.
+            match allowed.remove(pid) {
+                Ok(()) | Err(MapError::KeyNotFound) => {},
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let added: Vec<&u32> = pids.difference(&before).collect();
+        for pid in &added {
+            allowed.insert(**pid, 0, 0)
+                .with_context(|| format!("Failed to allow process {}, at most {} processes can match", pid, MAX_ALLOWED))?;
+        }
+
+        Ok(!added.is_empty())
+    }
+
+    /// Write the filter to the config map with the next generation.
+    fn write_filter(&mut self, filter: &Filter) -> anyhow::Result<()> {
+        match self.maps.get_mut(CONFIG_MAP_NAME) {
+            Some(map) => {
+                let mut map_data: HashMap<&mut MapData, ConfigKey, FilterConfig> =
+                    HashMap::try_from(map)?;
+
+                // Bump the generation so the verdicts cached for the sockets are decided again.
+                let generation = match map_data.get(&ConfigKey::FILTER, 0) {
+                    Ok(config) => config.generation.wrapping_add(1),
+                    Err(MapError::KeyNotFound) => 1,
+                    Err(e) => return Err(e.into()),
+                };
+                map_data.insert(ConfigKey::FILTER, filter.config(generation)?, 0)?;
+
                 Ok(())
             },
             None => {
@@ -88,78 +291,237 @@ impl EbpfHandler {
         }
     }
 
-    /// Attach to required kprobes.
+    /// Trace the process, along with the processes it forks from now on. Only the flows of the
+    /// traced processes are tracked by a filter with `traced` set.
+    pub fn trace(&mut self, pid: u32) -> anyhow::Result<()> {
+        match self.maps.get_mut(TRACED_MAP_NAME) {
+            Some(map) => {
+                let mut traced: HashMap<&mut MapData, u32, u32> = HashMap::try_from(map)?;
+                traced.insert(pid, std::process::id(), 0)?;
+                Ok(())
+            },
+            None => Err(anyhow!("Failed to read traced map name")),
+        }
+    }
+
+    /// Attach the programs, and pin the links to the pin path if provided. Nothing is
+    /// attached when re-using the pins as the programs are still attached.
     pub fn attach(&mut self) -> anyhow::Result<()> {
-        self.attach_to_beginning("tcp_sendmsg_kprobe", "tcp_sendmsg")?;
-        self.attach_to_beginning("tcp_recvmsg_kprobe", "tcp_recvmsg")?;
-        self.attach_to_beginning("udp_sendmsg_kprobe", "udp_sendmsg")?;
-        self.attach_to_beginning("udp_recvmsg_kprobe", "udp_recvmsg")?;
+        if self.reused() {
+            return Ok(());
+        }
+
+        // Messages are counted on entry, and the errors and latency on return, except for the
+        // messages received by udp_recvmsg whose peer is only known on return.
+        // The programs looking up the socket cache are given the socket by BTF, which its
+        // storage needs.
+        let btf = aya::Btf::from_sys_fs()
+            .map_err(|e| anyhow!("Failed to read the kernel BTF, needed by the fentry and fexit programs: {}", e))?;
+        for fn_name in ["tcp_sendmsg", "tcp_recvmsg", "udp_sendmsg", "udp_recvmsg"] {
+            self.attach_fentry(&format!("{}_fentry", fn_name), fn_name, &btf)?;
+            self.attach_fexit(&format!("{}_fexit", fn_name), fn_name, &btf)?;
+        }
+
+        // Keep the socket cache in sync with the peer of the sockets, their storage is freed
+        // along with them.
+        self.attach_fentry("ip4_datagram_connect_fentry", "ip4_datagram_connect", &btf)?;
+        self.attach_fentry("udp_disconnect_fentry", "udp_disconnect", &btf)?;
+
+        self.attach_btf_tracepoint("tcp_retransmit_skb", &btf)?;
+        self.attach_btf_tracepoint("tcp_send_reset", &btf)?;
+        self.attach_btf_tracepoint("tcp_receive_reset", &btf)?;
+
+        // Track connections from open to close.
+        self.attach_btf_tracepoint("inet_sock_set_state", &btf)?;
+        self.attach_fexit("inet_csk_accept_fexit", "inet_csk_accept", &btf)?;
+
+        // Follow the descendants of the traced command and of the allowed processes.
+        self.attach_to_tracepoint("task_newtask", "task", "task_newtask")?;
+        self.attach_to_tracepoint("sched_process_exit", "sched", "sched_process_exit")?;
+
+        // Everything is pinned once the version is, see `Pins::exist`.
+        if let (Some(pins), Some(map)) = (&self.pins, self.maps.get(VERSION_MAP_NAME)) {
+            map.pin(pins.map(VERSION_MAP_NAME))?;
+        }
 
         Ok(())
     }
 
-    /// Rotate data and add it to the heap provided.
+    /// Rotate data and add it to the heaps and the connections provided.
     /// 
-    /// The flow info is shared between ebpf program and user app via a double buffer.
+    /// The flow info is shared between ebpf program and user app via a double buffer. After
+    /// the flag is flipped, wait for the writers still updating the previous buffer to leave
+    /// before draining it so that no update is lost at the rotation boundary. Returns false if
+    /// they didn't leave in time, in which case the buffer is drained anyway.
     pub fn rotate_data(
         &mut self,
         ingress_heap: &mut LimitedMaxHeap,
         cur_flag_value: u32,
         egress_heap: &mut LimitedMaxHeap,
-    ) -> anyhow::Result<()> {
-        if let Some(map) = self.ebpf.map_mut(FLAG_MAP_NAME) {
+        connections: &mut ConnectionTable,
+    ) -> anyhow::Result<bool> {
+        if let Some(map) = self.maps.get_mut(FLAG_MAP_NAME) {
             let mut array: Array<&mut _, u32> = Array::try_from(map).unwrap();
-            if cur_flag_value == 0 {
-                let _ = array.set(0, 1, 0);
-                self.fetch_latest_data(INGRESS_TRACKER_0_MAP_NAME, ingress_heap);
-                self.fetch_latest_data(EGRESS_TRACKER_0_MAP_NAME, egress_heap);
-            } else {
-                let _ = array.set(0, 0, 0);
-                self.fetch_latest_data(INGRESS_TRACKER_1_MAP_NAME, ingress_heap);
-                self.fetch_latest_data(EGRESS_TRACKER_1_MAP_NAME, egress_heap);
+            array.set(0, cur_flag_value ^ 0x1, 0)?;
+        }
+
+        let writers_left = wait_for_writers(|| self.inflight_writers(cur_flag_value), WRITERS_TIMEOUT)?;
+
+        if cur_flag_value == 0 {
+            self.fetch_latest_data(
+                INGRESS_TRACKER_0_MAP_NAME, INGRESS_HISTOGRAMS_0_MAP_NAME, Section::Ingress, ingress_heap, connections,
+            );
+            self.fetch_latest_data(
+                EGRESS_TRACKER_0_MAP_NAME, EGRESS_HISTOGRAMS_0_MAP_NAME, Section::Egress, egress_heap, connections,
+            );
+        } else {
+            self.fetch_latest_data(
+                INGRESS_TRACKER_1_MAP_NAME, INGRESS_HISTOGRAMS_1_MAP_NAME, Section::Ingress, ingress_heap, connections,
+            );
+            self.fetch_latest_data(
+                EGRESS_TRACKER_1_MAP_NAME, EGRESS_HISTOGRAMS_1_MAP_NAME, Section::Egress, egress_heap, connections,
+            );
+        }
+
+        Ok(writers_left)
+    }
+
+    /// Drain the connection events sent since the last call into the churn tracker.
+    pub fn drain_conn_events(&mut self, churn: &mut ChurnTracker) {
+        while let Some(item) = self.conn_events.next() {
+            if item.len() < size_of::<ConnEvent>() {
+                continue;
+            }
+
+            // Copy the event out of the entry as it is released once the item is dropped.
+            let event = unsafe { std::ptr::read_unaligned(item.as_ptr() as *const ConnEvent) };
+            churn.record(&event);
+        }
+    }
+
+    /// Number of writers in-flight on the tracker across all the CPUs.
+    fn inflight_writers(&self, tracker: u32) -> anyhow::Result<u64> {
+        match self.maps.get(INFLIGHT_MAP_NAME) {
+            Some(map) => {
+                let array: PerCpuArray<&MapData, u64> = PerCpuArray::try_from(map)?;
+                let writers = array.get(&tracker, 0)?;
+                Ok(writers.iter().fold(0u64, |total, w| total.wrapping_add(*w)))
+            },
+            None => {
+                Err(anyhow!("Failed to read inflight map name"))
             }
         }
+    }
+
+    // Attach to the entry of the kernel function mentioned via fn_name.
+    fn attach_fentry(&mut self, program_name: &str, fn_name: &str, btf: &aya::Btf) -> anyhow::Result<()> {
+        let ebpf = self.ebpf.as_mut().unwrap();
+        let program: &mut FEntry = ebpf.program_mut(program_name).unwrap().try_into()?;
+        program.load(fn_name, btf)?;
+
+        let link_id = program.attach()?;
+        if let Some(pins) = &self.pins {
+            FdLink::from(program.take_link(link_id)?).pin(pins.link(program_name))?;
+        }
 
         Ok(())
     }
 
+    // Attach to the return of the kernel function mentioned via fn_name. Unlike kretprobes,
+    // fexit programs see the return of every call however many are in progress at once.
+    fn attach_fexit(&mut self, program_name: &str, fn_name: &str, btf: &aya::Btf) -> anyhow::Result<()> {
+        let ebpf = self.ebpf.as_mut().unwrap();
+        let program: &mut FExit = ebpf.program_mut(program_name).unwrap().try_into()?;
+        program.load(fn_name, btf)?;
 
-    // Attach to the beginning of the kernel function mentioned via kprobe_name.
-    fn attach_to_beginning(&mut self, program_name: &str, kprobe_name: &str) -> anyhow::Result<()> {
-        let program: &mut KProbe = self.ebpf.program_mut(program_name).unwrap().try_into()?;
+        let link_id = program.attach()?;
+        if let Some(pins) = &self.pins {
+            FdLink::from(program.take_link(link_id)?).pin(pins.link(program_name))?;
+        }
+
+        Ok(())
+    }
+
+    // Attach to the tracepoint of the same name as the program, with its arguments typed by BTF.
+    fn attach_btf_tracepoint(&mut self, name: &str, btf: &aya::Btf) -> anyhow::Result<()> {
+        let ebpf = self.ebpf.as_mut().unwrap();
+        let program: &mut BtfTracePoint = ebpf.program_mut(name).unwrap().try_into()?;
+        program.load(name, btf)?;
+
+        let link_id = program.attach()?;
+        if let Some(pins) = &self.pins {
+            FdLink::from(program.take_link(link_id)?).pin(pins.link(name))?;
+        }
+
+        Ok(())
+    }
+
+    // Attach to the tracepoint mentioned via category and name.
+    fn attach_to_tracepoint(&mut self, program_name: &str, category: &str, name: &str) -> anyhow::Result<()> {
+        let ebpf = self.ebpf.as_mut().unwrap();
+        let program: &mut TracePoint = ebpf.program_mut(program_name).unwrap().try_into()?;
         program.load()?;
 
-        program.attach(kprobe_name, 0)?;
+        let link_id = program.attach(category, name)?;
+        if let Some(pins) = &self.pins {
+            let link = FdLink::try_from(program.take_link(link_id)?)
+                .map_err(|e| anyhow!("Failed to pin {}, BPF links for tracepoints need Linux 5.15+: {}", program_name, e))?;
+            link.pin(pins.link(program_name))?;
+        }
 
         Ok(())
     }
 
-    /// Fetch latest flow info data from the provided 
+    /// Fetch latest flow info data from the provided map along with its histograms into the
+    /// heap, and join it with the other direction in the connections.
     fn fetch_latest_data(
         &mut self,
         map_name: &str, 
+        histograms_map_name: &str,
+        section: Section,
         heap: &mut LimitedMaxHeap,
+        connections: &mut ConnectionTable,
     ) {
-        if let Some(map) = self.ebpf.map_mut(map_name) {
-            let mut map_data: PerCpuHashMap<&mut MapData, FlowKey, u64> =
+        let mut histograms = self.drain_histograms(histograms_map_name);
+        if let Some(map) = self.maps.get_mut(map_name) {
+            let start = Instant::now();
+            let mut map_data: PerCpuHashMap<&mut MapData, FlowKey, FlowStats> =
                 PerCpuHashMap::try_from(map).unwrap();
             let keys: Vec<Result<FlowKey, MapError>> = map_data.keys().collect();
+            let nr_keys = keys.len();
             for key in keys.into_iter().flatten() {
-                if let Ok(cur_throughput) = map_data.get(&key, 0) {
-                    let mut total_throughput = 0;
-                    for index in 0..self.nr_cpus {
-                        total_throughput += cur_throughput[index];
-                    }
-
-                    heap.add(&key, total_throughput);
+                if let Ok(cur_stats) = map_data.get(&key, 0) {
+                    let flow_info = FlowInfo::from_per_cpu(&key, &cur_stats[..self.nr_cpus])
+                        .with_histograms(&histograms.remove(&key).unwrap_or_default());
+                    connections.add(section, &flow_info);
+                    heap.add_flow(flow_info);
                 }
 
                 if map_data.remove(&key).is_err() {
                     eprintln!("Error removing data from map...");
                 }
             }
+
+            debug!("Drained {} flows from {} in {:?}", nr_keys, map_name, start.elapsed());
         }
     }
+
+    /// Drain the histograms of the flows from the provided map.
+    fn drain_histograms(&mut self, map_name: &str) -> std::collections::HashMap<FlowKey, FlowHistograms> {
+        let mut histograms = std::collections::HashMap::new();
+        if let Some(map) = self.maps.get_mut(map_name) {
+            let mut map_data: HashMap<&mut MapData, FlowKey, FlowHistograms> = HashMap::try_from(map).unwrap();
+            let keys: Vec<Result<FlowKey, MapError>> = map_data.keys().collect();
+            for key in keys.into_iter().flatten() {
+                if let Ok(value) = map_data.get(&key, 0) {
+                    histograms.insert(key, value);
+                }
+                let _ = map_data.remove(&key);
+            }
+        }
+
+        histograms
+    }
     
     fn init_flag(ebpf: &mut Ebpf) -> anyhow::Result<()> {
         if let Some(map) = ebpf.map_mut(FLAG_MAP_NAME) {
@@ -182,4 +544,72 @@ impl EbpfHandler {
 
         Ok(())
     }
+
+    fn init_version(ebpf: &mut Ebpf) -> anyhow::Result<()> {
+        if let Some(map) = ebpf.map_mut(VERSION_MAP_NAME) {
+            let mut array: Array<&mut _, u32> = Array::try_from(map)?;
+            array.set(0, MAPS_LAYOUT_VERSION, 0)?;
+        }
+
+        Ok(())
+    }
+
+    /// Resolve offsets of the fields in `tcp_sock` from the kernel BTF and pass them to the ebpf
+    /// program. TCP health metrics are not sampled if this fails.
+    fn init_tcp_sock_offsets(ebpf: &mut Ebpf) -> anyhow::Result<()> {
+        let fields = ["srtt_us", "snd_cwnd", "total_retrans"];
+        let offsets = match Btf::from_sys_fs().and_then(|btf| btf.member_offsets("tcp_sock", &fields)) {
+            Ok(offsets) => offsets,
+            Err(e) => {
+                warn!("Failed to resolve tcp_sock from BTF, TCP health metrics are disabled: {}", e);
+                return Ok(());
+            }
+        };
+
+        let (Some(srtt_us), Some(snd_cwnd), Some(total_retrans)) =
+            (offsets.get("srtt_us"), offsets.get("snd_cwnd"), offsets.get("total_retrans")) else {
+            warn!("Missing tcp_sock fields in BTF, TCP health metrics are disabled");
+            return Ok(());
+        };
+
+        if let Some(map) = ebpf.map_mut(TCP_SOCK_OFFSETS_MAP_NAME) {
+            let mut array: Array<&mut _, TcpSockOffsets> = Array::try_from(map)?;
+            let offsets = TcpSockOffsets {
+                srtt_us: *srtt_us,
+                snd_cwnd: *snd_cwnd,
+                total_retrans: *total_retrans,
+                valid: 1,
+            };
+            array.set(0, offsets, 0)?;
+        }
+
+        Ok(())
+    }
+
+    /// Resolve offsets of `task_struct.signal` and `signal_struct.live` from the kernel BTF and
+    /// pass them to the ebpf program. Processes are otherwise untracked once their leader exits.
+    fn init_task_offsets(ebpf: &mut Ebpf) -> anyhow::Result<()> {
+        let offsets = Btf::from_sys_fs().and_then(|btf| {
+            Ok((btf.member_offsets("task_struct", &["signal"])?, btf.member_offsets("signal_struct", &["live"])?))
+        });
+        let (task, signal) = match offsets {
+            Ok(offsets) => offsets,
+            Err(e) => {
+                warn!("Failed to resolve task_struct from BTF, processes are untracked once their leader exits: {}", e);
+                return Ok(());
+            }
+        };
+
+        let (Some(signal), Some(live)) = (task.get("signal"), signal.get("live")) else {
+            warn!("Missing task_struct fields in BTF, processes are untracked once their leader exits");
+            return Ok(());
+        };
+
+        if let Some(map) = ebpf.map_mut(TASK_OFFSETS_MAP_NAME) {
+            let mut array: Array<&mut _, TaskOffsets> = Array::try_from(map)?;
+            array.set(0, TaskOffsets { signal: *signal, live: *live, valid: 1 }, 0)?;
+        }
+
+        Ok(())
+    }
 }
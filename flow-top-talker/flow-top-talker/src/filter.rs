@@ -0,0 +1,218 @@
+use std::{ffi::CString, fmt, str::FromStr};
+
+use anyhow::{anyhow, bail};
+use flow_top_talker_common::common_types::{FilterConfig, FILTER_ALLOWED, FILTER_TRACED, MAX_FILTER_VALUES};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cli::Cli,
+    matcher::{Pattern, ProcessMatcher},
+};
+
+/// Filters passed to the eBPF program, flows of the other processes are not tracked.
+///
+/// A flow is kept when it matches any of the values of a kind and all the kinds set, e.g.
+/// `pid=1,2 uid=0` keeps the flows of processes 1 and 2 run by root.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Filter {
+    /// Process ids, covering all the threads of the processes.
+    pub pids: Vec<u32>,
+    /// Thread ids.
+    pub tids: Vec<u32>,
+    /// User ids, with the user names resolved.
+    pub uids: Vec<u32>,
+    /// Only the command launched after `--` and its descendants.
+    #[serde(default)]
+    pub traced: bool,
+    /// Patterns of the process names, see `Pattern`.
+    #[serde(default)]
+    pub comms: Vec<String>,
+    /// Patterns of the executable paths.
+    #[serde(default)]
+    pub exes: Vec<String>,
+}
+
+impl Filter {
+    pub fn from_cli(cli: &Cli) -> anyhow::Result<Self> {
+        let mut uids = cli.uid.clone();
+        for user in &cli.user {
+            uids.push(user_id(user)?);
+        }
+
+        Ok(Self {
+            pids: cli.pid.clone(),
+            tids: cli.tid.clone(),
+            uids,
+            traced: !cli.command.is_empty(),
+            comms: cli.comm.clone(),
+            exes: cli.exe.clone(),
+        })
+    }
+
+    /// Filter as passed to the ebpf program, with the generation of the update.
+    pub fn config(&self, generation: u32) -> anyhow::Result<FilterConfig> {
+        let mut config = FilterConfig { generation, ..Default::default() };
+        config.nr_pids = copy_values("pid", &self.pids, &mut config.pids)?;
+        config.nr_tids = copy_values("tid", &self.tids, &mut config.tids)?;
+        config.nr_uids = copy_values("uid", &self.uids, &mut config.uids)?;
+        if self.traced {
+            config.flags |= FILTER_TRACED;
+        }
+        if !self.comms.is_empty() || !self.exes.is_empty() {
+            config.flags |= FILTER_ALLOWED;
+        }
+        Ok(config)
+    }
+
+    /// Matcher of the processes allowed by the name and executable patterns, if any.
+    pub fn matcher(&self) -> anyhow::Result<Option<ProcessMatcher>> {
+        ProcessMatcher::new(&self.comms, &self.exes)
+    }
+}
+
+/// Copy the values of a kind into the fixed size array of the ebpf program.
+fn copy_values(kind: &str, values: &[u32], array: &mut [u32; MAX_FILTER_VALUES]) -> anyhow::Result<u32> {
+    if values.len() > MAX_FILTER_VALUES {
+        bail!("Too many {} filters, at most {} are supported", kind, MAX_FILTER_VALUES);
+    }
+
+    array[..values.len()].copy_from_slice(values);
+    Ok(values.len() as u32)
+}
+
+/// Id of the user by its name.
+fn user_id(user: &str) -> anyhow::Result<u32> {
+    let name = CString::new(user)?;
+    let entry = unsafe { libc::getpwnam(name.as_ptr()) };
+    if entry.is_null() {
+        bail!("Unknown user {}", user);
+    }
+
+    Ok(unsafe { (*entry).pw_uid })
+}
+
+/// Parse the filter as displayed, e.g. `pid=1234,1240 uid=0`, or `none` to clear the filter.
+/// Users can also be given by name with `user=NAME`, and `traced` keeps the flows to the
+/// command launched. Name and executable patterns (`comm=nginx* exe=/usr/sbin/*`) take a
+/// single pattern each, as they can contain commas.
+impl FromStr for Filter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut filter = Filter::default();
+        for token in s.split_whitespace() {
+            let invalid = || anyhow!("Invalid filter `{}`, expected pid=PID, tid=TID, uid=UID, user=NAME, comm=PATTERN, exe=PATTERN, traced or none", token);
+            let (field, values) = match token.split_once('=') {
+                Some((field, values)) => (field, values),
+                None if token == "none" => continue,
+                None if token == "traced" => {
+                    filter.traced = true;
+                    continue;
+                }
+                None => return Err(invalid()),
+            };
+
+            let patterns = match field {
+                "comm" => Some(&mut filter.comms),
+                "exe" => Some(&mut filter.exes),
+                _ => None,
+            };
+            if let Some(patterns) = patterns {
+                Pattern::parse(values)?;
+                patterns.push(values.to_string());
+                continue;
+            }
+
+            for value in values.split(',') {
+                match field {
+                    "pid" => filter.pids.push(value.parse().map_err(|_| invalid())?),
+                    "tid" => filter.tids.push(value.parse().map_err(|_| invalid())?),
+                    "uid" => filter.uids.push(value.parse().map_err(|_| invalid())?),
+                    "user" => filter.uids.push(user_id(value)?),
+                    _ => return Err(invalid()),
+                }
+            }
+        }
+
+        Ok(filter)
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut filters = Vec::new();
+        if self.traced {
+            filters.push("traced".to_string());
+        }
+
+        for (kind, values) in [("pid", &self.pids), ("tid", &self.tids), ("uid", &self.uids)] {
+            if !values.is_empty() {
+                let values: Vec<String> = values.iter().map(u32::to_string).collect();
+                filters.push(format!("{}={}", kind, values.join(",")));
+            }
+        }
+
+        for (kind, patterns) in [("comm", &self.comms), ("exe", &self.exes)] {
+            filters.extend(patterns.iter().map(|pattern| format!("{}={}", kind, pattern)));
+        }
+
+        if filters.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", filters.join(" "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_display() {
+        let filter: Filter = "pid=1234,1300 tid=1240 uid=0".parse().unwrap();
+        assert_eq!(filter, Filter { pids: vec![1234, 1300], tids: vec![1240], uids: vec![0], ..Default::default() });
+        assert_eq!(filter.to_string(), "pid=1234,1300 tid=1240 uid=0");
+        assert_eq!(filter.to_string().parse::<Filter>().unwrap(), filter);
+
+        assert_eq!(Filter::default().to_string(), "none");
+        assert_eq!("none".parse::<Filter>().unwrap(), Filter::default());
+        assert_eq!("".parse::<Filter>().unwrap(), Filter::default());
+        assert_eq!("user=root".parse::<Filter>().unwrap(), Filter { uids: vec![0], ..Default::default() });
+
+        let error = "pid=nginx".parse::<Filter>().unwrap_err();
+        assert_eq!(error.to_string(), "Invalid filter `pid=nginx`, expected pid=PID, tid=TID, uid=UID, user=NAME, comm=PATTERN, exe=PATTERN, traced or none");
+        assert!("gid=0".parse::<Filter>().is_err());
+
+        let filter: Filter = "traced uid=1000".parse().unwrap();
+        assert_eq!(filter, Filter { uids: vec![1000], traced: true, ..Default::default() });
+        assert_eq!(filter.to_string(), "traced uid=1000");
+
+        // Patterns are kept whole, commas included.
+        let filter: Filter = "comm=nginx* comm=re:^php-fpm{1,2} exe=/usr/sbin/*".parse().unwrap();
+        assert_eq!((filter.comms.len(), filter.exes.len()), (2, 1));
+        assert_eq!(filter.to_string(), "comm=nginx* comm=re:^php-fpm{1,2} exe=/usr/sbin/*");
+        assert_eq!("comm=re:(nginx".parse::<Filter>().unwrap_err().to_string(), "Invalid regex `(nginx`");
+        assert!("pid=".parse::<Filter>().is_err());
+    }
+
+    #[test]
+    fn config_values() {
+        let filter = Filter { pids: vec![7, 8], uids: vec![1000], traced: true, ..Default::default() };
+        let config = filter.config(3).unwrap();
+        assert_eq!((config.nr_pids, config.nr_tids, config.nr_uids, config.generation), (2, 0, 1, 3));
+        assert_eq!(config.flags, FILTER_TRACED);
+
+        let filter = Filter { exes: vec!["/usr/sbin/nginx".to_string()], ..Default::default() };
+        assert_eq!(filter.config(1).unwrap().flags, FILTER_ALLOWED);
+        assert_eq!((&config.pids[..2], config.uids[0]), (&[7, 8][..], 1000));
+        // Thread 9 of process 7, the tgid is in the upper 32 bits.
+        assert!(config.matches((7 << 32) | 9, 1000));
+        assert!(!config.matches((7 << 32) | 9, 0));
+        assert!(!config.matches((9 << 32) | 7, 1000));
+
+        let filter = Filter { pids: (0..=MAX_FILTER_VALUES as u32).collect(), ..Default::default() };
+        let error = filter.config(1).unwrap_err();
+        assert_eq!(error.to_string(), "Too many pid filters, at most 16 are supported");
+    }
+}
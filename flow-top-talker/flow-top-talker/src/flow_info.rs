@@ -1,10 +1,13 @@
 use std::collections::BinaryHeap;
 use std::collections::binary_heap::Iter;
 
-use flow_top_talker_common::common_types::FlowKey;
+use flow_top_talker_common::common_types::{
+    FlowHistograms, FlowKey, FlowStats, Owner, ERRNO_NAMES, ERRNO_SLOTS, LATENCY_BUCKETS, SIZE_BUCKETS,
+};
+use serde::{Deserialize, Serialize};
 
 /// Aggregated flow info.
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct FlowInfo {
     pub throughput: u64,
     pub src_addr: u32,
@@ -12,8 +15,176 @@ pub struct FlowInfo {
     pub src_port: u16,
     pub dest_port: u16,
     pub protocol: u8,
+    pub owner: Owner,
+    pub health: Option<TcpHealth>,
+    pub buffers: Option<SockBuffers>,
+    pub errors: [u32; ERRNO_SLOTS],
+    pub resets_sent: u32,
+    pub resets_received: u32,
+    pub sizes: [u32; SIZE_BUCKETS],
+    pub latencies: [u32; LATENCY_BUCKETS],
+    pub per_cpu: Vec<CpuStats>,
 }
 
+/// Traffic of the flow processed on a CPU in the interval.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CpuStats {
+    pub bytes: u64,
+    pub messages: u64,
+}
+
+/// Latest TCP health metrics of the flow along with the retransmits in the interval.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TcpHealth {
+    pub rtt_us: u32,
+    pub snd_cwnd: u32,
+    pub total_retrans: u32,
+    pub retransmits: u32,
+}
+
+/// Latest occupancy and size of the socket buffers of the flow in bytes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SockBuffers {
+    pub wmem_queued: u32,
+    pub sndbuf: u32,
+    pub rmem_alloc: u32,
+    pub rcvbuf: u32,
+}
+
+impl SockBuffers {
+    /// Occupancy of the send buffer in percent.
+    pub fn send_queue_pct(&self) -> u32 {
+        percent(self.wmem_queued, self.sndbuf)
+    }
+
+    /// Occupancy of the receive buffer in percent.
+    pub fn recv_queue_pct(&self) -> u32 {
+        percent(self.rmem_alloc, self.rcvbuf)
+    }
+}
+
+fn percent(used: u32, size: u32) -> u32 {
+    (used as u64 * 100).checked_div(size as u64).unwrap_or(0) as u32
+}
+
+impl FlowInfo {
+    pub fn new(flow_key: &FlowKey, stats: &FlowStats) -> Self {
+        // Kernel stores smoothed RTT shifted left by 3.
+        let health = (stats.sampled_ns != 0).then_some(TcpHealth {
+            rtt_us: stats.srtt_us >> 3,
+            snd_cwnd: stats.snd_cwnd,
+            total_retrans: stats.total_retrans,
+            retransmits: stats.retransmits,
+        });
+
+        let buffers = (stats.buffers_sampled_ns != 0).then_some(SockBuffers {
+            wmem_queued: stats.wmem_queued,
+            sndbuf: stats.sndbuf,
+            rmem_alloc: stats.rmem_alloc,
+            rcvbuf: stats.rcvbuf,
+        });
+
+        Self {
+            src_addr: flow_key.src_addr,
+            dest_addr: flow_key.dest_addr,
+            src_port: flow_key.src_port,
+            dest_port: flow_key.dest_port,
+            protocol: flow_key.protocol,
+            throughput: stats.bytes,
+            owner: stats.owner,
+            health,
+            buffers,
+            errors: stats.errors,
+            resets_sent: stats.resets_sent,
+            resets_received: stats.resets_received,
+            sizes: [0; SIZE_BUCKETS],
+            latencies: [0; LATENCY_BUCKETS],
+            per_cpu: Vec::new(),
+        }
+    }
+
+    /// Flow with the breakdown of the traffic by the CPU which processed it, the stats are
+    /// indexed by the CPU.
+    pub fn from_per_cpu(flow_key: &FlowKey, per_cpu_stats: &[FlowStats]) -> Self {
+        let mut total_stats = FlowStats::default();
+        for stats in per_cpu_stats {
+            total_stats.merge(stats);
+        }
+
+        let mut flow_info = Self::new(flow_key, &total_stats);
+        flow_info.per_cpu = per_cpu_stats
+            .iter()
+            .map(|stats| CpuStats {
+                bytes: stats.bytes,
+                messages: stats.messages as u64,
+            })
+            .collect();
+        flow_info
+    }
+
+    /// Flow with the histograms of its messages, which are tracked apart from the stats.
+    pub fn with_histograms(mut self, histograms: &FlowHistograms) -> Self {
+        self.sizes = histograms.sizes;
+        self.latencies = histograms.latencies;
+        self
+    }
+
+    /// CPU which processed most of the bytes of the flow along with its share in percent.
+    pub fn busiest_cpu(&self) -> Option<(usize, u64)> {
+        let (cpu, stats) = self.per_cpu.iter().enumerate().max_by_key(|(_, stats)| stats.bytes)?;
+        let share = (stats.bytes * 100).checked_div(self.throughput)?;
+        Some((cpu, share))
+    }
+
+    pub fn flow_key(&self) -> FlowKey {
+        FlowKey::new(self.src_addr, self.dest_addr, self.src_port, self.dest_port, self.protocol)
+    }
+
+    /// Number of messages sent or received in the interval.
+    pub fn messages(&self) -> u64 {
+        self.sizes.iter().map(|count| *count as u64).sum()
+    }
+
+    /// Approximate percentile (0 to 100) of the time spent in the calls in microseconds, as
+    /// the upper bound of the log2 bucket it falls in. None if there were no calls.
+    pub fn latency_percentile(&self, percentile: u64) -> Option<u64> {
+        let calls: u64 = self.latencies.iter().map(|count| *count as u64).sum();
+        if calls == 0 {
+            return None;
+        }
+
+        // Rank of the call at the percentile, rounding up.
+        let rank = (calls * percentile).div_ceil(100).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.latencies.iter().enumerate() {
+            seen += *count as u64;
+            if seen >= rank {
+                return Some(1 << (bucket + 1));
+            }
+        }
+
+        None
+    }
+
+    /// Summary of the errors and resets of the flow in the interval, None if there were none.
+    pub fn error_summary(&self) -> Option<String> {
+        let mut summary: Vec<String> = self.errors.iter()
+            .zip(ERRNO_NAMES)
+            .filter(|(count, _)| **count > 0)
+            .map(|(count, name)| format!("{}:{}", name, count))
+            .collect();
+
+        if self.resets_sent > 0 {
+            summary.push(format!("RST-out:{}", self.resets_sent));
+        }
+
+        if self.resets_received > 0 {
+            summary.push(format!("RST-in:{}", self.resets_received));
+        }
+
+        (!summary.is_empty()).then(|| summary.join(" "))
+    }
+}
 
 impl PartialOrd for FlowInfo {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
@@ -27,10 +198,40 @@ impl Ord for FlowInfo {
     }
 }
 
-/// Limited size max heap based on throughput.
+/// Bytes and number of the flows in a direction.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Totals {
+    pub bytes: u64,
+    pub flows: u64,
+}
+
+impl Totals {
+    pub fn add(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        self.flows += 1;
+    }
+
+    /// Totals of the flows which are not part of the flows provided.
+    pub fn other(&self, flows: &[FlowInfo]) -> Totals {
+        let shown: u64 = flows.iter().map(|f| f.throughput).sum();
+        Totals {
+            bytes: self.bytes.saturating_sub(shown),
+            flows: self.flows.saturating_sub(flows.len() as u64),
+        }
+    }
+
+    /// Share of the bytes in percent of the totals.
+    pub fn share(&self, bytes: u64) -> u64 {
+        (bytes * 100).checked_div(self.bytes).unwrap_or(0)
+    }
+}
+
+/// Limited size max heap based on throughput, which also keeps the totals of all the flows
+/// added including the ones which didn't make it to the top N.
 pub struct LimitedMaxHeap {
     top_n: usize,
     heap: BinaryHeap<FlowInfo>,
+    totals: Totals,
 }
 
 impl LimitedMaxHeap {
@@ -38,37 +239,31 @@ impl LimitedMaxHeap {
         Self {
             top_n,
             heap: BinaryHeap::new(),
+            totals: Totals::default(),
         }
     }
 
+    #[cfg(test)]
     pub fn add(
         &mut self,
         flow_key: &FlowKey,
         total_throughput: u64,
     ) {
+        let stats = FlowStats { bytes: total_throughput, ..Default::default() };
+        self.add_flow(FlowInfo::new(flow_key, &stats));
+    }
+
+    pub fn add_flow(&mut self, flow_info: FlowInfo) {
+        self.totals.add(flow_info.throughput);
         if self.heap.len() == self.top_n {
             let lowest_flow = self.heap.peek().unwrap();
-            if lowest_flow.throughput < total_throughput {
+            if lowest_flow.throughput < flow_info.throughput {
                 self.heap.pop();
-                self.heap.push(FlowInfo {
-                    src_addr: flow_key.src_addr,
-                    dest_addr: flow_key.dest_addr,
-                    src_port: flow_key.src_port,
-                    dest_port: flow_key.dest_port,
-                    protocol: flow_key.protocol,
-                    throughput: total_throughput,
-                });
+                self.heap.push(flow_info);
             }
         } else {
-            self.heap.push(FlowInfo {
-                src_addr: flow_key.src_addr,
-                dest_addr: flow_key.dest_addr,
-                src_port: flow_key.src_port,
-                dest_port: flow_key.dest_port,
-                protocol: flow_key.protocol,
-                throughput: total_throughput,
-            });                                
-        }   
+            self.heap.push(flow_info);
+        }
     }
 
     #[cfg(test)]
@@ -81,8 +276,13 @@ impl LimitedMaxHeap {
         self.heap.pop()
     }
 
+    pub fn totals(&self) -> Totals {
+        self.totals
+    }
+
     pub fn clear(&mut self) {
-        self.heap.clear()
+        self.heap.clear();
+        self.totals = Totals::default();
     }
 
     pub fn liter(&self) -> Liter {
@@ -106,9 +306,9 @@ impl<'a> Iterator for Liter<'a> {
 mod tests {
     use std::collections::BinaryHeap;
 
-    use flow_top_talker_common::common_types::FlowKey;
+    use flow_top_talker_common::common_types::{errno_slot, FlowHistograms, FlowKey, FlowStats};
 
-    use crate::{flow_info:: LimitedMaxHeap, FlowInfo};
+    use crate::flow_info::{FlowInfo, LimitedMaxHeap, Totals};
 
     #[test]
     fn add_data_to_heap_2() {
@@ -162,4 +362,95 @@ mod tests {
         assert_eq!(heap.pop().unwrap().throughput, 196);
         assert_eq!(heap.pop().unwrap().throughput, 198);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn totals_and_other() {
+        let mut heap = LimitedMaxHeap::new(2);
+        for (port, bytes) in [(1, 100), (2, 300), (3, 50), (4, 50)] {
+            heap.add(&FlowKey::new(0, 0, port, 0, 0), bytes);
+        }
+
+        let totals = heap.totals();
+        assert_eq!((totals.bytes, totals.flows), (500, 4));
+        assert_eq!(totals.share(400), 80);
+
+        let top: Vec<FlowInfo> = heap.liter().cloned().collect();
+        assert_eq!(totals.other(&top), Totals { bytes: 100, flows: 2 });
+
+        heap.clear();
+        assert_eq!(heap.totals(), Totals::default());
+        assert_eq!(heap.totals().share(0), 0);
+    }
+
+    #[test]
+    fn error_summary() {
+        let key = FlowKey::new(0, 0, 0, 0, 0);
+        assert_eq!(FlowInfo::new(&key, &FlowStats::default()).error_summary(), None);
+
+        let mut stats = FlowStats::default();
+        stats.errors[errno_slot(32)] = 2;
+        let mut other = FlowStats { resets_received: 1, ..Default::default() };
+        other.errors[errno_slot(32)] = 1;
+        other.errors[errno_slot(4)] = 1;
+        stats.merge(&other);
+
+        let summary = FlowInfo::new(&key, &stats).error_summary();
+        assert_eq!(summary.as_deref(), Some("EPIPE:3 OTHER:1 RST-in:1"));
+    }
+
+    #[test]
+    fn latency_percentile() {
+        let key = FlowKey::new(0, 0, 0, 0, 0);
+        assert_eq!(FlowInfo::new(&key, &FlowStats::default()).latency_percentile(50), None);
+
+        // 98 calls under 2us, one between 1ms and 2ms and one above 8s.
+        let mut histograms = FlowHistograms::default();
+        histograms.latencies[0] = 98;
+        histograms.latencies[10] = 1;
+        histograms.latencies[23] = 1;
+
+        let flow = FlowInfo::new(&key, &FlowStats::default()).with_histograms(&histograms);
+        assert_eq!(flow.latency_percentile(50), Some(2));
+        assert_eq!(flow.latency_percentile(99), Some(2048));
+        assert_eq!(flow.latency_percentile(100), Some(1 << 24));
+    }
+
+    #[test]
+    fn sock_buffers_percent() {
+        let key = FlowKey::new(0, 0, 0, 0, 0);
+        assert_eq!(FlowInfo::new(&key, &FlowStats::default()).buffers, None);
+
+        let stats = FlowStats {
+            buffers_sampled_ns: 1,
+            wmem_queued: 3000,
+            sndbuf: 4000,
+            rmem_alloc: 10,
+            ..Default::default()
+        };
+        let buffers = FlowInfo::new(&key, &stats).buffers.unwrap();
+        assert_eq!(buffers.send_queue_pct(), 75);
+        assert_eq!(buffers.recv_queue_pct(), 0);
+    }
+
+    #[test]
+    fn per_cpu_breakdown() {
+        let key = FlowKey::new(0, 0, 0, 0, 0);
+        let mut per_cpu = [FlowStats::default(); 4];
+        per_cpu[1].bytes = 100;
+        per_cpu[1].messages = 1;
+        per_cpu[3].bytes = 300;
+        per_cpu[3].messages = 2;
+        let mut histograms = FlowHistograms::default();
+        histograms.sizes[6] = 1;
+        histograms.sizes[8] = 2;
+
+        let flow = FlowInfo::from_per_cpu(&key, &per_cpu).with_histograms(&histograms);
+        assert_eq!(flow.throughput, 400);
+        assert_eq!(flow.messages(), 3);
+        assert_eq!(flow.per_cpu.len(), 4);
+        assert_eq!((flow.per_cpu[3].bytes, flow.per_cpu[3].messages), (300, 2));
+        assert_eq!(flow.busiest_cpu(), Some((3, 75)));
+
+        assert_eq!(FlowInfo::new(&key, &FlowStats::default()).busiest_cpu(), None);
+    }
+}
@@ -1,19 +1,48 @@
+mod btf;
+mod churn;
 mod cli;
+mod client;
+mod collector;
+mod command;
+mod config;
+mod connection;
+mod daemon;
+mod filter;
 mod flow_info;
 mod ebpf_handler;
+mod map_sizing;
+mod matcher;
+mod pinning;
+mod protocol;
+mod report;
+mod resolver;
+mod roles;
+mod rotation;
+mod theme;
+mod ui;
+mod units;
 
 use std::{
-    net::{IpAddr, Ipv4Addr}, num::NonZeroUsize, time::Duration
+    collections::HashSet, time::{Duration, Instant}
 };
 
-use flow_info::LimitedMaxHeap;
 use flow_top_talker_common::common_types::TCP;
-use lru::LruCache;
 
-use crate::cli::Cli;
-use crate::flow_info::FlowInfo;
-use crate::ebpf_handler::EbpfHandler;
-use clap::Parser;
+use crate::churn::{ChurnKey, ChurnStats};
+use crate::cli::{Cli, Mode};
+use crate::client::Client;
+use crate::collector::Collector;
+use crate::command::Command;
+use crate::connection::{ConnectionInfo, ServiceInfo, SortMetric};
+use crate::filter::Filter;
+use crate::pinning::Pins;
+use crate::protocol::Message;
+use crate::report::{CommandSummary, Report};
+use crate::resolver::{Labels, Resolver};
+use crate::roles::{orient, socket_owners, Listeners};
+use crate::theme::Theme;
+use crate::units::format_rate;
+use crate::ui::{Section, Snapshot, UiState, View};
 
 use crossterm::{
     event,
@@ -22,20 +51,60 @@ use crossterm::{
 };
 use ratatui::{prelude::*, widgets::*};
 
+/// Time the client waits for keys before checking for new snapshots.
+const CLIENT_POLL: Duration = Duration::from_millis(100);
+
+/// Where the snapshots displayed come from.
+enum Source {
+    Local(Box<Collector>),
+    Remote(Client),
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let cli = Cli::parse();
+    let mut cli = Cli::load()?;
 
     env_logger::init();
 
-    let mut ebpf_handler = EbpfHandler::init()?;
-    ebpf_handler.add_config(&cli)?;
-    ebpf_handler.attach()?;
+    if let (true, Some(path)) = (cli.unpin, &cli.pin_path) {
+        Pins::new(path).remove()?;
+        println!("Removed the maps and programs pinned in {}", path.display());
+        return Ok(());
+    }
+
+    // The traced processes and the filter would otherwise outlive the command.
+    if !cli.command.is_empty() && (cli.mode != Mode::Local || cli.pin_path.is_some()) {
+        anyhow::bail!("A command can only be traced in local mode without a pin path");
+    }
+
+    let mut command = None;
+    let mut source = match cli.mode {
+        Mode::Daemon => return daemon::run(&cli),
+        Mode::Report => return report::run(&cli),
+        Mode::Local => {
+            let mut collector = Collector::new(&cli)?;
+            println!("{}", collector.ebpf_handler().map_sizing());
+            if !cli.command.is_empty() {
+                command = Some(Command::spawn(&cli.command, |pid| collector.ebpf_handler().trace(pid))?);
+            }
+            config::reload_on_sighup()?;
+            Source::Local(Box::new(collector))
+        }
+        Mode::Client => {
+            let mut client = Client::connect(&cli.socket)?;
+            // Snapshots are collected with the settings of the daemon.
+            cli.interval_ms = client.interval_ms;
+            cli.top_n = client.top_n;
+
+            let filter = Filter::from_cli(&cli)?;
+            if filter != Filter::default() && filter != client.filter {
+                client.set_filter(filter)?;
+            }
+            Source::Remote(client)
+        }
+    };
 
-    let mut ingress_heap = LimitedMaxHeap::new(cli.top_n);
-    let mut egress_heap = LimitedMaxHeap::new(cli.top_n);
-    let mut dns_cache: LruCache<IpAddr, String> =
-        LruCache::new(NonZeroUsize::new(10_000).unwrap());
+    let mut resolver = Resolver::new(cli.host_name, Labels::parse(&cli.label)?);
 
     enable_raw_mode()?;
     let mut stdout = std::io::stdout();
@@ -43,118 +112,579 @@ async fn main() -> anyhow::Result<()> {
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
     
-    let mut flag = 0u32;
+    let mut snapshot = Snapshot::default();
+    let theme = Theme::new(cli.theme, cli.warn_rate, cli.crit_rate);
+    let mut ui = UiState::new(cli.view, cli.sort, cli.by_service, theme);
+    // The filter applied by the collector, which is restricted to the command if one is traced.
+    ui.filter = match &mut source {
+        Source::Local(collector) => collector.ebpf_handler().filter().clone(),
+        Source::Remote(client) => client.filter.clone(),
+    };
+    let interval = Duration::from_millis(cli.interval_ms);
+    let mut next_rotation = Instant::now() + interval;
+    let mut collected = Instant::now();
+    let mut report = Report::default();
+    let mut exit_status = None;
     loop {
-        if event::poll(Duration::from_secs(1))? {
+        // Keys only redraw the view, data is rotated once per interval.
+        let timeout = match source {
+            Source::Local(_) => next_rotation.saturating_duration_since(Instant::now()),
+            Source::Remote(_) => CLIENT_POLL,
+        };
+        if event::poll(timeout)? {
             if let event::Event::Key(key) = event::read()? {
-                if key.code == event::KeyCode::Char('q') {
+                if key.code == event::KeyCode::Char('q') && ui.prompt.is_none() {
                     break;
                 }
+                if let Some(filter) = ui.handle_key(key.code, &snapshot) {
+                    set_filter(&mut source, &mut ui, filter);
+                }
             }
         }
 
-        ebpf_handler.rotate_data(&mut ingress_heap, flag, &mut egress_heap)?;
+        match &mut source {
+            Source::Local(collector) => {
+                if Instant::now() >= next_rotation {
+                    snapshot = collector.collect()?;
+                    next_rotation += interval;
+                    if command.is_some() {
+                        report.add(&snapshot, collected.elapsed());
+                    }
+                    collected = Instant::now();
+                }
+            }
+            Source::Remote(client) => {
+                while let Some(message) = client.try_receive()? {
+                    match message {
+                        Message::Snapshot(received) => snapshot = *received,
+                        Message::Filter { filter } => {
+                            ui.filter = filter;
+                            ui.status = None;
+                        }
+                        Message::Error { message } => ui.status = Some(message),
+                        Message::Hello { .. } => {}
+                    }
+                }
+            }
+        }
 
-        let ingress_top_flow_rows: Vec<Row> = generate_row(&ingress_heap, &mut dns_cache, &cli);
-        let egress_top_flow_rows: Vec<Row> = generate_row(&egress_heap, &mut dns_cache, &cli);
+        if let Some(command) = &command {
+            exit_status = command.try_wait()?;
+            if exit_status.is_some() {
+                break;
+            }
+        }
+
+        // The daemon reloads its own config, a client only views.
+        if matches!(source, Source::Local(_)) && config::reload_requested() {
+            match Cli::load().and_then(|reloaded| Filter::from_cli(&reloaded)) {
+                Ok(filter) => set_filter(&mut source, &mut ui, filter),
+                Err(e) => ui.status = Some(format!("Failed to reload the config: {}", e)),
+            }
+        }
+
+        if let Some((section, flow_key)) = ui.detail {
+            let flow = snapshot.flows(section).iter().find(|f| f.flow_key() == flow_key);
+            terminal.draw(|frame| {
+                let area = Layout::default()
+                    .margin(1)
+                    .constraints([Constraint::Percentage(100)])
+                    .split(frame.size());
+                ui::draw_detail(frame, section, &flow_key, flow, &ui.theme, &cli, area[0]);
+            })?;
+            continue;
+        }
+
+        let churn_rows: Vec<Row> = generate_churn_row(&snapshot.churn, &mut resolver, &cli, &ui.theme);
+
+        if ui.view == View::Combined {
+            let connections = if ui.by_service {
+                snapshot.connections.by_service(&snapshot.listeners).top(cli.top_n, ui.sort)
+            } else {
+                snapshot.connections.top(cli.top_n, ui.sort)
+            };
+            let connection_rows: Vec<Row> =
+                generate_connection_row(&connections, &snapshot.listeners, &mut resolver, &cli, &ui.theme);
+            terminal.draw(|frame| {
+                let terminal_section = Layout::default()
+                    .direction(Direction::Vertical)
+                    .margin(1)
+                    .constraints([
+                        Constraint::Length(1),
+                        Constraint::Percentage(70),
+                        Constraint::Percentage(30),
+                    ])
+                    .split(frame.size());
+
+                draw_totals(frame, &snapshot, &cli, &ui, terminal_section[0]);
+                draw_connection_section(frame, connection_rows, cli.top_n, &ui, terminal_section[1]);
+                draw_churn_section(frame, churn_rows, cli.top_n, &ui.theme, terminal_section[2]);
+            })?;
+            continue;
+        }
+
+        if ui.view == View::Services {
+            let mut services = snapshot.connections.services(&snapshot.listeners, ui.sort);
+            services.truncate(cli.top_n);
+            let inodes: HashSet<u64> = services.iter().map(|s| s.listener.inode).collect();
+            let mut owners = socket_owners(&inodes);
+            for service in services.iter_mut() {
+                service.process = owners.remove(&service.listener.inode);
+            }
+
+            let service_rows: Vec<Row> = generate_service_row(&services, &mut resolver, &cli, &ui.theme);
+            terminal.draw(|frame| {
+                let terminal_section = Layout::default()
+                    .direction(Direction::Vertical)
+                    .margin(1)
+                    .constraints([
+                        Constraint::Length(1),
+                        Constraint::Percentage(70),
+                        Constraint::Percentage(30),
+                    ])
+                    .split(frame.size());
+
+                draw_totals(frame, &snapshot, &cli, &ui, terminal_section[0]);
+                draw_service_section(frame, service_rows, cli.top_n, ui.sort, &ui.theme, terminal_section[1]);
+                draw_churn_section(frame, churn_rows, cli.top_n, &ui.theme, terminal_section[2]);
+            })?;
+            continue;
+        }
+
+        let ingress_top_flow_rows: Vec<Row> =
+            generate_row(&snapshot, Section::Ingress, &mut resolver, &cli, &ui.theme);
+        let egress_top_flow_rows: Vec<Row> =
+            generate_row(&snapshot, Section::Egress, &mut resolver, &cli, &ui.theme);
 
         terminal.draw(|frame| {
             let terminal_section = Layout::default()
                 .direction(Direction::Vertical)
                 .margin(1)
                 .constraints([
-                    Constraint::Percentage(50),
-                    Constraint::Percentage(50),
+                    Constraint::Length(1),
+                    Constraint::Percentage(35),
+                    Constraint::Percentage(35),
+                    Constraint::Percentage(30),
                 ])
                 .split(frame.size());
 
-            draw_section(frame, ingress_top_flow_rows, cli.top_n, "Ingress", terminal_section[0]);
-            draw_section(frame, egress_top_flow_rows, cli.top_n, "Egress", terminal_section[1]);
+            draw_totals(frame, &snapshot, &cli, &ui, terminal_section[0]);
+            for (section, rows, area) in [
+                (Section::Ingress, ingress_top_flow_rows, terminal_section[1]),
+                (Section::Egress, egress_top_flow_rows, terminal_section[2]),
+            ] {
+                let nr_flows = snapshot.flows(section).len();
+                draw_section(frame, rows, &cli, section, &ui, nr_flows, area);
+            }
+            draw_churn_section(frame, churn_rows, cli.top_n, &ui.theme, terminal_section[3]);
         })?;
-
-        ingress_heap.clear();
-        egress_heap.clear();
-        flag ^= 0x1;
     }
 
     disable_raw_mode()?;
     execute!(std::io::stdout(), LeaveAlternateScreen)?;
+
+    if let (Some(command), Source::Local(collector)) = (command, &mut source) {
+        let status = match exit_status {
+            Some(status) => status,
+            None => command.terminate()?,
+        };
+        let elapsed = command.elapsed();
+        // Flows of the last partial interval.
+        report.add(&collector.collect()?, collected.elapsed());
+
+        let summary = report.summary(elapsed, Some(CommandSummary::new(&command, status)), &mut resolver, &cli);
+        summary.write(&mut std::io::stdout(), cli.format, cli.bits)?;
+    }
+
     Ok(())
 }
 
-/// Draw each section for ingress and egress.
+/// Draw each section for ingress and egress, highlighting the selected flow.
 fn draw_section<'a>(
     frame: &mut Frame<'a>,
     rows: Vec<Row<'a>>,
-    top_n: usize,
-    header_str: &str,
+    cli: &Cli,
+    section: Section,
+    ui: &UiState,
+    nr_flows: usize,
     section_area: Rect,
 ) {
-    let header = Row::new(vec!["SrcIp:Port", "DestIp:Port", "Protocol", "Throughput(Bps)"])
-        .style(Style::default().add_modifier(Modifier::BOLD))
-        .set_style(Style::default().bg(Color::Blue));
+    let header = Row::new(vec![
+        "Client", "Server", "Protocol", "Role", "Process", "Throughput", "p50(us)", "p99(us)",
+        "SndQ%", "RcvQ%", "RTT(ms)", "Retrans/s", "Cwnd", "Errors",
+    ])
+        .style(ui.theme.header());
+
+    let mut title = format!("Top {} {} flows", cli.top_n, section.name());
+    if section == ui.focus {
+        title.push_str(" [Tab: switch, Up/Down: select, Enter: details, v: combined view]");
+    }
 
+    // Only the flows can be selected, not the row of the other flows.
+    let mut state = ui.table_state(section, nr_flows);
     let table = Table::new(rows)
         .header(header)
-        .block(Block::default().borders(Borders::ALL).title(format!("Top {} {} flows", top_n, header_str)))
+        .block(Block::default().borders(Borders::ALL).title(title))
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
         .widths(&[
-            Constraint::Percentage(30),
-            Constraint::Percentage(30),
-            Constraint::Percentage(20),
-            Constraint::Percentage(20),
+            Constraint::Percentage(12),
+            Constraint::Percentage(12),
+            Constraint::Percentage(5),
+            Constraint::Percentage(5),
+            Constraint::Percentage(9),
+            Constraint::Percentage(7),
+            Constraint::Percentage(5),
+            Constraint::Percentage(5),
+            Constraint::Percentage(5),
+            Constraint::Percentage(5),
+            Constraint::Percentage(5),
+            Constraint::Percentage(5),
+            Constraint::Percentage(5),
+            Constraint::Percentage(15),
         ]);
     
+    frame.render_stateful_widget(table, section_area, &mut state);
+}
+
+/// Draw the section with both directions of each connection.
+fn draw_connection_section<'a>(
+    frame: &mut Frame<'a>,
+    rows: Vec<Row<'a>>,
+    top_n: usize,
+    ui: &UiState,
+    section_area: Rect,
+) {
+    let header = Row::new(vec![
+        "Client", "Server", "Protocol", "Role", "Process", "Conns", "TX", "RX", "Total"
+    ])
+        .style(ui.theme.header());
+
+    let kind = if ui.by_service { "services" } else { "connections" };
+    let title = format!(
+        "Top {} {} by {} [v: services view, s: sort, a: aggregate by service]",
+        top_n, kind, ui.sort.name()
+    );
+    let table = Table::new(rows)
+        .header(header)
+        .block(Block::default().borders(Borders::ALL).title(title))
+        .widths(&[
+            Constraint::Percentage(18),
+            Constraint::Percentage(18),
+            Constraint::Percentage(7),
+            Constraint::Percentage(7),
+            Constraint::Percentage(14),
+            Constraint::Percentage(6),
+            Constraint::Percentage(10),
+            Constraint::Percentage(10),
+            Constraint::Percentage(10),
+        ]);
+
     frame.render_widget(table, section_area);
 }
 
+/// Generate rows for the connections.
+fn generate_connection_row<'a>(
+    connections: &[ConnectionInfo],
+    listeners: &Listeners,
+    resolver: &mut Resolver,
+    cli: &Cli,
+    theme: &Theme,
+) -> Vec<Row<'a>> {
+    connections.iter().map(|c| {
+        let mut cells: Vec<Cell<'_>> = Vec::new();
+        let role = listeners.role(&c.flow_key);
+        let (client, server) = orient(&c.flow_key, role);
+        cells.push(Cell::from(resolver.endpoint(client.0, client.1)));
+        cells.push(Cell::from(resolver.endpoint(server.0, server.1)));
 
-/// Generate rows based on the data on the heap and arguments provided by the user.
-fn generate_row<'a>(
-    heap: &LimitedMaxHeap,
-    dns_cache: &mut LruCache<IpAddr, String>,
-    cli: &Cli
+        if c.flow_key.protocol == TCP {
+            cells.push(Cell::from("TCP".to_string()));
+        } else {
+            cells.push(Cell::from("UDP".to_string()));
+        };
+
+        cells.push(Cell::from(role.name().to_string()));
+
+        if c.owner.pid != 0 {
+            cells.push(Cell::from(format!("{}/{}", c.owner.pid, c.owner.comm())));
+        } else {
+            cells.push(Cell::from("-".to_string()));
+        }
+
+        cells.push(Cell::from(format!("{}", c.connections)));
+
+        for value in [c.tx, c.rx, c.total()].map(|value| cli.per_second(value)) {
+            cells.push(Cell::from(format_rate(value, cli.bits)).style(theme.rate(value)));
+        }
+
+        Row::new(cells)
+    }).collect()
+}
+
+/// Draw the section with the traffic of the local listening services.
+fn draw_service_section<'a>(
+    frame: &mut Frame<'a>,
+    rows: Vec<Row<'a>>,
+    top_n: usize,
+    sort: SortMetric,
+    theme: &Theme,
+    section_area: Rect,
+) {
+    let header = Row::new(vec![
+        "Listen", "Protocol", "Process", "Conns", "TX", "RX", "Total"
+    ])
+        .style(theme.header());
+
+    let title = format!("Top {} listening services by {} [v: split view, s: sort]", top_n, sort.name());
+    let table = Table::new(rows)
+        .header(header)
+        .block(Block::default().borders(Borders::ALL).title(title))
+        .widths(&[
+            Constraint::Percentage(22),
+            Constraint::Percentage(8),
+            Constraint::Percentage(20),
+            Constraint::Percentage(8),
+            Constraint::Percentage(13),
+            Constraint::Percentage(13),
+            Constraint::Percentage(13),
+        ]);
+
+    frame.render_widget(table, section_area);
+}
+
+/// Generate rows for the listening services.
+fn generate_service_row<'a>(
+    services: &[ServiceInfo],
+    resolver: &mut Resolver,
+    cli: &Cli,
+    theme: &Theme,
 ) -> Vec<Row<'a>> {
+    services.iter().map(|s| {
+        let mut cells: Vec<Cell<'_>> = Vec::new();
+        // Listeners bound to any address are shown as `*`.
+        if s.listener.addr == 0 {
+            cells.push(Cell::from(format!("*:{}", s.listener.port)));
+        } else {
+            cells.push(Cell::from(resolver.endpoint(s.listener.addr, s.listener.port)));
+        }
 
-    let mut top_flow_info: Vec<&FlowInfo> = heap
-        .liter()
-        .collect();
+        if s.listener.protocol == TCP {
+            cells.push(Cell::from("TCP".to_string()));
+        } else {
+            cells.push(Cell::from("UDP".to_string()));
+        };
 
-    top_flow_info.sort_by(|f1, f2| f2.throughput.cmp(&f1.throughput));
+        match &s.process {
+            Some(process) => cells.push(Cell::from(format!("{}/{}", process.pid, process.comm))),
+            None => cells.push(Cell::from("-".to_string())),
+        }
 
-    let rows: Vec<Row> = top_flow_info.into_iter().map(|f| {
+        cells.push(Cell::from(format!("{}", s.connections)));
+
+        for value in [s.tx, s.rx, s.total()].map(|value| cli.per_second(value)) {
+            cells.push(Cell::from(format_rate(value, cli.bits)).style(theme.rate(value)));
+        }
+
+        Row::new(cells)
+    }).collect()
+}
+
+/// Draw the section for the connections opened and closed in the interval.
+fn draw_churn_section<'a>(
+    frame: &mut Frame<'a>,
+    rows: Vec<Row<'a>>,
+    top_n: usize,
+    theme: &Theme,
+    section_area: Rect,
+) {
+    let header = Row::new(vec![
+        "Process", "Remote", "New/s", "Closed/s", "Short-lived", "AvgLife(ms)", "Resets", "Failed"
+    ])
+        .style(theme.header());
+
+    let table = Table::new(rows)
+        .header(header)
+        .block(Block::default().borders(Borders::ALL).title(format!("Top {} connection churn", top_n)))
+        .widths(&[
+            Constraint::Percentage(20),
+            Constraint::Percentage(25),
+            Constraint::Percentage(8),
+            Constraint::Percentage(9),
+            Constraint::Percentage(10),
+            Constraint::Percentage(12),
+            Constraint::Percentage(8),
+            Constraint::Percentage(8),
+        ]);
+
+    frame.render_widget(table, section_area);
+}
+
+/// Generate rows for the endpoints with the most connections opened and closed.
+fn generate_churn_row<'a>(
+    churn: &[(ChurnKey, ChurnStats)],
+    resolver: &mut Resolver,
+    cli: &Cli,
+    theme: &Theme,
+) -> Vec<Row<'a>> {
+    churn.iter().map(|(key, stats)| {
         let mut cells: Vec<Cell<'_>> = Vec::new();
-        let src_addr = IpAddr::V4(Ipv4Addr::from(f.src_addr));
-        let dest_addr = IpAddr::V4(Ipv4Addr::from(f.dest_addr));
 
-        if !cli.host_name {
-            cells.push(Cell::from(format!("{:?}:{}", src_addr, f.src_port)));
-            cells.push(Cell::from(format!("{:?}:{}", dest_addr, f.dest_port)));
+        if key.owner.pid != 0 {
+            cells.push(Cell::from(format!("{}/{}", key.owner.pid, key.owner.comm())));
         } else {
-            let src_addr = dns_cache.get_or_insert(src_addr, ||
-                dns_lookup::lookup_addr(&src_addr).unwrap_or("Unknown".to_string())
-            );
-            cells.push(Cell::from(format!("{}:{}", src_addr, f.src_port)));
-            let dest_addr = dns_cache.get_or_insert(dest_addr, ||
-                dns_lookup::lookup_addr(&dest_addr).unwrap_or("Unknown".to_string())
-            );
-            cells.push(Cell::from(format!("{}:{}", dest_addr, f.dest_port)));
+            cells.push(Cell::from("-".to_string()));
         }
 
+        cells.push(Cell::from(resolver.endpoint(key.remote_addr, key.remote_port)));
+
+        cells.push(Cell::from(format!("{}", cli.per_second(stats.opened as u64))));
+        cells.push(Cell::from(format!("{}", cli.per_second(stats.closed as u64))));
+
+        let style = theme.warning(stats.short_lived > 0);
+        cells.push(Cell::from(format!("{}", stats.short_lived)).style(style));
+
+        match stats.avg_duration() {
+            Some(duration) => cells.push(Cell::from(format!("{:.1}", duration.as_secs_f64() * 1000.0))),
+            None => cells.push(Cell::from("-".to_string())),
+        }
+
+        let style = theme.alert(stats.resets + stats.failed > 0);
+        cells.push(Cell::from(format!("{}", stats.resets)).style(style));
+        cells.push(Cell::from(format!("{}", stats.failed)).style(style));
+
+        Row::new(cells)
+    }).collect()
+}
+
+/// Generate rows based on the flows and arguments provided by the user.
+fn generate_row<'a>(
+    snapshot: &Snapshot,
+    section: Section,
+    resolver: &mut Resolver,
+    cli: &Cli,
+    theme: &Theme,
+) -> Vec<Row<'a>> {
+    let top_flow_info = snapshot.flows(section);
+    let listeners = &snapshot.listeners;
+
+    let mut rows: Vec<Row> = top_flow_info.iter().map(|f| {
+        let mut cells: Vec<Cell<'_>> = Vec::new();
+        let flow_key = f.flow_key();
+        let role = listeners.role(&flow_key);
+        let (client, server) = orient(&flow_key, role);
+        cells.push(Cell::from(resolver.endpoint(client.0, client.1)));
+        cells.push(Cell::from(resolver.endpoint(server.0, server.1)));
+
         if f.protocol == TCP {
             cells.push(Cell::from("TCP".to_string()));
         } else {
             cells.push(Cell::from("UDP".to_string()));
         };
 
-        let color = match f.throughput {
-            val if val > 100_000 => Color::Red,
-            val if val > 10_000 => Color::Yellow,
-            _ => Color::default()
-        };
-        cells.push(Cell::from(format!("{}", f.throughput)).style(Style::default().fg(color)));
+        cells.push(Cell::from(role.name().to_string()));
 
-        Row::new(cells)
+        if f.owner.pid != 0 {
+            cells.push(Cell::from(format!("{}/{}", f.owner.pid, f.owner.comm())));
+        } else {
+            cells.push(Cell::from("-".to_string()));
+        }
+
+        let throughput = cli.per_second(f.throughput);
+        cells.push(Cell::from(format_rate(throughput, cli.bits)).style(theme.rate(throughput)));
+
+        // Time spent in sendmsg/recvmsg, high values mean the thread is blocked, e.g. by a
+        // full send buffer.
+        for percentile in [50, 99] {
+            match f.latency_percentile(percentile) {
+                Some(latency) => cells.push(Cell::from(format!("<{}", latency))),
+                None => cells.push(Cell::from("-".to_string())),
+            }
+        }
+
+        // Full send queue means the flow is limited by the peer or the send buffer size, while
+        // full receive queue means the process is not keeping up.
+        match f.buffers {
+            Some(buffers) => {
+                for pct in [buffers.send_queue_pct(), buffers.recv_queue_pct()] {
+                    cells.push(Cell::from(format!("{}", pct)).style(theme.alert(pct >= 90)));
+                }
+            }
+            None => cells.extend((0..2).map(|_| Cell::from("-".to_string()))),
+        }
+
+        // Health metrics are only sampled for TCP flows.
+        match f.health {
+            Some(health) => {
+                cells.push(Cell::from(format!("{:.2}", health.rtt_us as f64 / 1000.0)));
+                let style = theme.alert(health.retransmits > 0);
+                cells.push(Cell::from(format!("{}", cli.per_second(health.retransmits as u64))).style(style));
+                cells.push(Cell::from(format!("{}", health.snd_cwnd)));
+            }
+            None => cells.extend((0..3).map(|_| Cell::from("-".to_string()))),
+        }
+
+        // Highlight the flows with errors, so throughput drops can be correlated with them.
+        match f.error_summary() {
+            Some(summary) => {
+                cells.push(Cell::from(summary).style(theme.alert(true)));
+                Row::new(cells).style(Style::default().add_modifier(Modifier::BOLD))
+            }
+            None => {
+                cells.push(Cell::from("-".to_string()));
+                Row::new(cells)
+            }
+        }
     }).collect();
 
+    // Flows below the cut-off are summed into a single row.
+    let other = snapshot.totals(section).other(top_flow_info);
+    if other.flows > 0 {
+        let mut cells = vec![Cell::from(format!("other ({} flows)", other.flows))];
+        cells.extend((0..4).map(|_| Cell::from("")));
+        cells.push(Cell::from(format_rate(cli.per_second(other.bytes), cli.bits)));
+        rows.push(Row::new(cells).style(Style::default().add_modifier(Modifier::ITALIC)));
+    }
+
     rows
-}
\ No newline at end of file
+}
+
+/// Draw the totals of all the flows in the interval and the share of the top N, along with
+/// the active filter. The prompt replaces them while a filter is entered.
+fn draw_totals(frame: &mut Frame, snapshot: &Snapshot, cli: &Cli, ui: &UiState, area: Rect) {
+    if let Some(prompt) = &ui.prompt {
+        let text = Paragraph::new(format!("Filter (pid=PID tid=TID uid=UID user=NAME comm=PATTERN exe=PATTERN traced, or none): {}_", prompt))
+            .style(Style::default().add_modifier(Modifier::BOLD));
+        frame.render_widget(text, area);
+        return;
+    }
+
+    let mut totals: Vec<String> = [Section::Ingress, Section::Egress]
+        .into_iter()
+        .map(|section| {
+            let totals = snapshot.totals(section);
+            let top: u64 = snapshot.flows(section).iter().map(|f| f.throughput).sum();
+            format!(
+                "{}: {} in {} flows (top {}%)",
+                section.name(), format_rate(cli.per_second(totals.bytes), cli.bits), totals.flows, totals.share(top),
+            )
+        })
+        .collect();
+    totals.push(format!("Filter: {}", ui.filter));
+    totals.extend(snapshot.warning.clone());
+    totals.extend(ui.status.clone());
+
+    let text = Paragraph::new(totals.join("  |  ")).style(Style::default().add_modifier(Modifier::BOLD));
+    frame.render_widget(text, area);
+}
+
+/// Apply the filter. A client asks the daemon, which replies with the filter applied.
+fn set_filter(source: &mut Source, ui: &mut UiState, filter: Filter) {
+    let result = match source {
+        Source::Local(collector) => collector.ebpf_handler().set_filter(&filter).map(|applied| ui.filter = applied),
+        Source::Remote(client) => client.set_filter(filter),
+    };
+
+    if let Err(e) = result {
+        ui.status = Some(format!("Failed to change the filter: {}", e));
+    }
+}
@@ -0,0 +1,272 @@
+use std::fmt;
+
+use anyhow::anyhow;
+use flow_top_talker_common::common_types::{
+    ConnStart, FlowHistograms, FlowKey, FlowStats, HISTOGRAM_MAP_NAMES, TRACKER_MAP_NAMES,
+};
+
+/// Size of `struct htab_elem` in the kernel on 64 bit architectures.
+/// https://github.com/torvalds/linux/blob/1a1d569a75f3ab2923cb62daf356d102e4df2b86/kernel/bpf/hashtab.c#L110
+const HTAB_ELEM_SIZE: u64 = 48;
+
+/// Size of each bucket (`struct bucket`) in the kernel hash table.
+const HTAB_BUCKET_SIZE: u64 = 16;
+
+/// Type of the value stored in the tracker maps.
+type TrackerValue = FlowStats;
+
+/// Locked memory limit commonly given to services loading eBPF programs (`LimitMEMLOCK=64M`).
+/// The limit is lifted when possible and newer kernels account the maps to the cgroup instead,
+/// but a footprint above it is pointed out as the maps would fail to load where it applies.
+pub const TYPICAL_MEMLOCK_LIMIT: u64 = 64 * 1024 * 1024;
+
+/// Resolved size of the flow tracker maps along with the locked memory they would take.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MapSizing {
+    pub max_flows: u32,
+    pub nr_cpus: usize,
+    pub footprint: u64,
+    pub shrunk_from: Option<u32>,
+}
+
+impl MapSizing {
+    /// Resolve the number of flows each tracker map can hold.
+    ///
+    /// If a budget (in bytes) is provided and the requested size doesn't fit in it, either
+    /// refuse to continue or shrink the maps to the largest size which fits.
+    pub fn resolve(
+        requested: u32,
+        nr_cpus: usize,
+        budget: Option<u64>,
+        auto_shrink: bool,
+    ) -> anyhow::Result<MapSizing> {
+        if requested == 0 {
+            return Err(anyhow!("Max flows should be greater than 0"));
+        }
+
+        let footprint = total_footprint(requested, nr_cpus);
+        let budget = match budget {
+            Some(budget) if footprint > budget => budget,
+            _ => {
+                return Ok(MapSizing { max_flows: requested, nr_cpus, footprint, shrunk_from: None });
+            }
+        };
+
+        if !auto_shrink {
+            return Err(anyhow!(
+                "Tracking {} flows needs {} of locked memory on {} CPUs which exceeds the budget of {}",
+                requested, format_bytes(footprint), nr_cpus, format_bytes(budget)
+            ));
+        }
+
+        // Footprint grows with the number of entries, so find the largest size which
+        // fits in the budget.
+        let (mut low, mut high) = (0u32, requested);
+        while low < high {
+            let mid = high - (high - low) / 2;
+            if total_footprint(mid, nr_cpus) <= budget {
+                low = mid;
+            } else {
+                high = mid - 1;
+            }
+        }
+
+        if low == 0 {
+            return Err(anyhow!(
+                "Budget of {} is too small to track a single flow on {} CPUs",
+                format_bytes(budget), nr_cpus
+            ));
+        }
+
+        Ok(MapSizing {
+            max_flows: low,
+            nr_cpus,
+            footprint: total_footprint(low, nr_cpus),
+            shrunk_from: Some(requested),
+        })
+    }
+
+    /// Whether the footprint exceeds the memlock limit of a typical host, see
+    /// `TYPICAL_MEMLOCK_LIMIT`.
+    pub fn exceeds_typical_memlock(&self) -> bool {
+        self.footprint > TYPICAL_MEMLOCK_LIMIT
+    }
+}
+
+impl fmt::Display for MapSizing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Tracking up to {} flows per map using {} of locked memory across {} CPUs",
+            self.max_flows, format_bytes(self.footprint), self.nr_cpus
+        )?;
+
+        if let Some(requested) = self.shrunk_from {
+            write!(f, " (shrunk from {} flows to fit the memory budget)", requested)?;
+        }
+
+        if self.exceeds_typical_memlock() {
+            write!(
+                f,
+                "\nWarning: this exceeds the typical memlock limit of {}, lower --max-flows or set --memory-budget",
+                format_bytes(TYPICAL_MEMLOCK_LIMIT)
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Approximate locked memory used by a single `PerCpuHashMap` tracker.
+///
+/// Preallocated per cpu hash maps allocate an element (header, key and a pointer to the per
+/// cpu value) for every entry, a per cpu value on every possible CPU and a bucket per slot.
+/// https://github.com/torvalds/linux/blob/1a1d569a75f3ab2923cb62daf356d102e4df2b86/kernel/bpf/hashtab.c#L531
+pub fn tracker_footprint(max_entries: u32, nr_cpus: usize) -> u64 {
+    let max_entries = max_entries as u64;
+    let key_size = round_up(size_of::<FlowKey>() as u64);
+    let value_size = round_up(size_of::<TrackerValue>() as u64);
+
+    let elems = max_entries * (HTAB_ELEM_SIZE + key_size + size_of::<usize>() as u64);
+    let values = max_entries * value_size * nr_cpus as u64;
+    let buckets = max_entries.next_power_of_two() * HTAB_BUCKET_SIZE;
+
+    elems + values + buckets
+}
+
+/// Approximate locked memory used by a single histogram map, which is a (not per cpu) hash
+/// map keyed by the flow.
+pub fn histograms_footprint(max_entries: u32) -> u64 {
+    hash_footprint(max_entries, size_of::<FlowKey>(), size_of::<FlowHistograms>())
+}
+
+/// Approximate locked memory used by the connections in progress, which is a LRU hash map
+/// as well.
+pub fn conn_starts_footprint(max_entries: u32) -> u64 {
+    lru_footprint(max_entries, size_of::<ConnStart>())
+}
+
+/// Approximate locked memory used by the entry time of the calls in progress, which is a LRU
+/// hash map keyed by the thread.
+pub fn active_calls_footprint(max_entries: u32) -> u64 {
+    hash_footprint(max_entries, size_of::<u64>(), size_of::<u64>())
+}
+
+/// Locked memory used by all the tracker and histogram maps, the connections and the calls in
+/// progress, which are sized by the max flows. The socket cache grows with the sockets instead.
+pub fn total_footprint(max_entries: u32, nr_cpus: usize) -> u64 {
+    tracker_footprint(max_entries, nr_cpus) * TRACKER_MAP_NAMES.len() as u64
+        + histograms_footprint(max_entries) * HISTOGRAM_MAP_NAMES.len() as u64
+        + conn_starts_footprint(max_entries)
+        + active_calls_footprint(max_entries)
+}
+
+/// Approximate locked memory used by a LRU hash map keyed by the address of the socket.
+fn lru_footprint(max_entries: u32, value_size: usize) -> u64 {
+    hash_footprint(max_entries, size_of::<u64>(), value_size)
+}
+
+/// Approximate locked memory used by a (not per cpu) hash map, LRU or not.
+fn hash_footprint(max_entries: u32, key_size: usize, value_size: usize) -> u64 {
+    let max_entries = max_entries as u64;
+    let key_size = round_up(key_size as u64);
+    let value_size = round_up(value_size as u64);
+
+    let elems = max_entries * (HTAB_ELEM_SIZE + key_size + value_size);
+    let buckets = max_entries.next_power_of_two() * HTAB_BUCKET_SIZE;
+
+    elems + buckets
+}
+
+/// Format bytes in human readable form.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[unit])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Budget in bytes of a budget given in MiB.
+pub fn budget_bytes(mib: u64) -> anyhow::Result<u64> {
+    mib.checked_mul(1024 * 1024)
+        .ok_or_else(|| anyhow!("Memory budget of {} MiB is too large", mib))
+}
+
+fn round_up(size: u64) -> u64 {
+    size.div_ceil(8) * 8
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::map_sizing::{budget_bytes, format_bytes, total_footprint, MapSizing};
+
+    #[test]
+    fn resolve_without_budget() {
+        let sizing = MapSizing::resolve(10240, 4, None, false).unwrap();
+        assert_eq!(sizing.max_flows, 10240);
+        assert_eq!(sizing.footprint, total_footprint(10240, 4));
+        assert_eq!(sizing.shrunk_from, None);
+    }
+
+    #[test]
+    fn resolve_within_budget() {
+        let budget = total_footprint(10240, 8);
+        let sizing = MapSizing::resolve(10240, 8, Some(budget), false).unwrap();
+        assert_eq!(sizing.max_flows, 10240);
+    }
+
+    #[test]
+    fn resolve_exceeding_budget() {
+        let budget = total_footprint(10240, 96) - 1;
+        assert!(MapSizing::resolve(10240, 96, Some(budget), false).is_err());
+
+        let sizing = MapSizing::resolve(10240, 96, Some(budget), true).unwrap();
+        assert!(sizing.max_flows < 10240);
+        assert!(sizing.footprint <= budget);
+        assert!(total_footprint(sizing.max_flows + 1, 96) > budget);
+        assert_eq!(sizing.shrunk_from, Some(10240));
+    }
+
+    #[test]
+    fn resolve_tiny_budget() {
+        assert!(MapSizing::resolve(10240, 96, Some(1), true).is_err());
+        assert!(MapSizing::resolve(0, 96, None, true).is_err());
+    }
+
+    #[test]
+    fn typical_memlock_warning() {
+        let sizing = MapSizing::resolve(10240, 4, None, false).unwrap();
+        assert!(!sizing.exceeds_typical_memlock());
+        assert!(!sizing.to_string().contains('\n'));
+
+        // Every CPU holds a copy of the per cpu values.
+        let sizing = MapSizing::resolve(10240, 96, None, false).unwrap();
+        assert!(sizing.to_string().ends_with(
+            "\nWarning: this exceeds the typical memlock limit of 64.0 MiB, lower --max-flows or set --memory-budget"
+        ));
+    }
+
+    #[test]
+    fn budget_in_bytes() {
+        assert_eq!(budget_bytes(64).unwrap(), 64 << 20);
+        assert!(budget_bytes(u64::MAX).is_err());
+        assert!(budget_bytes(1 << 44).is_err());
+    }
+
+    #[test]
+    fn format_bytes_units() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(10 * 1024 * 1024), "10.0 MiB");
+    }
+}
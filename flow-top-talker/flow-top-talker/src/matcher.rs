@@ -0,0 +1,137 @@
+use std::{collections::HashSet, fs, path::Path};
+
+use anyhow::Context;
+use regex::Regex;
+
+/// Prefix of the patterns which are regular expressions rather than globs.
+const REGEX_PREFIX: &str = "re:";
+
+/// Pattern of a process name or executable path, a glob (`nginx*`) matching the whole value,
+/// or a regular expression prefixed with `re:` (`re:^nginx`) matching anywhere in it.
+#[derive(Clone, Debug)]
+pub enum Pattern {
+    Glob(glob::Pattern),
+    Regex(Regex),
+}
+
+impl Pattern {
+    pub fn parse(pattern: &str) -> anyhow::Result<Pattern> {
+        match pattern.strip_prefix(REGEX_PREFIX) {
+            Some(regex) => Regex::new(regex)
+                .map(Pattern::Regex)
+                .with_context(|| format!("Invalid regex `{}`", regex)),
+            None => glob::Pattern::new(pattern)
+                .map(Pattern::Glob)
+                .with_context(|| format!("Invalid glob `{}`", pattern)),
+        }
+    }
+
+    pub fn matches(&self, value: &str) -> bool {
+        match self {
+            Pattern::Glob(glob) => glob.matches(value),
+            Pattern::Regex(regex) => regex.is_match(value),
+        }
+    }
+}
+
+/// Processes to track by their name (`comm`) and executable path.
+///
+/// As with the other filters, a process is matched when it matches any of the patterns of a
+/// kind and all the kinds given.
+#[derive(Clone, Debug)]
+pub struct ProcessMatcher {
+    comms: Vec<Pattern>,
+    exes: Vec<Pattern>,
+}
+
+impl ProcessMatcher {
+    /// Matcher of the patterns, None if there are no patterns to match.
+    pub fn new(comms: &[String], exes: &[String]) -> anyhow::Result<Option<ProcessMatcher>> {
+        if comms.is_empty() && exes.is_empty() {
+            return Ok(None);
+        }
+
+        let parse = |patterns: &[String]| patterns.iter().map(|pattern| Pattern::parse(pattern)).collect::<anyhow::Result<_>>();
+        Ok(Some(ProcessMatcher { comms: parse(comms)?, exes: parse(exes)? }))
+    }
+
+    /// Whether the process with the name and executable (None for kernel threads, or if it
+    /// can't be read) matches.
+    pub fn matches(&self, comm: &str, exe: Option<&Path>) -> bool {
+        let comm_matches = self.comms.is_empty() || self.comms.iter().any(|pattern| pattern.matches(comm));
+        let exe_matches = self.exes.is_empty()
+            || exe
+                .and_then(|exe| exe.to_str())
+                .is_some_and(|exe| self.exes.iter().any(|pattern| pattern.matches(exe)));
+
+        comm_matches && exe_matches
+    }
+
+    /// Scan the running processes from procfs for the ones matching.
+    ///
+    /// Processes which exit during the scan are skipped, the executable is only read when
+    /// there are patterns for it.
+    pub fn scan(&self) -> HashSet<u32> {
+        let mut pids = HashSet::new();
+        let Ok(entries) = fs::read_dir("/proc") else {
+            return pids;
+        };
+
+        for entry in entries.flatten() {
+            let Some(pid) = entry.file_name().to_str().and_then(|pid| pid.parse::<u32>().ok()) else {
+                continue;
+            };
+            let Ok(comm) = fs::read_to_string(entry.path().join("comm")) else {
+                continue;
+            };
+            let exe = if self.exes.is_empty() {
+                None
+            } else {
+                fs::read_link(entry.path().join("exe")).ok()
+            };
+
+            if self.matches(comm.trim_end(), exe.as_deref()) {
+                pids.insert(pid);
+            }
+        }
+
+        pids
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::matcher::{Pattern, ProcessMatcher};
+
+    fn patterns(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(|pattern| pattern.to_string()).collect()
+    }
+
+    #[test]
+    fn match_comm_and_exe() {
+        assert!(Pattern::parse("nginx*").unwrap().matches("nginx: worker"));
+        assert!(!Pattern::parse("nginx").unwrap().matches("nginx: worker"));
+        assert!(Pattern::parse("re:worker").unwrap().matches("nginx: worker"));
+        assert!(!Pattern::parse("re:^worker").unwrap().matches("nginx: worker"));
+
+        let error = Pattern::parse("re:(nginx").unwrap_err();
+        assert_eq!(error.to_string(), "Invalid regex `(nginx`");
+        assert!(Pattern::parse("[nginx").is_err());
+
+        assert!(ProcessMatcher::new(&[], &[]).unwrap().is_none());
+
+        let nginx = Some(Path::new("/usr/sbin/nginx"));
+        let matcher = ProcessMatcher::new(&patterns(&["nginx", "re:^php-fpm"]), &[]).unwrap().unwrap();
+        assert!(matcher.matches("nginx", nginx));
+        assert!(matcher.matches("php-fpm7.4", None));
+        assert!(!matcher.matches("postgres", None));
+
+        // Both the name and the executable have to match, kernel threads have no executable.
+        let matcher = ProcessMatcher::new(&patterns(&["nginx"]), &patterns(&["/usr/*/nginx"])).unwrap().unwrap();
+        assert!(matcher.matches("nginx", nginx));
+        assert!(!matcher.matches("nginx", Some(Path::new("/opt/nginx/nginx"))));
+        assert!(!matcher.matches("nginx", None));
+    }
+}
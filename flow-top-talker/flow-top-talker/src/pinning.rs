@@ -0,0 +1,205 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+use aya::maps::{Array, Map, MapData, MapType};
+use flow_top_talker_common::common_types::{
+    ConfigKey, FilterConfig, FlowHistograms, FlowKey, FlowStats, TaskOffsets, TcpSockOffsets, ALLOWED_MAP_NAME,
+    CONFIG_MAP_NAME, CONN_EVENTS_MAP_NAME, EGRESS_HISTOGRAMS_0_MAP_NAME, EGRESS_HISTOGRAMS_1_MAP_NAME,
+    EGRESS_TRACKER_0_MAP_NAME, EGRESS_TRACKER_1_MAP_NAME, FLAG_MAP_NAME, INFLIGHT_MAP_NAME,
+    INGRESS_HISTOGRAMS_0_MAP_NAME, INGRESS_HISTOGRAMS_1_MAP_NAME, INGRESS_TRACKER_0_MAP_NAME,
+    INGRESS_TRACKER_1_MAP_NAME, MAPS_LAYOUT_VERSION,
+    TASK_OFFSETS_MAP_NAME, TCP_SOCK_OFFSETS_MAP_NAME, TRACED_MAP_NAME, VERSION_MAP_NAME,
+};
+
+/// Directory of the pinned maps under the pin path.
+const MAPS_DIR: &str = "maps";
+
+/// Directory of the pinned links, which keep the programs attached, under the pin path.
+const LINKS_DIR: &str = "links";
+
+/// Size of the key and value of a map used by the user space program.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Layout {
+    name: &'static str,
+    key_size: u32,
+    value_size: u32,
+}
+
+impl Layout {
+    fn new<K, V>(name: &'static str) -> Layout {
+        Layout { name, key_size: size_of::<K>() as u32, value_size: size_of::<V>() as u32 }
+    }
+}
+
+/// Layout of the maps used by the user space program, as built from common_types. The version
+/// map comes first so that a change of version is reported before the changes it explains.
+fn layouts() -> Vec<Layout> {
+    vec![
+        Layout::new::<u32, u32>(VERSION_MAP_NAME),
+        Layout::new::<FlowKey, FlowStats>(INGRESS_TRACKER_0_MAP_NAME),
+        Layout::new::<FlowKey, FlowStats>(INGRESS_TRACKER_1_MAP_NAME),
+        Layout::new::<FlowKey, FlowStats>(EGRESS_TRACKER_0_MAP_NAME),
+        Layout::new::<FlowKey, FlowStats>(EGRESS_TRACKER_1_MAP_NAME),
+        Layout::new::<FlowKey, FlowHistograms>(INGRESS_HISTOGRAMS_0_MAP_NAME),
+        Layout::new::<FlowKey, FlowHistograms>(INGRESS_HISTOGRAMS_1_MAP_NAME),
+        Layout::new::<FlowKey, FlowHistograms>(EGRESS_HISTOGRAMS_0_MAP_NAME),
+        Layout::new::<FlowKey, FlowHistograms>(EGRESS_HISTOGRAMS_1_MAP_NAME),
+        Layout::new::<u32, u32>(FLAG_MAP_NAME),
+        Layout::new::<u32, u64>(INFLIGHT_MAP_NAME),
+        Layout::new::<ConfigKey, FilterConfig>(CONFIG_MAP_NAME),
+        Layout::new::<u32, TcpSockOffsets>(TCP_SOCK_OFFSETS_MAP_NAME),
+        Layout::new::<u32, TaskOffsets>(TASK_OFFSETS_MAP_NAME),
+        Layout::new::<u32, u32>(TRACED_MAP_NAME),
+        Layout::new::<u32, u32>(ALLOWED_MAP_NAME),
+        // Ring buffers have neither keys nor values, the layout of the events is only
+        // covered by the version.
+        Layout::new::<(), ()>(CONN_EVENTS_MAP_NAME),
+    ]
+}
+
+/// Names of the maps used by the user space program.
+pub fn map_names() -> impl Iterator<Item = &'static str> {
+    layouts().into_iter().map(|layout| layout.name)
+}
+
+/// Check the layout of a pinned map against the one expected by this build.
+fn check_layout(expected: &Layout, found: &Layout) -> anyhow::Result<()> {
+    if expected != found {
+        return Err(anyhow!(
+            "Pinned map {} has keys of {} bytes and values of {} bytes, expected {} and {}",
+            expected.name, found.key_size, found.value_size, expected.key_size, expected.value_size
+        ));
+    }
+
+    Ok(())
+}
+
+/// Maps and programs pinned under a directory in bpffs.
+///
+/// Maps are pinned under `maps/` and named after the map, while the links which keep the
+/// programs attached are pinned under `links/` and named after the program.
+pub struct Pins {
+    root: PathBuf,
+}
+
+impl Pins {
+    pub fn new(root: &Path) -> Pins {
+        Pins { root: root.to_path_buf() }
+    }
+
+    pub fn map(&self, name: &str) -> PathBuf {
+        self.root.join(MAPS_DIR).join(name)
+    }
+
+    pub fn link(&self, name: &str) -> PathBuf {
+        self.root.join(LINKS_DIR).join(name)
+    }
+
+    /// Whether the maps and programs were pinned. The version map is pinned last, once the
+    /// programs are attached, so its pin means all the others were pinned as well.
+    pub fn exist(&self) -> bool {
+        self.map(VERSION_MAP_NAME).exists()
+    }
+
+    /// Create the directories of the pins, the pin path must be on a bpffs mount.
+    pub fn create_dirs(&self) -> anyhow::Result<()> {
+        for dir in [MAPS_DIR, LINKS_DIR] {
+            let path = self.root.join(dir);
+            fs::create_dir_all(&path)
+                .with_context(|| format!("Failed to create {}, is it on a bpffs mount?", path.display()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Open the pinned maps used by the user space program, once checked that they were
+    /// pinned with the same layout as the one of this build.
+    pub fn open_maps(&self) -> anyhow::Result<HashMap<&'static str, Map>> {
+        let mut maps = HashMap::new();
+        for expected in layouts() {
+            let path = self.map(expected.name);
+            let data = MapData::from_pin(&path)
+                .with_context(|| format!("Failed to open pinned map {}", path.display()))?;
+            let info = data.info()?;
+            let found = Layout { name: expected.name, key_size: info.key_size(), value_size: info.value_size() };
+
+            let map = match info.map_type()? {
+                MapType::Array => Map::Array(data),
+                MapType::PerCpuArray => Map::PerCpuArray(data),
+                MapType::Hash => Map::HashMap(data),
+                MapType::PerCpuHash => Map::PerCpuHashMap(data),
+                MapType::RingBuf => Map::RingBuf(data),
+                map_type => return Err(anyhow!("Pinned map {} has unexpected type {:?}", expected.name, map_type)),
+            };
+
+            check_layout(&expected, &found)?;
+            if expected.name == VERSION_MAP_NAME {
+                let version: Array<&MapData, u32> = Array::try_from(&map)?;
+                let version = version.get(&0, 0)?;
+                if version != MAPS_LAYOUT_VERSION {
+                    return Err(anyhow!(
+                        "Maps pinned in {} have layout version {} while this build uses {}, remove them with --unpin",
+                        self.root.display(), version, MAPS_LAYOUT_VERSION
+                    ));
+                }
+            }
+
+            maps.insert(expected.name, map);
+        }
+
+        Ok(maps)
+    }
+
+    /// Number of flows each of the pinned tracker maps can hold.
+    pub fn max_flows(&self) -> anyhow::Result<u32> {
+        let data = MapData::from_pin(self.map(INGRESS_TRACKER_0_MAP_NAME))?;
+        Ok(data.info()?.max_entries())
+    }
+
+    /// Remove the pins. The programs are detached and the maps freed once no one else holds
+    /// them.
+    pub fn remove(&self) -> anyhow::Result<()> {
+        if !self.root.exists() {
+            return Err(anyhow!("Nothing is pinned in {}", self.root.display()));
+        }
+
+        fs::remove_dir_all(&self.root)
+            .with_context(|| format!("Failed to remove {}", self.root.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pin_paths_and_layouts() {
+        let pins = Pins::new(Path::new("/sys/fs/bpf/flow-top-talker"));
+        assert_eq!(pins.map("FLAG"), Path::new("/sys/fs/bpf/flow-top-talker/maps/FLAG"));
+        assert_eq!(pins.link("tcp_sendmsg_fentry"), Path::new("/sys/fs/bpf/flow-top-talker/links/tcp_sendmsg_fentry"));
+
+        let layouts = layouts();
+        assert_eq!(layouts[0].name, VERSION_MAP_NAME);
+
+        let tracker = layouts[1];
+        assert_eq!((tracker.key_size, tracker.value_size), (16, size_of::<FlowStats>() as u32));
+        assert!(check_layout(&tracker, &tracker).is_ok());
+
+        // The kernel refuses hash maps with keys of 0 bytes.
+        let config = layouts.iter().find(|layout| layout.name == CONFIG_MAP_NAME).unwrap();
+        assert_eq!((config.key_size, config.value_size), (4, size_of::<FilterConfig>() as u32));
+
+        // Keys padded to the cache line by an older build can't be re-used.
+        let found = Layout { key_size: 128, ..tracker };
+        let error = check_layout(&tracker, &found).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("Pinned map INGRESS_TRACKER_0 has keys of 128 bytes and values of {} bytes, expected 16 and {}",
+                tracker.value_size, tracker.value_size)
+        );
+    }
+}
@@ -0,0 +1,86 @@
+use std::io::{BufRead, Write};
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{filter::Filter, ui::Snapshot};
+
+/// Messages sent by the daemon to its clients.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    /// First message after connecting, with the settings the snapshots are collected with.
+    Hello { interval_ms: u64, top_n: usize, filter: Filter },
+    /// Flows of the last interval, sent to every client once per interval.
+    Snapshot(Box<Snapshot>),
+    /// Filter applied after a request of a client or a reload of the daemon, sent to every
+    /// client.
+    Filter { filter: Filter },
+    /// Request of the client failed.
+    Error { message: String },
+}
+
+/// Requests sent by the clients to the daemon.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Request {
+    SetFilter { filter: Filter },
+}
+
+/// Encode the message as a single line of JSON.
+pub fn encode<T: Serialize>(message: &T) -> anyhow::Result<Vec<u8>> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+/// Send the message as a single line of JSON.
+pub fn send<T: Serialize>(writer: &mut impl Write, message: &T) -> anyhow::Result<()> {
+    writer.write_all(&encode(message)?)?;
+    Ok(())
+}
+
+/// Receive the next message, None if the other side closed the connection.
+pub fn receive<T: DeserializeOwned>(reader: &mut impl BufRead) -> anyhow::Result<Option<T>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+
+    let message = serde_json::from_str(&line).context("Malformed message")?;
+    Ok(Some(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufReader;
+
+    use flow_top_talker_common::common_types::{FlowKey, FlowStats};
+
+    use crate::{
+        flow_info::FlowInfo,
+        protocol::{receive, send, Message},
+        ui::Snapshot,
+    };
+
+    #[test]
+    fn round_trip() {
+        let flow = FlowInfo::from_per_cpu(
+            &FlowKey::new(1, 2, 40000, 443, 0),
+            &[FlowStats { bytes: 100, ..Default::default() }; 2],
+        );
+        let snapshot = Snapshot { egress: vec![flow.clone()], ..Default::default() };
+
+        let mut buffer = Vec::new();
+        send(&mut buffer, &Message::Snapshot(Box::new(snapshot))).unwrap();
+        send(&mut buffer, &Message::Error { message: "denied".to_string() }).unwrap();
+
+        let mut reader = BufReader::new(buffer.as_slice());
+        match receive(&mut reader).unwrap() {
+            Some(Message::Snapshot(snapshot)) => assert_eq!(snapshot.egress, [flow]),
+            _ => panic!("expected a snapshot"),
+        }
+        assert!(matches!(receive(&mut reader).unwrap(), Some(Message::Error { .. })));
+        assert!(receive::<Message>(&mut reader).unwrap().is_none());
+    }
+}
@@ -0,0 +1,450 @@
+use std::{
+    collections::HashMap,
+    io,
+    process::ExitStatus,
+    thread,
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use flow_top_talker_common::common_types::{FlowKey, Owner, TCP};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cli::Cli,
+    collector::Collector,
+    command::Command,
+    map_sizing::format_bytes,
+    resolver::{Labels, Resolver},
+    roles::{orient, Role},
+    ui::{Section, Snapshot},
+    units::format_rate,
+};
+
+/// Format of the report printed to stdout.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportFormat {
+    Table,
+    Json,
+    Markdown,
+}
+
+/// Traffic of a flow over a run.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FlowReport {
+    pub flow_key: FlowKey,
+    pub owner: Owner,
+    pub role: Role,
+    pub bytes: u64,
+    /// Highest rate of the flow over an interval in bytes per second.
+    pub peak: u64,
+}
+
+/// Flows seen over a run, accumulated from the connections of the snapshot of every interval,
+/// which hold all the flows drained rather than only the top N.
+#[derive(Default)]
+pub struct Report {
+    ingress: HashMap<FlowKey, FlowReport>,
+    egress: HashMap<FlowKey, FlowReport>,
+    ingress_bytes: u64,
+    egress_bytes: u64,
+    intervals: u64,
+}
+
+impl Report {
+    /// Add the flows of the snapshot of an interval which lasted for `elapsed`, which is
+    /// shorter than the interval for the last one of a run.
+    pub fn add(&mut self, snapshot: &Snapshot, elapsed: Duration) {
+        self.intervals += 1;
+        self.ingress_bytes += snapshot.totals(Section::Ingress).bytes;
+        self.egress_bytes += snapshot.totals(Section::Egress).bytes;
+
+        let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+        for connection in snapshot.connections.iter() {
+            for (section, bytes) in [(Section::Ingress, connection.rx), (Section::Egress, connection.tx)] {
+                if bytes == 0 {
+                    continue;
+                }
+
+                let flows = match section {
+                    Section::Ingress => &mut self.ingress,
+                    Section::Egress => &mut self.egress,
+                };
+                let flow_key = connection.flow_key;
+                let report = flows.entry(flow_key).or_insert(FlowReport {
+                    flow_key,
+                    owner: connection.owner,
+                    role: snapshot.listeners.role(&flow_key),
+                    bytes: 0,
+                    peak: 0,
+                });
+                report.owner = connection.owner;
+                report.bytes += bytes;
+                report.peak = report.peak.max((bytes as f64 / seconds) as u64);
+            }
+        }
+    }
+
+    /// Top N flows of the section by bytes.
+    pub fn top(&self, section: Section, n: usize) -> Vec<FlowReport> {
+        let flows = match section {
+            Section::Ingress => &self.ingress,
+            Section::Egress => &self.egress,
+        };
+
+        let mut top: Vec<FlowReport> = flows.values().copied().collect();
+        top.sort_by(|f1, f2| f2.bytes.cmp(&f1.bytes).then(f2.peak.cmp(&f1.peak)));
+        top.truncate(n);
+        top
+    }
+
+    /// Bytes of all the flows of the section.
+    pub fn bytes(&self, section: Section) -> u64 {
+        match section {
+            Section::Ingress => self.ingress_bytes,
+            Section::Egress => self.egress_bytes,
+        }
+    }
+
+    /// Summary of the top flows of each section, with their average rate over the run and
+    /// their peak rate over an interval.
+    pub fn summary(&self, elapsed: Duration, command: Option<CommandSummary>, resolver: &mut Resolver, cli: &Cli) -> Summary {
+        let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+        let average = |bytes: u64| (bytes as f64 / seconds) as u64;
+
+        let mut section_summary = |section: Section| {
+            let flows = self.top(section, cli.top_n)
+                .into_iter()
+                .map(|flow| {
+                    let (client, server) = orient(&flow.flow_key, flow.role);
+                    FlowSummary {
+                        client: resolver.endpoint(client.0, client.1),
+                        server: resolver.endpoint(server.0, server.1),
+                        protocol: if flow.flow_key.protocol == TCP { "TCP" } else { "UDP" },
+                        pid: flow.owner.pid,
+                        comm: flow.owner.comm().to_string(),
+                        bytes: flow.bytes,
+                        average_rate: average(flow.bytes),
+                        peak_rate: flow.peak,
+                    }
+                })
+                .collect();
+
+            let bytes = self.bytes(section);
+            SectionSummary { bytes, average_rate: average(bytes), flows }
+        };
+
+        Summary {
+            duration_secs: elapsed.as_secs_f64(),
+            intervals: self.intervals,
+            command,
+            ingress: section_summary(Section::Ingress),
+            egress: section_summary(Section::Egress),
+        }
+    }
+}
+
+/// Command traced during the run and how it exited.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct CommandSummary {
+    pub command: String,
+    /// Exit status as displayed, e.g. `exit status: 0` or `signal: 15 (SIGTERM)`.
+    pub status: String,
+    /// Exit code of the command, None if it was killed by a signal.
+    pub exit_code: Option<i32>,
+}
+
+impl CommandSummary {
+    pub fn new(command: &Command, status: ExitStatus) -> CommandSummary {
+        CommandSummary { command: command.to_string(), status: status.to_string(), exit_code: status.code() }
+    }
+}
+
+/// Flow of the report, rates are in bytes per second.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct FlowSummary {
+    pub client: String,
+    pub server: String,
+    pub protocol: &'static str,
+    /// Process which owns the socket, 0 if unknown.
+    pub pid: u32,
+    pub comm: String,
+    pub bytes: u64,
+    pub average_rate: u64,
+    pub peak_rate: u64,
+}
+
+impl FlowSummary {
+    fn process(&self) -> String {
+        match self.pid {
+            0 => "-".to_string(),
+            pid => format!("{}/{}", pid, self.comm),
+        }
+    }
+}
+
+/// Flows of a direction, the bytes and average rate cover all the flows and not only the top
+/// N listed.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct SectionSummary {
+    pub bytes: u64,
+    pub average_rate: u64,
+    pub flows: Vec<FlowSummary>,
+}
+
+/// Report of a run as printed, serialized as is to JSON.
+#[derive(Clone, Debug, Serialize)]
+pub struct Summary {
+    pub duration_secs: f64,
+    pub intervals: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<CommandSummary>,
+    pub ingress: SectionSummary,
+    pub egress: SectionSummary,
+}
+
+impl Summary {
+    pub fn write(&self, out: &mut impl io::Write, format: ReportFormat, bits: bool) -> anyhow::Result<()> {
+        match format {
+            ReportFormat::Json => {
+                serde_json::to_writer_pretty(&mut *out, self)?;
+                writeln!(out)?;
+            }
+            ReportFormat::Table | ReportFormat::Markdown => self.write_text(out, format, bits)?,
+        }
+
+        Ok(())
+    }
+
+    fn title(&self) -> String {
+        match &self.command {
+            Some(command) => {
+                format!("`{}` exited with {} after {:.1}s", command.command, command.status, self.duration_secs)
+            }
+            None => format!("Top flows over {:.1}s ({} intervals)", self.duration_secs, self.intervals),
+        }
+    }
+
+    fn write_text(&self, out: &mut impl io::Write, format: ReportFormat, bits: bool) -> io::Result<()> {
+        let markdown = format == ReportFormat::Markdown;
+        if markdown {
+            writeln!(out, "# {}\n", self.title())?;
+        } else {
+            writeln!(out, "{}\n", self.title())?;
+        }
+
+        for (section, summary) in [(Section::Ingress, &self.ingress), (Section::Egress, &self.egress)] {
+            let totals = format!(
+                "{} ({} on average)", format_bytes(summary.bytes), format_rate(summary.average_rate, bits),
+            );
+            let mut rows = vec![[
+                "Client", "Server", "Protocol", "Process", "Bytes", "Average", "Peak",
+            ].map(String::from)];
+            rows.extend(summary.flows.iter().map(|flow| [
+                flow.client.clone(),
+                flow.server.clone(),
+                flow.protocol.to_string(),
+                flow.process(),
+                format_bytes(flow.bytes),
+                format_rate(flow.average_rate, bits),
+                format_rate(flow.peak_rate, bits),
+            ]));
+
+            if markdown {
+                writeln!(out, "## {}\n\n{}\n", section.name(), totals)?;
+                write_markdown_table(out, &rows)?;
+            } else {
+                writeln!(out, "{}: {}", section.name(), totals)?;
+                write_table(out, &rows)?;
+            }
+            writeln!(out)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Collect for the duration or number of intervals of the CLI, then print the report and
+/// exit.
+pub fn run(cli: &Cli) -> anyhow::Result<()> {
+    let Some(limit) = cli.duration.map(Limit::Duration).or(cli.iterations.map(Limit::Intervals)) else {
+        anyhow::bail!("Report mode needs either --duration or --iterations");
+    };
+
+    let mut collector = Collector::new(cli)?;
+    // Standard output only holds the report.
+    eprintln!("{}", collector.ebpf_handler().map_sizing());
+    let mut resolver = Resolver::new(cli.host_name, Labels::parse(&cli.label)?);
+    let started = Instant::now();
+    let interval = Duration::from_millis(cli.interval_ms);
+    let mut next_rotation = started + interval;
+    let mut collected = started;
+    let mut report = Report::default();
+    loop {
+        // The last interval is cut short to end with the duration.
+        let deadline = match limit {
+            Limit::Duration(duration) => next_rotation.min(started + duration),
+            Limit::Intervals(_) => next_rotation,
+        };
+        thread::sleep(deadline.saturating_duration_since(Instant::now()));
+        let snapshot = collector.collect()?;
+        if let Some(warning) = &snapshot.warning {
+            eprintln!("{}", warning);
+        }
+        report.add(&snapshot, collected.elapsed());
+        collected = Instant::now();
+        next_rotation += interval;
+
+        let done = match limit {
+            Limit::Duration(duration) => started.elapsed() >= duration,
+            Limit::Intervals(intervals) => report.intervals >= intervals,
+        };
+        if done {
+            break;
+        }
+    }
+
+    let summary = report.summary(started.elapsed(), None, &mut resolver, cli);
+    summary.write(&mut io::stdout().lock(), cli.format, cli.bits)
+}
+
+/// When the report mode stops collecting.
+#[derive(Copy, Clone, Debug)]
+enum Limit {
+    Duration(Duration),
+    Intervals(u64),
+}
+
+/// Write the rows with the columns aligned, the first row being the header.
+fn write_table<const N: usize>(out: &mut impl io::Write, rows: &[[String; N]]) -> io::Result<()> {
+    let mut widths = [0; N];
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    for row in rows {
+        let cells: Vec<String> = row.iter().zip(widths).map(|(cell, width)| format!("{:<width$}", cell)).collect();
+        writeln!(out, "{}", cells.join("  ").trim_end())?;
+    }
+
+    Ok(())
+}
+
+/// Write the rows as a Markdown table, the first row being the header.
+fn write_markdown_table<const N: usize>(out: &mut impl io::Write, rows: &[[String; N]]) -> io::Result<()> {
+    for (index, row) in rows.iter().enumerate() {
+        let cells: Vec<String> = row.iter().map(|cell| cell.replace('|', "\\|")).collect();
+        writeln!(out, "| {} |", cells.join(" | "))?;
+        if index == 0 {
+            writeln!(out, "|{}", "---|".repeat(N))?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use clap::Parser;
+    use flow_top_talker_common::common_types::{FlowKey, FlowStats, TCP};
+
+    use crate::{
+        cli::Cli,
+        connection::ConnectionTable,
+        flow_info::{FlowInfo, Totals},
+        report::{Report, ReportFormat},
+        resolver::{Labels, Resolver},
+        ui::{Section, Snapshot},
+    };
+
+    /// Snapshot of the ingress flows, of which only the first one makes the top N.
+    fn snapshot(flows: &[(u16, u64)]) -> Snapshot {
+        let ingress: Vec<FlowInfo> = flows
+            .iter()
+            .map(|(port, bytes)| {
+                let flow_key = FlowKey::new(0x0a000001, 0x0a000002, 40000, *port, TCP);
+                FlowInfo::new(&flow_key, &FlowStats { bytes: *bytes, ..Default::default() })
+            })
+            .collect();
+        let mut connections = ConnectionTable::default();
+        for flow in &ingress {
+            connections.add(Section::Ingress, flow);
+        }
+        let total = flows.iter().map(|(_, bytes)| bytes).sum::<u64>() + 100;
+
+        Snapshot {
+            ingress: ingress.into_iter().take(1).collect(),
+            connections,
+            ingress_totals: Totals { bytes: total, flows: flows.len() as u64 + 1 },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn accumulate_flows() {
+        let second = Duration::from_secs(1);
+        let mut report = Report::default();
+        report.add(&snapshot(&[(80, 1000), (443, 300)]), second);
+        report.add(&snapshot(&[(80, 100), (443, 900)]), second);
+
+        // Flows count in the intervals they didn't make the top N in.
+        let top = report.top(Section::Ingress, 10);
+        let flows: Vec<(u16, u64, u64)> = top.iter().map(|f| (f.flow_key.dest_port, f.bytes, f.peak)).collect();
+        assert_eq!(flows, [(443, 1200, 900), (80, 1100, 1000)]);
+        assert_eq!(report.top(Section::Ingress, 1).len(), 1);
+        assert!(report.top(Section::Egress, 10).is_empty());
+
+        // Totals also cover the bytes missing from the connections.
+        assert_eq!(report.bytes(Section::Ingress), 2500);
+    }
+
+    #[test]
+    fn peak_of_short_interval() {
+        let mut report = Report::default();
+        report.add(&snapshot(&[(80, 1000)]), Duration::from_secs(1));
+        report.add(&snapshot(&[(80, 600)]), Duration::from_millis(500));
+
+        assert_eq!(report.top(Section::Ingress, 1)[0].peak, 1200);
+    }
+
+    #[test]
+    fn write_formats() {
+        let mut report = Report::default();
+        report.add(&snapshot(&[(80, 4096), (443, 1024)]), Duration::from_secs(1));
+        report.add(&snapshot(&[(80, 6144)]), Duration::from_secs(1));
+
+        let cli = Cli::parse_from(["flow-top-talker", "-n", "1"]);
+        let mut resolver = Resolver::new(false, Labels::default());
+        let summary = report.summary(Duration::from_secs(5), None, &mut resolver, &cli);
+        assert_eq!(summary.ingress.flows.len(), 1);
+        let flow = &summary.ingress.flows[0];
+        assert_eq!((flow.bytes, flow.average_rate, flow.peak_rate), (10240, 2048, 6144));
+
+        let write = |format| {
+            let mut out = Vec::new();
+            summary.write(&mut out, format, false).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let table = write(ReportFormat::Table);
+        assert!(table.starts_with("Top flows over 5.0s (2 intervals)\n\nIngress: 11.2 KiB (2.2 KiB/s on average)\n"));
+        assert!(table.contains("Client          Server       Protocol  Process  Bytes     Average    Peak\n"));
+        assert!(table.contains("10.0.0.1:40000  10.0.0.2:80  TCP       -        10.0 KiB  2.0 KiB/s  6.0 KiB/s\n"));
+
+        let markdown = write(ReportFormat::Markdown);
+        assert!(markdown.contains("## Ingress\n\n11.2 KiB (2.2 KiB/s on average)\n\n| Client | Server |"));
+        assert!(markdown.contains("|---|---|---|---|---|---|---|\n| 10.0.0.1:40000 | 10.0.0.2:80 | TCP | - |"));
+
+        let json: serde_json::Value = serde_json::from_str(&write(ReportFormat::Json)).unwrap();
+        assert_eq!(json["intervals"], 2);
+        assert_eq!(json["ingress"]["flows"][0]["server"], "10.0.0.2:80");
+        assert_eq!(json["ingress"]["flows"][0]["peak_rate"], 6144);
+        assert_eq!(json["egress"]["bytes"], 0);
+        assert!(json.get("command").is_none());
+    }
+}
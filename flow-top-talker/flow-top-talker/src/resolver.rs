@@ -0,0 +1,107 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr},
+    num::NonZeroUsize,
+};
+
+use anyhow::anyhow;
+use lru::LruCache;
+
+/// Names of the hosts and endpoints provided by the user, e.g. `10.0.0.5=db-primary` or
+/// `10.0.0.5:5432=postgres`.
+#[derive(Debug, Default)]
+pub struct Labels {
+    hosts: HashMap<u32, String>,
+    endpoints: HashMap<(u32, u16), String>,
+}
+
+impl Labels {
+    /// Parse the labels in the form `ADDR[:PORT]=NAME`, the later ones win.
+    pub fn parse(labels: &[String]) -> anyhow::Result<Labels> {
+        let mut parsed = Labels::default();
+        for label in labels {
+            let invalid = || anyhow!("Invalid label `{}`, expected ADDR[:PORT]=NAME", label);
+            let (endpoint, name) = label.split_once('=').ok_or_else(invalid)?;
+            if name.is_empty() {
+                return Err(invalid());
+            }
+
+            match endpoint.split_once(':') {
+                Some((addr, port)) => {
+                    let addr: Ipv4Addr = addr.parse().map_err(|_| invalid())?;
+                    let port: u16 = port.parse().map_err(|_| invalid())?;
+                    parsed.endpoints.insert((addr.into(), port), name.to_string());
+                }
+                None => {
+                    let addr: Ipv4Addr = endpoint.parse().map_err(|_| invalid())?;
+                    parsed.hosts.insert(addr.into(), name.to_string());
+                }
+            }
+        }
+
+        Ok(parsed)
+    }
+}
+
+/// Formats the endpoints, naming them by the labels or, if requested, by the host names.
+pub struct Resolver {
+    host_name: bool,
+    labels: Labels,
+    dns_cache: LruCache<IpAddr, String>,
+}
+
+impl Resolver {
+    pub fn new(host_name: bool, labels: Labels) -> Self {
+        Self {
+            host_name,
+            labels,
+            dns_cache: LruCache::new(NonZeroUsize::new(10_000).unwrap()),
+        }
+    }
+
+    /// Format the endpoint, port 0 stands for any port of the flows aggregated by service.
+    pub fn endpoint(&mut self, addr: u32, port: u16) -> String {
+        if let Some(name) = self.labels.endpoints.get(&(addr, port)) {
+            return name.clone();
+        }
+
+        let port = if port == 0 { "*".to_string() } else { port.to_string() };
+        if let Some(name) = self.labels.hosts.get(&addr) {
+            return format!("{}:{}", name, port);
+        }
+
+        let ip_addr = IpAddr::V4(Ipv4Addr::from(addr));
+        if !self.host_name {
+            return format!("{:?}:{}", ip_addr, port);
+        }
+
+        let host_name = self.dns_cache.get_or_insert(ip_addr, ||
+            dns_lookup::lookup_addr(&ip_addr).unwrap_or("Unknown".to_string())
+        );
+        format!("{}:{}", host_name, port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::resolver::{Labels, Resolver};
+
+    #[test]
+    fn label_endpoints() {
+        let labels: Vec<String> = ["10.0.0.5=db-primary", "10.0.0.5:5432=postgres"]
+            .iter()
+            .map(|label| label.to_string())
+            .collect();
+        let mut resolver = Resolver::new(false, Labels::parse(&labels).unwrap());
+
+        let db = 0x0a000005;
+        assert_eq!(resolver.endpoint(db, 5432), "postgres");
+        assert_eq!(resolver.endpoint(db, 22), "db-primary:22");
+        assert_eq!(resolver.endpoint(db, 0), "db-primary:*");
+        assert_eq!(resolver.endpoint(0x0a000006, 80), "10.0.0.6:80");
+
+        for label in ["10.0.0.5", "10.0.0.5=", "db=primary", "10.0.0.5:http=web"] {
+            assert!(Labels::parse(&[label.to_string()]).is_err(), "{}", label);
+        }
+    }
+}
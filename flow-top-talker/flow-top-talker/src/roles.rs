@@ -0,0 +1,256 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    ops::RangeInclusive,
+};
+
+use flow_top_talker_common::common_types::{FlowKey, TCP, UDP};
+use serde::{Deserialize, Serialize};
+
+/// State of a listening TCP socket in `/proc/net/tcp`.
+const TCP_LISTEN: u8 = 0x0a;
+/// State of an unconnected UDP socket in `/proc/net/udp`.
+const UDP_UNCONNECTED: u8 = 0x07;
+
+/// Default range of the ephemeral ports, used if it can't be read.
+const DEFAULT_EPHEMERAL_PORTS: RangeInclusive<u16> = 32768..=60999;
+
+/// Role of the local side of the flow.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+impl Role {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::Client => "client",
+            Role::Server => "server",
+        }
+    }
+}
+
+/// Local socket which accepts connections (TCP) or datagrams from any peer (UDP).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Listener {
+    pub protocol: u8,
+    pub addr: u32,
+    pub port: u16,
+    pub inode: u64,
+}
+
+/// Local listening sockets, used to find the role of the local side of the flows.
+///
+/// TCP listeners are the sockets in the listen state. UDP has no such state, so unconnected
+/// UDP sockets bound outside of the ephemeral port range are considered listeners.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(from = "Vec<Listener>", into = "Vec<Listener>")]
+pub struct Listeners {
+    listeners: HashMap<(u8, u16), Vec<Listener>>,
+}
+
+impl From<Vec<Listener>> for Listeners {
+    fn from(sockets: Vec<Listener>) -> Self {
+        let mut listeners = Listeners::default();
+        for listener in sockets {
+            listeners.listeners.entry((listener.protocol, listener.port)).or_default().push(listener);
+        }
+
+        listeners
+    }
+}
+
+impl From<Listeners> for Vec<Listener> {
+    fn from(listeners: Listeners) -> Self {
+        listeners.listeners.into_values().flatten().collect()
+    }
+}
+
+impl Listeners {
+    /// Scan the listening sockets of the network namespace from procfs.
+    pub fn scan() -> anyhow::Result<Listeners> {
+        let ephemeral_ports = fs::read_to_string("/proc/sys/net/ipv4/ip_local_port_range")
+            .ok()
+            .and_then(|range| parse_port_range(&range))
+            .unwrap_or(DEFAULT_EPHEMERAL_PORTS);
+
+        let tcp = fs::read_to_string("/proc/net/tcp")?;
+        let udp = fs::read_to_string("/proc/net/udp")?;
+
+        Ok(Self::parse(&tcp, &udp, ephemeral_ports))
+    }
+
+    pub(crate) fn parse(tcp: &str, udp: &str, ephemeral_ports: RangeInclusive<u16>) -> Listeners {
+        let sockets = parse_proc_net(tcp, TCP).filter(|(state, _)| *state == TCP_LISTEN)
+            .chain(parse_proc_net(udp, UDP).filter(|(state, listener)| {
+                *state == UDP_UNCONNECTED && !ephemeral_ports.contains(&listener.port)
+            }));
+
+        sockets.map(|(_, listener)| listener).collect::<Vec<Listener>>().into()
+    }
+
+    /// Listener of the local side of the flow, if it is a server.
+    pub fn listener(&self, flow_key: &FlowKey) -> Option<&Listener> {
+        self.listeners
+            .get(&(flow_key.protocol, flow_key.src_port))?
+            .iter()
+            .find(|listener| listener.addr == 0 || listener.addr == flow_key.src_addr)
+    }
+
+    /// Role of the local side of the flow, whose source is always the local side.
+    pub fn role(&self, flow_key: &FlowKey) -> Role {
+        match self.listener(flow_key) {
+            Some(_) => Role::Server,
+            None => Role::Client,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Listener> {
+        self.listeners.values().flatten()
+    }
+}
+
+/// Process which holds a socket open.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Process {
+    pub pid: u32,
+    pub comm: String,
+}
+
+/// Find the processes holding the sockets with the given inodes open by scanning the file
+/// descriptors of all processes, the first process found for each socket is kept.
+///
+/// Processes which exit during the scan or whose file descriptors can't be read are skipped.
+pub fn socket_owners(inodes: &HashSet<u64>) -> HashMap<u64, Process> {
+    let mut owners = HashMap::new();
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return owners;
+    };
+
+    for entry in entries.flatten() {
+        let Some(pid) = entry.file_name().to_str().and_then(|pid| pid.parse::<u32>().ok()) else {
+            continue;
+        };
+        let Ok(fds) = fs::read_dir(entry.path().join("fd")) else {
+            continue;
+        };
+
+        for fd in fds.flatten() {
+            let inode = fs::read_link(fd.path())
+                .ok()
+                .and_then(|link| socket_inode(link.to_str()?));
+            let Some(inode) = inode.filter(|inode| inodes.contains(inode)) else {
+                continue;
+            };
+
+            owners.entry(inode).or_insert_with(|| Process {
+                pid,
+                comm: fs::read_to_string(entry.path().join("comm"))
+                    .map(|comm| comm.trim_end().to_string())
+                    .unwrap_or_default(),
+            });
+        }
+
+        if owners.len() == inodes.len() {
+            break;
+        }
+    }
+
+    owners
+}
+
+/// Inode of the socket from the target of its file descriptor link, `socket:[<inode>]`.
+fn socket_inode(link: &str) -> Option<u64> {
+    link.strip_prefix("socket:[")?.strip_suffix(']')?.parse().ok()
+}
+
+/// Client and server endpoints of the flow based on the role of the local side.
+pub fn orient(flow_key: &FlowKey, role: Role) -> ((u32, u16), (u32, u16)) {
+    let local = (flow_key.src_addr, flow_key.src_port);
+    let remote = (flow_key.dest_addr, flow_key.dest_port);
+    match role {
+        Role::Client => (local, remote),
+        Role::Server => (remote, local),
+    }
+}
+
+/// Parse the sockets from `/proc/net/{tcp,udp}` along with their state.
+///
+/// Addresses are printed as the hex of the address in network byte order read as a native
+/// integer, while ports are printed in host byte order.
+fn parse_proc_net(content: &str, protocol: u8) -> impl Iterator<Item = (u8, Listener)> + '_ {
+    content.lines().skip(1).filter_map(move |line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (addr, port) = fields.get(1)?.split_once(':')?;
+        let state = u8::from_str_radix(fields.get(3)?, 16).ok()?;
+        let inode = fields.get(9)?.parse().ok()?;
+
+        let listener = Listener {
+            protocol,
+            addr: u32::from_be(u32::from_str_radix(addr, 16).ok()?),
+            port: u16::from_str_radix(port, 16).ok()?,
+            inode,
+        };
+
+        Some((state, listener))
+    })
+}
+
+fn parse_port_range(range: &str) -> Option<RangeInclusive<u16>> {
+    let mut ports = range.split_whitespace().map(|port| port.parse::<u16>());
+    match (ports.next()?, ports.next()?) {
+        (Ok(low), Ok(high)) if low <= high => Some(low..=high),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use flow_top_talker_common::common_types::{FlowKey, TCP, UDP};
+
+    use crate::roles::{orient, socket_inode, Listeners, Role};
+
+    const TCP_SOCKETS: &str = "\
+  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 0100007F:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 101 1 0
+   1: 00000000:01BB 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 102 1 0
+   2: 0100007F:01BB 0100007F:9C40 01 00000000:00000000 00:00000000 00000000     0        0 103 1 0
+";
+
+    const UDP_SOCKETS: &str = "\
+   sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode ref pointer drops
+    0: 00000000:0035 00000000:0000 07 00000000:00000000 00:00000000 00000000     0        0 201 2 0 0
+    1: 00000000:9C40 00000000:0000 07 00000000:00000000 00:00000000 00000000     0        0 202 2 0 0
+";
+
+    #[test]
+    fn detect_roles() {
+        let listeners = Listeners::parse(TCP_SOCKETS, UDP_SOCKETS, 32768..=60999);
+        assert_eq!(listeners.iter().count(), 3);
+
+        let localhost = 0x7f000001;
+        let accepted = FlowKey::new(0x0a000001, 0x0a000002, 443, 40000, TCP);
+        let connected = FlowKey::new(0x0a000001, 0x0a000002, 40000, 443, TCP);
+        assert_eq!(listeners.role(&accepted), Role::Server);
+        assert_eq!(listeners.role(&connected), Role::Client);
+
+        // Listener bound to localhost doesn't match other local addresses.
+        assert_eq!(listeners.role(&FlowKey::new(localhost, localhost, 8080, 40000, TCP)), Role::Server);
+        assert_eq!(listeners.role(&FlowKey::new(0x0a000001, localhost, 8080, 40000, TCP)), Role::Client);
+
+        // UDP sockets in the ephemeral range are clients.
+        assert_eq!(listeners.role(&FlowKey::new(0, 0x08080808, 53, 40000, UDP)), Role::Server);
+        assert_eq!(listeners.role(&FlowKey::new(0, 0x08080808, 40000, 53, UDP)), Role::Client);
+
+        assert_eq!(orient(&accepted, Role::Server), ((0x0a000002, 40000), (0x0a000001, 443)));
+        assert_eq!(orient(&connected, Role::Client), ((0x0a000001, 40000), (0x0a000002, 443)));
+    }
+
+    #[test]
+    fn parse_socket_inode() {
+        assert_eq!(socket_inode("socket:[12345]"), Some(12345));
+        assert_eq!(socket_inode("pipe:[12345]"), None);
+        assert_eq!(socket_inode("/dev/null"), None);
+    }
+}
@@ -0,0 +1,146 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Max time to wait for the writers to leave the previous buffer. Writers only hold the
+/// buffer while updating a single flow, so this should only be hit if something is wrong.
+pub const WRITERS_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// Poll interval while waiting for the writers.
+const WRITERS_POLL_INTERVAL: Duration = Duration::from_micros(50);
+
+/// Wait for the in-flight writers of the previous buffer to leave after the flag is flipped.
+///
+/// Returns false if the writers didn't leave within the timeout.
+pub fn wait_for_writers(
+    mut writers: impl FnMut() -> anyhow::Result<u64>,
+    timeout: Duration,
+) -> anyhow::Result<bool> {
+    let start = Instant::now();
+    loop {
+        if writers()? == 0 {
+            return Ok(true);
+        }
+
+        if start.elapsed() >= timeout {
+            return Ok(false);
+        }
+
+        thread::sleep(WRITERS_POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+            Arc, Mutex,
+        },
+        thread,
+        time::Duration,
+    };
+
+    use flow_top_talker_common::epoch::{self, EpochState};
+
+    use crate::rotation::wait_for_writers;
+
+    /// In memory double buffer which mimics the ebpf maps.
+    #[derive(Default)]
+    struct Buffers {
+        flag: AtomicU32,
+        writers: [AtomicU64; 2],
+        trackers: [Mutex<HashMap<u32, u64>>; 2],
+    }
+
+    impl EpochState for Buffers {
+        fn active(&self) -> Option<u32> {
+            Some(self.flag.load(Ordering::SeqCst))
+        }
+
+        fn add_writers(&self, buffer: u32, delta: u64) -> Option<()> {
+            self.writers[buffer as usize].fetch_add(delta, Ordering::SeqCst);
+            Some(())
+        }
+    }
+
+    impl Buffers {
+        /// Same steps as the ebpf program, lookup and update or insert.
+        fn write(&self, key: u32, size: u64) {
+            let buffer = epoch::enter(self).unwrap();
+            *self.trackers[buffer as usize].lock().unwrap().entry(key).or_default() += size;
+            epoch::exit(self, buffer);
+        }
+
+        /// Same steps as the user program, flip, wait and then get and remove key by key.
+        ///
+        /// Unlike ebpf programs, threads can be preempted while holding the buffer so wait
+        /// longer than the user program does.
+        fn rotate(&self, cur_flag: u32) -> u64 {
+            self.flag.store(cur_flag ^ 0x1, Ordering::SeqCst);
+            let left = wait_for_writers(
+                || Ok(self.writers[cur_flag as usize].load(Ordering::SeqCst)),
+                Duration::from_secs(5),
+            );
+            assert!(left.unwrap());
+
+            let tracker = &self.trackers[cur_flag as usize];
+            let keys: Vec<u32> = tracker.lock().unwrap().keys().copied().collect();
+            let mut total = 0;
+            for key in keys {
+                total += tracker.lock().unwrap().get(&key).copied().unwrap_or_default();
+                thread::yield_now();
+                tracker.lock().unwrap().remove(&key);
+            }
+
+            total
+        }
+    }
+
+    #[test]
+    fn rotation_is_lossless() {
+        let buffers = Arc::new(Buffers::default());
+        let done = Arc::new(AtomicBool::new(false));
+
+        let writers: Vec<_> = (0..4).map(|id| {
+            let buffers = buffers.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut written = 0u64;
+                let mut size = id as u64;
+                while !done.load(Ordering::Relaxed) {
+                    size = size % 1500 + 1;
+                    buffers.write((size % 8) as u32, size);
+                    written += size;
+                }
+
+                written
+            })
+        }).collect();
+
+        let mut drained = 0;
+        let mut flag = 0u32;
+        for _ in 0..200 {
+            thread::sleep(Duration::from_micros(200));
+            drained += buffers.rotate(flag);
+            flag ^= 0x1;
+        }
+
+        done.store(true, Ordering::Relaxed);
+        let written: u64 = writers.into_iter().map(|w| w.join().unwrap()).sum();
+
+        // Drain both buffers once the writers are gone.
+        drained += buffers.rotate(flag);
+        drained += buffers.rotate(flag ^ 0x1);
+
+        assert_eq!(drained, written);
+    }
+
+    #[test]
+    fn wait_for_writers_timeout() {
+        assert!(wait_for_writers(|| Ok(0), Duration::ZERO).unwrap());
+        assert!(!wait_for_writers(|| Ok(1), Duration::from_millis(1)).unwrap());
+    }
+}
@@ -0,0 +1,108 @@
+use clap::ValueEnum;
+use ratatui::prelude::{Color, Modifier, Style};
+use serde::Deserialize;
+
+/// Theme of the TUI, monochrome only uses text attributes for terminals without colours.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThemeName {
+    Color,
+    Mono,
+}
+
+/// Styles of the TUI along with the thresholds the throughput is highlighted at.
+pub struct Theme {
+    mono: bool,
+    warn_rate: u64,
+    crit_rate: u64,
+}
+
+impl Theme {
+    /// Monochrome theme is also used when `NO_COLOR` is set to a non-empty value, see
+    /// <https://no-color.org>.
+    pub fn new(name: ThemeName, warn_rate: u64, crit_rate: u64) -> Self {
+        let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+        Self {
+            mono: name == ThemeName::Mono || no_color,
+            warn_rate,
+            crit_rate,
+        }
+    }
+
+    pub fn header(&self) -> Style {
+        if self.mono {
+            Style::default().add_modifier(Modifier::REVERSED)
+        } else {
+            Style::default().bg(Color::Blue)
+        }
+    }
+
+    /// Style of the throughput by the thresholds.
+    pub fn rate(&self, bytes: u64) -> Style {
+        if bytes > self.crit_rate {
+            self.alert(true)
+        } else if bytes > self.warn_rate {
+            self.warning(true)
+        } else {
+            Style::default()
+        }
+    }
+
+    /// Style of a value which needs attention, e.g. errors or full queues.
+    pub fn alert(&self, alert: bool) -> Style {
+        match (alert, self.mono) {
+            (false, _) => Style::default(),
+            (true, true) => Style::default().add_modifier(Modifier::BOLD),
+            (true, false) => Style::default().fg(Color::Red),
+        }
+    }
+
+    /// Style of a value which is worth noticing but is not necessarily a problem.
+    pub fn warning(&self, warning: bool) -> Style {
+        match (warning, self.mono) {
+            (false, _) => Style::default(),
+            (true, true) => Style::default().add_modifier(Modifier::UNDERLINED),
+            (true, false) => Style::default().fg(Color::Yellow),
+        }
+    }
+
+    pub fn bar(&self) -> Style {
+        if self.mono {
+            Style::default()
+        } else {
+            Style::default().fg(Color::Cyan)
+        }
+    }
+
+    pub fn bar_value(&self) -> Style {
+        if self.mono {
+            Style::default().add_modifier(Modifier::REVERSED)
+        } else {
+            Style::default().fg(Color::Black).bg(Color::Cyan)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ratatui::prelude::{Color, Modifier, Style};
+
+    use crate::theme::{Theme, ThemeName};
+
+    #[test]
+    fn rate_thresholds() {
+        let theme = Theme { mono: false, warn_rate: 10, crit_rate: 100 };
+        assert_eq!(theme.rate(10), Style::default());
+        assert_eq!(theme.rate(11), Style::default().fg(Color::Yellow));
+        assert_eq!(theme.rate(101), Style::default().fg(Color::Red));
+
+        let theme = Theme { mono: true, ..theme };
+        assert_eq!(theme.rate(101), Style::default().add_modifier(Modifier::BOLD));
+        assert_eq!(theme.header().bg, None);
+
+        if std::env::var_os("NO_COLOR").is_none() {
+            assert!(!Theme::new(ThemeName::Color, 10, 100).mono);
+        }
+        assert!(Theme::new(ThemeName::Mono, 10, 100).mono);
+    }
+}
@@ -0,0 +1,461 @@
+use std::net::Ipv4Addr;
+
+use clap::ValueEnum;
+use crossterm::event::KeyCode;
+use flow_top_talker_common::common_types::{FlowKey, LATENCY_BUCKETS, SIZE_BUCKETS, TCP};
+use ratatui::{prelude::*, widgets::*};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    churn::{ChurnKey, ChurnStats},
+    cli::Cli,
+    connection::{ConnectionTable, SortMetric},
+    filter::Filter,
+    flow_info::{FlowInfo, Totals},
+    roles::Listeners,
+    theme::Theme,
+    units::format_rate,
+};
+
+/// View of the flows, either ingress and egress in separate sections, both directions of
+/// each connection in a single row, or the traffic of the local listening services.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum View {
+    Split,
+    Combined,
+    Services,
+}
+
+/// Section of the flows which can be selected.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Section {
+    Ingress,
+    Egress,
+}
+
+impl Section {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Section::Ingress => "Ingress",
+            Section::Egress => "Egress",
+        }
+    }
+}
+
+/// Flows of the last interval, sorted by throughput, which are displayed until the next
+/// interval. Also sent by the daemon to its clients.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub ingress: Vec<FlowInfo>,
+    pub egress: Vec<FlowInfo>,
+    pub connections: ConnectionTable,
+    pub listeners: Listeners,
+    pub ingress_totals: Totals,
+    pub egress_totals: Totals,
+    pub churn: Vec<(ChurnKey, ChurnStats)>,
+    /// Condition of the collection worth pointing out, shown in the status line.
+    #[serde(default)]
+    pub warning: Option<String>,
+}
+
+impl Snapshot {
+    pub fn flows(&self, section: Section) -> &[FlowInfo] {
+        match section {
+            Section::Ingress => &self.ingress,
+            Section::Egress => &self.egress,
+        }
+    }
+
+    /// Totals of all the flows drained in the interval, not only the top N.
+    pub fn totals(&self, section: Section) -> Totals {
+        match section {
+            Section::Ingress => self.ingress_totals,
+            Section::Egress => self.egress_totals,
+        }
+    }
+}
+
+/// Selection of the flows and the flow opened in the detail view.
+///
+/// Detail view follows the flow by its key, as the position of the flow changes with every
+/// interval.
+pub struct UiState {
+    pub view: View,
+    pub sort: SortMetric,
+    pub by_service: bool,
+    pub theme: Theme,
+    /// Message shown in the header, e.g. the reply of the daemon to a filter change.
+    pub status: Option<String>,
+    /// Filter currently applied by the eBPF program, shown in the header.
+    pub filter: Filter,
+    /// Text of the filter being entered, opened with `f`.
+    pub prompt: Option<String>,
+    pub focus: Section,
+    pub selected: Option<usize>,
+    pub detail: Option<(Section, FlowKey)>,
+}
+
+impl UiState {
+    pub fn new(view: View, sort: SortMetric, by_service: bool, theme: Theme) -> Self {
+        Self {
+            view,
+            sort,
+            by_service,
+            theme,
+            status: None,
+            filter: Filter::default(),
+            prompt: None,
+            focus: Section::Ingress,
+            selected: None,
+            detail: None,
+        }
+    }
+
+    /// Handle the key pressed by the user.
+    ///
+    /// Up and down select a flow in the focused section, tab switches the section, enter
+    /// opens the detail view of the selected flow and escape closes it. `v` cycles through the
+    /// split, combined and services views, `s` changes the metric the connections and services
+    /// are ranked by and `a` toggles aggregating the connections by service in the combined view.
+    /// `f` opens a prompt to change the filter, prefilled with the current one.
+    ///
+    /// Returns the filter entered in the prompt, which is applied by the caller.
+    pub fn handle_key(&mut self, code: KeyCode, snapshot: &Snapshot) -> Option<Filter> {
+        if let Some(prompt) = &mut self.prompt {
+            match code {
+                KeyCode::Char(c) => prompt.push(c),
+                KeyCode::Backspace => {
+                    prompt.pop();
+                }
+                KeyCode::Esc => self.prompt = None,
+                KeyCode::Enter => match prompt.parse() {
+                    Ok(filter) => {
+                        self.prompt = None;
+                        return Some(filter);
+                    }
+                    Err(e) => self.status = Some(e.to_string()),
+                },
+                _ => {}
+            }
+            return None;
+        }
+
+        if self.detail.is_some() {
+            if matches!(code, KeyCode::Esc | KeyCode::Backspace) {
+                self.detail = None;
+            }
+            return None;
+        }
+
+        if code == KeyCode::Char('f') {
+            self.prompt = Some(self.filter.to_string());
+            self.status = None;
+            return None;
+        }
+
+        match code {
+            KeyCode::Char('v') => {
+                self.view = match self.view {
+                    View::Split => View::Combined,
+                    View::Combined => View::Services,
+                    View::Services => View::Split,
+                };
+                self.selected = None;
+                return None;
+            }
+            KeyCode::Char('s') => {
+                self.sort = self.sort.next();
+                return None;
+            }
+            KeyCode::Char('a') => {
+                self.by_service = !self.by_service;
+                return None;
+            }
+            // Flows can only be selected in the split view.
+            _ if self.view != View::Split => return None,
+            _ => {}
+        }
+
+        let nr_flows = snapshot.flows(self.focus).len();
+        match code {
+            KeyCode::Down | KeyCode::Char('j') if nr_flows > 0 => {
+                self.selected = Some(self.selected.map_or(0, |index| (index + 1).min(nr_flows - 1)));
+            }
+            KeyCode::Up | KeyCode::Char('k') if nr_flows > 0 => {
+                self.selected = Some(self.selected.map_or(0, |index| index.saturating_sub(1)));
+            }
+            KeyCode::Tab => {
+                self.focus = match self.focus {
+                    Section::Ingress => Section::Egress,
+                    Section::Egress => Section::Ingress,
+                };
+                self.selected = None;
+            }
+            KeyCode::Enter => {
+                if let Some(flow) = self.selected.and_then(|index| snapshot.flows(self.focus).get(index)) {
+                    self.detail = Some((self.focus, flow.flow_key()));
+                }
+            }
+            KeyCode::Esc => self.selected = None,
+            _ => {}
+        }
+
+        None
+    }
+
+    /// Table state of the section, with the selection clamped to the flows displayed.
+    pub fn table_state(&self, section: Section, nr_flows: usize) -> TableState {
+        let selected = match self.selected {
+            Some(index) if section == self.focus && nr_flows > 0 => Some(index.min(nr_flows - 1)),
+            _ => None,
+        };
+
+        TableState::default().with_selected(selected)
+    }
+}
+
+/// Draw the detail view of the flow with the histograms of the message sizes and the time
+/// spent in the calls.
+pub fn draw_detail(
+    frame: &mut Frame,
+    section: Section,
+    flow_key: &FlowKey,
+    flow: Option<&FlowInfo>,
+    theme: &Theme,
+    cli: &Cli,
+    area: Rect,
+) {
+    let areas = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(10),
+            Constraint::Percentage(30),
+            Constraint::Percentage(30),
+            Constraint::Min(0),
+        ])
+        .split(area);
+
+    let title = format!(
+        "{} flow {}:{} -> {}:{} [Esc: back]",
+        section.name(),
+        Ipv4Addr::from(flow_key.src_addr), flow_key.src_port,
+        Ipv4Addr::from(flow_key.dest_addr), flow_key.dest_port,
+    );
+
+    let flow = match flow {
+        Some(flow) => flow,
+        None => {
+            let text = Paragraph::new("Flow is not in the top flows of the last interval.")
+                .block(Block::default().borders(Borders::ALL).title(title));
+            frame.render_widget(text, area);
+            return;
+        }
+    };
+
+    let messages = flow.messages();
+    let avg_size = flow.throughput.checked_div(messages).unwrap_or(0);
+    let process = if flow.owner.pid != 0 {
+        format!("{}/{}", flow.owner.pid, flow.owner.comm())
+    } else {
+        "-".to_string()
+    };
+
+    let lines = vec![
+        Line::from(format!("Protocol: {}", if flow.protocol == TCP { "TCP" } else { "UDP" })),
+        Line::from(format!("Process: {}", process)),
+        Line::from(format!("Throughput: {}", format_rate(cli.per_second(flow.throughput), cli.bits))),
+        Line::from(format!("Messages/s: {}  Avg size: {} B", cli.per_second(messages), avg_size)),
+        Line::from(format!("Errors: {}", flow.error_summary().unwrap_or("-".to_string()))),
+        Line::from(match flow.buffers {
+            Some(buffers) => format!(
+                "Send queue: {}/{} B ({}%)  Receive queue: {}/{} B ({}%)",
+                buffers.wmem_queued, buffers.sndbuf, buffers.send_queue_pct(),
+                buffers.rmem_alloc, buffers.rcvbuf, buffers.recv_queue_pct(),
+            ),
+            None => "Send queue: -  Receive queue: -".to_string(),
+        }),
+        Line::from(match flow.busiest_cpu() {
+            Some((cpu, share)) => format!(
+                "CPUs: {}  Busiest: cpu{} ({}% of bytes)",
+                flow.per_cpu.iter().filter(|stats| stats.bytes > 0 || stats.messages > 0).count(),
+                cpu, share,
+            ),
+            None => "CPUs: -".to_string(),
+        }),
+    ];
+    let summary = Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title(title));
+    frame.render_widget(summary, areas[0]);
+
+    let labels: Vec<String> = (0..SIZE_BUCKETS).map(|bucket| bucket_label(bucket, SIZE_BUCKETS)).collect();
+    draw_histogram(frame, "Message sizes (bytes, log2 buckets)", &labels, &flow.sizes, theme, areas[1]);
+
+    let labels: Vec<String> = (0..LATENCY_BUCKETS).map(|bucket| bucket_label(bucket, LATENCY_BUCKETS)).collect();
+    draw_histogram(frame, "Time in call (us, log2 buckets)", &labels, &flow.latencies, theme, areas[2]);
+
+    // CPUs which didn't process the flow are left out, they can be many on large hosts.
+    let (labels, bytes): (Vec<String>, Vec<u64>) = flow.per_cpu
+        .iter()
+        .enumerate()
+        .filter(|(_, stats)| stats.bytes > 0 || stats.messages > 0)
+        .map(|(cpu, stats)| (format!("cpu{}", cpu), stats.bytes))
+        .unzip();
+    draw_histogram(frame, "Bytes per CPU", &labels, &bytes, theme, areas[3]);
+}
+
+fn draw_histogram<T: Copy + Into<u64>>(
+    frame: &mut Frame,
+    title: &str,
+    labels: &[String],
+    counts: &[T],
+    theme: &Theme,
+    area: Rect,
+) {
+    let data: Vec<(&str, u64)> = labels
+        .iter()
+        .zip(counts)
+        .map(|(label, count)| (label.as_str(), (*count).into()))
+        .collect();
+
+    let histogram = BarChart::default()
+        .block(Block::default().borders(Borders::ALL).title(title.to_string()))
+        .data(&data)
+        .bar_width(5)
+        .bar_gap(1)
+        .bar_style(theme.bar())
+        .value_style(theme.bar_value());
+    frame.render_widget(histogram, area);
+}
+
+/// Label of the histogram bucket by its lower bound, the last bucket is open ended.
+fn bucket_label(bucket: usize, buckets: usize) -> String {
+    let value = 1u64 << bucket;
+    let label = match value {
+        value if value >= 1 << 20 => format!("{}M", value >> 20),
+        value if value >= 1 << 10 => format!("{}K", value >> 10),
+        value => format!("{}", value),
+    };
+
+    if bucket == buckets - 1 {
+        format!("{}+", label)
+    } else {
+        label
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crossterm::event::KeyCode;
+    use flow_top_talker_common::common_types::{
+        latency_bucket, size_bucket, FlowKey, FlowStats, LATENCY_BUCKETS, SIZE_BUCKETS,
+    };
+
+    use crate::{
+        connection::{ConnectionTable, SortMetric},
+        filter::Filter,
+        flow_info::FlowInfo,
+        roles::Listeners,
+        theme::{Theme, ThemeName},
+        ui::{bucket_label, Section, Snapshot, UiState, View},
+    };
+
+    #[test]
+    fn enter_filter() {
+        let snapshot = Snapshot::default();
+        let theme = Theme::new(ThemeName::Color, 10_000, 100_000);
+        let mut ui = UiState::new(View::Split, SortMetric::Total, false, theme);
+        ui.filter = Filter { pids: vec![12], ..Default::default() };
+
+        ui.handle_key(KeyCode::Char('f'), &snapshot);
+        assert_eq!(ui.prompt.as_deref(), Some("pid=12"));
+
+        // Keys go to the prompt while it is open.
+        for code in [KeyCode::Backspace, KeyCode::Char('3'), KeyCode::Char('v')] {
+            assert_eq!(ui.handle_key(code, &snapshot), None);
+        }
+        assert_eq!((ui.prompt.as_deref(), ui.view), (Some("pid=13v"), View::Split));
+
+        assert_eq!(ui.handle_key(KeyCode::Enter, &snapshot), None);
+        assert_eq!(ui.status.as_deref(), Some("Invalid filter `pid=13v`, expected pid=PID, tid=TID, uid=UID, user=NAME, comm=PATTERN, exe=PATTERN, traced or none"));
+
+        ui.handle_key(KeyCode::Backspace, &snapshot);
+        assert_eq!(ui.handle_key(KeyCode::Enter, &snapshot), Some(Filter { pids: vec![13], ..Default::default() }));
+        assert_eq!(ui.prompt, None);
+
+        // The filter is only changed once applied by the caller.
+        assert_eq!(ui.filter, Filter { pids: vec![12], ..Default::default() });
+        ui.handle_key(KeyCode::Char('f'), &snapshot);
+        ui.handle_key(KeyCode::Esc, &snapshot);
+        assert_eq!(ui.prompt, None);
+    }
+
+    #[test]
+    fn select_and_open_detail() {
+        let flows: Vec<FlowInfo> = (0..3)
+            .map(|port| FlowInfo::new(&FlowKey::new(1, 2, port, 80, 0), &FlowStats::default()))
+            .collect();
+        let snapshot = Snapshot {
+            ingress: flows.clone(),
+            egress: Vec::new(),
+            connections: ConnectionTable::default(),
+            listeners: Listeners::default(),
+            ..Default::default()
+        };
+        let theme = Theme::new(ThemeName::Color, 10_000, 100_000);
+        let mut ui = UiState::new(View::Split, SortMetric::Total, false, theme);
+
+        ui.handle_key(KeyCode::Down, &snapshot);
+        ui.handle_key(KeyCode::Down, &snapshot);
+        ui.handle_key(KeyCode::Down, &snapshot);
+        ui.handle_key(KeyCode::Down, &snapshot);
+        assert_eq!(ui.selected, Some(2));
+        assert_eq!(ui.table_state(Section::Ingress, 1).selected(), Some(0));
+        assert_eq!(ui.table_state(Section::Egress, 3).selected(), None);
+
+        ui.handle_key(KeyCode::Up, &snapshot);
+        ui.handle_key(KeyCode::Enter, &snapshot);
+        assert_eq!(ui.detail, Some((Section::Ingress, flows[1].flow_key())));
+
+        // Keys other than escape are ignored in the detail view.
+        ui.handle_key(KeyCode::Tab, &snapshot);
+        assert_eq!(ui.focus, Section::Ingress);
+        ui.handle_key(KeyCode::Esc, &snapshot);
+        assert_eq!(ui.detail, None);
+
+        ui.handle_key(KeyCode::Tab, &snapshot);
+        ui.handle_key(KeyCode::Down, &snapshot);
+        ui.handle_key(KeyCode::Enter, &snapshot);
+        assert_eq!((ui.focus, ui.selected, ui.detail), (Section::Egress, None, None));
+
+        // Selection is disabled in the combined view.
+        ui.handle_key(KeyCode::Char('v'), &snapshot);
+        ui.handle_key(KeyCode::Tab, &snapshot);
+        ui.handle_key(KeyCode::Char('s'), &snapshot);
+        assert_eq!((ui.view, ui.focus, ui.sort), (View::Combined, Section::Egress, SortMetric::Tx));
+
+        ui.handle_key(KeyCode::Char('v'), &snapshot);
+        ui.handle_key(KeyCode::Down, &snapshot);
+        assert_eq!((ui.view, ui.selected), (View::Services, None));
+        ui.handle_key(KeyCode::Char('v'), &snapshot);
+        assert_eq!(ui.view, View::Split);
+    }
+
+    #[test]
+    fn size_buckets() {
+        assert_eq!(size_bucket(0), 0);
+        assert_eq!(size_bucket(1), 0);
+        assert_eq!(size_bucket(2), 1);
+        assert_eq!(size_bucket(1023), 9);
+        assert_eq!(size_bucket(1024), 10);
+        assert_eq!(size_bucket(u64::MAX), SIZE_BUCKETS - 1);
+
+        assert_eq!(latency_bucket(999), 0);
+        assert_eq!(latency_bucket(1_500_000), 10);
+        assert_eq!(latency_bucket(60_000_000_000), LATENCY_BUCKETS - 1);
+
+        assert_eq!(bucket_label(0, SIZE_BUCKETS), "1");
+        assert_eq!(bucket_label(10, SIZE_BUCKETS), "1K");
+        assert_eq!(bucket_label(SIZE_BUCKETS - 1, SIZE_BUCKETS), "512K+");
+        assert_eq!(bucket_label(LATENCY_BUCKETS - 1, LATENCY_BUCKETS), "8M+");
+    }
+}
@@ -0,0 +1,98 @@
+use std::time::Duration;
+
+/// Format the throughput in bytes per second with binary units, or in bits per second with
+/// decimal units as link speeds are.
+pub fn format_rate(bytes: u64, bits: bool) -> String {
+    if bits {
+        scale(bytes.saturating_mul(8), 1000, &["b/s", "Kb/s", "Mb/s", "Gb/s", "Tb/s"])
+    } else {
+        scale(bytes, 1024, &["B/s", "KiB/s", "MiB/s", "GiB/s", "TiB/s"])
+    }
+}
+
+fn scale(value: u64, base: u64, units: &[&str]) -> String {
+    if value < base {
+        return format!("{} {}", value, units[0]);
+    }
+
+    let mut scaled = value as f64;
+    let mut unit = 0;
+    while scaled >= base as f64 && unit < units.len() - 1 {
+        scaled /= base as f64;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", scaled, units[unit])
+}
+
+/// Parse a rate in bytes per second with an optional binary suffix, e.g. `512K` or `10M`.
+pub fn parse_rate(rate: &str) -> Result<u64, String> {
+    let rate = rate.trim();
+    let (value, shift) = match rate.char_indices().last() {
+        Some((index, 'K' | 'k')) => (&rate[..index], 10),
+        Some((index, 'M' | 'm')) => (&rate[..index], 20),
+        Some((index, 'G' | 'g')) => (&rate[..index], 30),
+        _ => (rate, 0),
+    };
+
+    value
+        .parse::<u64>()
+        .ok()
+        .and_then(|value| value.checked_mul(1 << shift))
+        .ok_or_else(|| format!("invalid rate `{}`, expected bytes per second like 10000, 512K or 10M", rate))
+}
+
+/// Parse a duration with a unit suffix, e.g. `500ms`, `30s`, `5m` or `1h`, in seconds without
+/// a suffix.
+pub fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let duration = duration.trim();
+    let (value, unit) = duration.split_at(duration.find(|c: char| !c.is_ascii_digit()).unwrap_or(duration.len()));
+    let millis = match unit {
+        "ms" => Some(1),
+        "" | "s" => Some(1000),
+        "m" => Some(60 * 1000),
+        "h" => Some(60 * 60 * 1000),
+        _ => None,
+    };
+
+    value
+        .parse::<u64>()
+        .ok()
+        .filter(|value| *value > 0)
+        .zip(millis)
+        .and_then(|(value, millis)| value.checked_mul(millis))
+        .map(Duration::from_millis)
+        .ok_or_else(|| format!("invalid duration `{}`, expected a duration like 30s, 5m or 1h", duration))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::units::{format_rate, parse_duration, parse_rate};
+
+    #[test]
+    fn format_and_parse_rates() {
+        assert_eq!(format_rate(512, false), "512 B/s");
+        assert_eq!(format_rate(1536, false), "1.5 KiB/s");
+        assert_eq!(format_rate(10 << 20, false), "10.0 MiB/s");
+        assert_eq!(format_rate(100, true), "800 b/s");
+        assert_eq!(format_rate(125_000_000, true), "1.0 Gb/s");
+        assert_eq!(format_rate(u64::MAX, false), "16777216.0 TiB/s");
+
+        assert_eq!(parse_rate("10000"), Ok(10000));
+        assert_eq!(parse_rate("512K"), Ok(512 << 10));
+        assert_eq!(parse_rate("10m"), Ok(10 << 20));
+        assert!(parse_rate("fast").is_err());
+        assert!(parse_rate("K").is_err());
+
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("45"), Ok(Duration::from_secs(45)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("0s"), Err("invalid duration `0s`, expected a duration like 30s, 5m or 1h".to_string()));
+        assert!(parse_duration("1d").is_err());
+        assert!(parse_duration("s").is_err());
+    }
+}